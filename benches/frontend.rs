@@ -0,0 +1,59 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use kaleidoscope::ast::fold;
+use kaleidoscope::{lex, parse_str};
+
+// Generate a program of `functions` definitions, each followed by a call to it.  The
+// definitions use most of the syntax: a user-defined operator, `var`, `for`, `if`,
+// calls, comments and every kind of literal, so the numbers reflect real programs
+// rather than one hot path.
+fn generate_program(functions: usize) -> String {
+    let mut source = String::from(
+        "# Benchmark input\n\
+         extern sin(x)\n\
+         extern printf(fmt ...)\n\
+         def binary| 5 (a b) if a then 1 else if b then 1 else 0\n\
+         def f0(x y) x + y\n",
+    );
+    for i in 1..functions {
+        let prev = i - 1;
+        source.push_str(&format!(
+            "// f{i} calls the one before it\n\
+             def f{i}(x y)\n  \
+               var a = x * {i}.5, b = y in\n    \
+                 (for j = 0, j < 10, 1.0 in\n      \
+                   a = a + sin(j) - b * 3) :\n    \
+                 if a < b | x > {i} then f{prev}(a, b - 1) else -a\n\
+             f{i}({i}, 2.25);\n\
+             printf(\"%f\\n\", f{i}(true, false));\n"
+        ));
+    }
+    return source;
+}
+
+fn bench_lexer(c: &mut Criterion) {
+    let source = generate_program(1000);
+    let tokens = lex(&source).len();
+
+    let mut group = c.benchmark_group("lexer");
+    group.throughput(Throughput::Elements(tokens as u64));
+    group.bench_function("tokens", |b| b.iter(|| lex(black_box(&source))));
+    group.finish();
+}
+
+fn bench_parser(c: &mut Criterion) {
+    let source = generate_program(1000);
+    let nodes: usize = parse_str(&source)
+        .unwrap()
+        .iter()
+        .map(|item| fold(item, 0, |count, _| count + 1))
+        .sum();
+
+    // Parsing includes lexing, so the difference from the lexer is the parser's own cost.
+    let mut group = c.benchmark_group("parser");
+    group.throughput(Throughput::Elements(nodes as u64));
+    group.bench_function("nodes", |b| b.iter(|| parse_str(black_box(&source))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_lexer, bench_parser);
+criterion_main!(benches);
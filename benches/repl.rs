@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use inkwell::context::Context;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::eval_loop;
+use kaleidoscope::State;
+
+// The number of expressions evaluated in each session.
+const EXPRESSIONS: usize = 50;
+
+// A REPL session: `functions` definitions, then EXPRESSIONS calls to them.  With
+// `redefine`, every call is preceded by a new definition of `touch`, which makes the
+// REPL build a new JIT for the call, as it did for every expression before the JIT was
+// kept.
+fn generate_session(functions: usize, redefine: bool) -> String {
+    let mut source = String::from("def f0(x) x + 1\n");
+    for i in 1..functions {
+        let prev = i - 1;
+        source.push_str(&format!(
+            "def f{i}(x) if x < {i} then f{prev}(x * 2) else f{prev}(x - 1)\n"
+        ));
+    }
+    for i in 0..EXPRESSIONS {
+        if redefine {
+            source.push_str(&format!("def touch() {i}\n"));
+        }
+        source.push_str(&format!("f{}({i})\n", functions - 1));
+    }
+    return source;
+}
+
+fn run_session(source: &str) {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text(source);
+    eval_loop(&mut state).unwrap();
+}
+
+fn bench_session(c: &mut Criterion) {
+    let kept = generate_session(200, false);
+    let rebuilt = generate_session(200, true);
+
+    // The difference between the two is what keeping the JIT saves.
+    let mut group = c.benchmark_group("repl");
+    group.sample_size(10);
+    group.bench_function("jit kept", |b| b.iter(|| run_session(&kept)));
+    group.bench_function("jit rebuilt", |b| b.iter(|| run_session(&rebuilt)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_session);
+criterion_main!(benches);
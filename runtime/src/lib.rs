@@ -0,0 +1,113 @@
+// The functions compiled Kaleidoscope programs call without defining them, such as
+// `print` and `readnumber`.  The JIT maps them into the programs it runs, and the
+// static library built from this crate links into programs compiled ahead of time
+// with `--main --emit-obj`, so both behave the same.
+
+use std::io::{Read, Write};
+
+// putchard - putchar that takes a double and returns 0.
+#[no_mangle]
+pub extern "C" fn putchard(x: f64) -> f64 {
+    print!("{}", x as u8 as char);
+    std::io::stdout().flush().unwrap();
+    0.0
+}
+
+// printd - printf that takes a double prints it as "%f\n", returning 0.
+#[no_mangle]
+pub extern "C" fn printd(x: f64) -> f64 {
+    println!("{x:.6}");
+    0.0
+}
+
+// print - prints a double as `format_value` does, then a newline, returning 0.
+// Always available, no `extern` needed.
+#[no_mangle]
+pub extern "C" fn print(x: f64) -> f64 {
+    println!("{}", format_value(x, None));
+    0.0
+}
+
+// assert - does nothing when `x` is true as a condition, returning 0.  When it is
+// false (0 or NaN) the program stops: the message goes to stderr and the process
+// exits with status 1, as C's assert does, so a self-checking test file fails loudly
+// whether it runs in the REPL or from the command line.  Always available.
+#[no_mangle]
+pub extern "C" fn assert(x: f64) -> f64 {
+    if x == 0.0 || x.is_nan() {
+        std::io::stdout().flush().unwrap();
+        eprintln!("Assertion failed: got {}", format_value(x, None));
+        std::process::exit(1);
+    }
+    0.0
+}
+
+// divzero - called by an integer `/` or `%` whose divisor is zero, before LLVM's
+// undefined result could be used.  Stops the program as `assert` does.  The JIT maps in
+// a handler of its own, which reports the error without stopping the REPL.  The '.' in
+// the symbol keeps it apart from any function a program defines.
+#[export_name = "kaleidoscope.divzero"]
+pub extern "C" fn divzero() {
+    std::io::stdout().flush().unwrap();
+    eprintln!("Division by zero");
+    std::process::exit(1);
+}
+
+// readnumber - reads a line from stdin and returns the number on it.  At the end of the
+// input, or when the line holds anything but a number, returns NaN.  Always available.
+#[no_mangle]
+pub extern "C" fn readnumber() -> f64 {
+    // Show any prompt the program printed before waiting for the answer.
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(len) if len > 0 => line.trim().parse().unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+// readchar - getchar that returns a double: the next byte of stdin, or -1 at the end of
+// the input.  Always available.
+#[no_mangle]
+pub extern "C" fn readchar() -> f64 {
+    std::io::stdout().flush().unwrap();
+    let mut byte = [0u8];
+    match std::io::stdin().read(&mut byte) {
+        Ok(1) => byte[0] as f64,
+        _ => -1.0,
+    }
+}
+
+// Format a result for display.  Finite values are rounded to `precision` significant
+// digits, or by default use the shortest form that reads back exactly, without a
+// trailing ".0".  The rest print as `nan`, `inf` and `-inf`, as C's printf does.
+pub fn format_value(value: f64, precision: Option<usize>) -> String {
+    if value.is_nan() {
+        return String::from("nan");
+    }
+    if value.is_infinite() {
+        return String::from(if value > 0.0 { "inf" } else { "-inf" });
+    }
+    match precision {
+        Some(digits) => format_significant(value, digits.max(1)),
+        None => format!("{value}"),
+    }
+}
+
+// Show a finite value with `digits` significant digits, so 0.333333 with 3 digits is
+// `0.333` and 12345.6 is `12300`.
+fn format_significant(value: f64, digits: usize) -> String {
+    // Scientific notation rounds at exactly the right digit.  Reading it back gives the
+    // rounded value, whose exponent may have grown (9.99 to 10 with 2 digits).
+    let rounded: f64 = format!("{value:.0$e}", digits - 1).parse().unwrap();
+    if rounded == 0.0 {
+        return format!("{rounded:.0$}", digits - 1);
+    }
+    let exponent = rounded.abs().log10().floor() as i32;
+    let decimals = digits as i32 - 1 - exponent;
+    if decimals <= 0 {
+        // A whole number: the shortest form has no digits past the rounded ones.
+        return format!("{rounded}");
+    }
+    format!("{rounded:.0$}", decimals as usize)
+}
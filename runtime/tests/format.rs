@@ -0,0 +1,26 @@
+use kaleidoscope_runtime::format_value;
+
+#[test]
+fn shortest_form_by_default() {
+    assert_eq!(format_value(3.0, None), "3");
+    assert_eq!(format_value(0.1, None), "0.1");
+    assert_eq!(format_value(-2.5, None), "-2.5");
+    assert_eq!(format_value(1e21, None), "1000000000000000000000");
+}
+
+#[test]
+fn significant_digits() {
+    assert_eq!(format_value(0.333333, Some(3)), "0.333");
+    assert_eq!(format_value(12345.6, Some(3)), "12300");
+    assert_eq!(format_value(9.99, Some(2)), "10");
+    assert_eq!(format_value(0.0, Some(3)), "0.00");
+}
+
+#[test]
+fn special_values() {
+    for precision in [None, Some(3)] {
+        assert_eq!(format_value(f64::NAN, precision), "nan");
+        assert_eq!(format_value(f64::INFINITY, precision), "inf");
+        assert_eq!(format_value(f64::NEG_INFINITY, precision), "-inf");
+    }
+}
@@ -1,109 +1,677 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::os::raw::c_char;
+
+use crate::compiler::Compiler;
+use crate::lexer::{SourceLocation, Span};
+use crate::parser::ParserState;
+use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, StructType};
+use inkwell::values::{
+    AnyValueEnum, AsValueRef, BasicMetadataValueEnum, BasicValue, BasicValueEnum, CallableValue,
+    FloatValue, FunctionValue, IntValue, PointerValue,
+};
+use inkwell::AddressSpace;
+use inkwell::FloatPredicate::{OGT, OLT, ONE, UGT, ULT};
+use inkwell::IntPredicate::{EQ, NE, SGT, SLT};
+use llvm_sys::core::{LLVMGetFirstUse, LLVMSetValueName2};
+
+// CodegenError - A recoverable failure while generating code for a node.
+#[derive(Debug)]
+pub struct CodegenError {
+    pub message: String,
+}
+
+impl CodegenError {
+    pub fn new(message: String) -> Self {
+        CodegenError { message }
+    }
+
+    fn unknown_variable(name: &str, loc: SourceLocation) -> Self {
+        CodegenError::new(format!(
+            "VariableExprAST code generation failure. Unknown variable `{name}` at {loc}"
+        ))
+    }
+
+    fn unknown_function(name: &str) -> Self {
+        CodegenError::new(format!("get_function failure. Unknown function `{name}`."))
+    }
+
+    fn non_constant_global(name: &str) -> Self {
+        CodegenError::new(format!(
+            "GlobalAST code generation failure. The initializer of `{name}` is not a constant."
+        ))
+    }
+
+    fn bad_assignment() -> Self {
+        CodegenError::new(String::from(
+            "BinaryExprAST code generation failure. Destination of '=' must be a variable.",
+        ))
+    }
+
+    fn misplaced_tuple() -> Self {
+        CodegenError::new(String::from(
+            "TupleExprAST code generation failure. A tuple can only be returned, bound with `var` or projected with `.N`.",
+        ))
+    }
+
+    fn misplaced_string() -> Self {
+        CodegenError::new(String::from(
+            "StringExprAST code generation failure. Strings can only be used as arguments to extern functions.",
+        ))
+    }
 
-use crate::State;
-use inkwell::values::{AnyValueEnum, BasicValue, FunctionValue, PointerValue};
-use inkwell::FloatPredicate::{ONE, ULT};
+    fn wrong_arg_count(callee: &str, expected: usize, is_var_arg: bool, got: usize) -> Self {
+        let at_least = if is_var_arg { "at least " } else { "" };
+        CodegenError::new(format!(
+            "CallExprAST code generation failure. Incorrect # of arguments passed to `{callee}`: expected {at_least}{expected}, got {got}."
+        ))
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+pub type CodegenResult<'ctx> = Result<AnyValueEnum<'ctx>, CodegenError>;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub enum AST {
     Null,
     Number(NumberExprAST),
+    String(StringExprAST),
     Variable(VariableExprAST),
     Binary(BinaryExprAST),
     Unary(UnaryExprAST),
     Call(CallExprAST),
+    FunctionRef(FunctionRefExprAST),
+    Tuple(TupleExprAST),
+    Projection(ProjectionExprAST),
     If(IfExprAST),
     For(ForExprAST),
+    While(WhileExprAST),
+    Break(BreakExprAST),
+    Continue(ContinueExprAST),
     Var(VarExprAST),
+    Block(BlockExprAST),
     Prototype(PrototypeAST),
     Function(FunctionAST),
+    Global(GlobalAST),
+}
+
+impl AST {
+    // Where the node is in the source, for the nodes that keep track of it.
+    pub fn get_loc(&self) -> Option<SourceLocation> {
+        match self {
+            AST::Variable(var) => Some(var.loc),
+            AST::Call(call) => Some(call.loc),
+            AST::FunctionRef(func_ref) => Some(func_ref.loc),
+            AST::Projection(projection) => Some(projection.loc),
+            AST::Break(break_expr) => Some(break_expr.loc),
+            AST::Continue(continue_expr) => Some(continue_expr.loc),
+            _ => None,
+        }
+    }
+}
+
+// Literal - The value of a literal.  Numbers written without a '.' are 64-bit
+// integers, everything else is a double.  `true` and `false` are booleans.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub enum Literal {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+}
+
+impl Literal {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Literal::Bool(val) => val as i64 as f64,
+            Literal::Int(val) => val as f64,
+            Literal::Float(val) => val,
+        }
+    }
+
+    // Booleans take part in arithmetic as the integers 0 and 1.
+    fn promote(self) -> Literal {
+        match self {
+            Literal::Bool(val) => Literal::Int(val as i64),
+            _ => self,
+        }
+    }
+
+    // The builtin unary '-'.  Integers wrap around, so the negation of i64::MIN is
+    // itself, as in the generated code.
+    fn negate(self) -> Literal {
+        match self.promote() {
+            Literal::Int(val) => Literal::Int(val.wrapping_neg()),
+            val => Literal::Float(-val.as_f64()),
+        }
+    }
+
+    pub fn codegen<'ctx>(self, state: &Compiler<'ctx>) -> AnyValueEnum<'ctx> {
+        match self {
+            Literal::Bool(val) => state
+                .context
+                .bool_type()
+                .const_int(val as u64, false)
+                .into(),
+            Literal::Int(val) => state.context.i64_type().const_int(val as u64, true).into(),
+            Literal::Float(val) => state.context.f64_type().const_float(val).into(),
+        }
+    }
+}
+
+// Type - A type a prototype can give its result, written after the argument list as in
+// `def f(x): int`.  Functions return a double unless they say otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub enum Type {
+    Double,
+    Int,
+    Bool,
+    // A fixed number of doubles, as in `def f(x): (double, double)`.
+    Tuple(usize),
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "double" => Some(Type::Double),
+            "int" => Some(Type::Int),
+            "bool" => Some(Type::Bool),
+            _ => None,
+        }
+    }
+
+    pub fn codegen<'ctx>(self, state: &Compiler<'ctx>) -> BasicTypeEnum<'ctx> {
+        match self {
+            Type::Double => state.context.f64_type().into(),
+            Type::Int => state.context.i64_type().into(),
+            Type::Bool => state.context.bool_type().into(),
+            Type::Tuple(size) => tuple_type(state, size).into(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Double => write!(f, "double"),
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Tuple(size) => write!(f, "({})", vec!["double"; *size].join(", ")),
+        }
+    }
+}
+
+// CallConv - The calling convention of an extern, as in `extern "fastcc" f(x)`, for
+// host functions built with another ABI than C's.  Functions defined in Kaleidoscope
+// always use C's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub enum CallConv {
+    C,
+    Fast,
+    Cold,
+}
+
+impl CallConv {
+    pub const ALL: [CallConv; 3] = [CallConv::C, CallConv::Fast, CallConv::Cold];
+
+    pub fn from_name(name: &str) -> Option<CallConv> {
+        CallConv::ALL
+            .into_iter()
+            .find(|call_conv| call_conv.get_name() == name)
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CallConv::C => "C",
+            CallConv::Fast => "fastcc",
+            CallConv::Cold => "coldcc",
+        }
+    }
+
+    // LLVM's number for the convention, from llvm/IR/CallingConv.h.
+    pub fn get_id(&self) -> u32 {
+        match self {
+            CallConv::C => 0,
+            CallConv::Fast => 8,
+            CallConv::Cold => 9,
+        }
+    }
+}
+
+// The LLVM type of a tuple of `size` doubles: a struct with a field for each.
+fn tuple_type<'ctx>(state: &Compiler<'ctx>, size: usize) -> StructType<'ctx> {
+    let f64_type = state.context.f64_type().into();
+    state.context.struct_type(&vec![f64_type; size], false)
 }
-// NumberExprAST - Expression class for numeric literals like "1.0".
+
+// NumberExprAST - Expression class for numeric literals like "1.0" or "1".
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct NumberExprAST {
-    val: f64,
+    val: Literal,
 }
 
 impl NumberExprAST {
     pub fn new(val: f64) -> Self {
-        return NumberExprAST { val };
+        return NumberExprAST {
+            val: Literal::Float(val),
+        };
+    }
+
+    pub fn new_int(val: i64) -> Self {
+        return NumberExprAST {
+            val: Literal::Int(val),
+        };
+    }
+
+    pub fn new_bool(val: bool) -> Self {
+        return NumberExprAST {
+            val: Literal::Bool(val),
+        };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &Compiler<'ctx>) -> AnyValueEnum<'ctx> {
+        self.val.codegen(state)
+    }
+}
+
+// StringExprAST - Expression class for string literals like "hello".  A string is a
+// pointer to its bytes, which only C functions understand, so strings may only be
+// passed as arguments to extern functions.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct StringExprAST {
+    val: String,
+}
+
+impl StringExprAST {
+    pub fn new(val: String) -> Self {
+        return StringExprAST { val };
     }
 
-    pub fn codegen<'ctx>(&self, state: &State<'ctx>) -> AnyValueEnum<'ctx> {
-        state.context.f64_type().const_float(self.val).into()
+    pub fn codegen<'ctx>(&self, state: &Compiler<'ctx>) -> PointerValue<'ctx> {
+        // Emit the bytes as a NUL-terminated private global and point at the first one.
+        state
+            .builder
+            .build_global_string_ptr(&self.val, "str")
+            .as_pointer_value()
     }
 }
 
 // VariableExprAST - Expression class for referencing a variable, like "a".
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct VariableExprAST {
     name: String,
+    loc: SourceLocation,
 }
 
 impl VariableExprAST {
-    pub fn new(name: String) -> Self {
-        return VariableExprAST { name };
-    }
-    pub fn codegen<'ctx>(&self, state: &State<'ctx>) -> AnyValueEnum<'ctx> {
-        let val = state.named_values.get(&self.name);
-        match val {
-            Some(ptr_val) => state.builder.build_load(*ptr_val, &self.name).into(),
-            None => panic!(
-                "VariableExprAST code generation failure. Could not find key `{}`",
-                self.name
-            ),
+    pub fn new(name: String, loc: SourceLocation) -> Self {
+        return VariableExprAST { name, loc };
+    }
+
+    // Find the variable's alloca and type, or report it as unbound.
+    fn lookup<'ctx>(
+        &self,
+        state: &Compiler<'ctx>,
+    ) -> Result<(PointerValue<'ctx>, BasicTypeEnum<'ctx>), CodegenError> {
+        state
+            .lookup(&self.name)
+            .or_else(|| get_global(state, &self.name))
+            .ok_or_else(|| CodegenError::unknown_variable(&self.name, self.loc))
+    }
+
+    pub fn codegen<'ctx>(&self, state: &Compiler<'ctx>) -> CodegenResult<'ctx> {
+        // A variable known to be constant is its literal, with nothing to load.
+        if let Some(val) = state.lookup_constant(&self.name) {
+            return Ok(val.codegen(state));
+        }
+        // The alloca's pointee type is the recorded one, so the load yields that type.
+        let (ptr_val, _) = self.lookup(state)?;
+        Ok(state.builder.build_load(ptr_val, &self.name).into())
+    }
+}
+
+// The builtin bitwise operators, which only take integers.
+fn is_bitwise_op(op: &str) -> bool {
+    matches!(op, "&" | "|" | "<<" | ">>")
+}
+
+// The builtin operators a program may define its own versions of, as the tutorial does
+// for `|` and `&`.  A definition takes over from the builtin.
+fn is_overridable_op(op: &str) -> bool {
+    is_bitwise_op(op) || op == "^" || op == "/"
+}
+
+// Apply a builtin binary operator to two literals, or None if it can't be folded.
+// `ordered` picks the comparison semantics, see Compiler::ordered_compare.
+fn fold_literals(op: &str, lhs: Literal, rhs: Literal, ordered: bool) -> Option<Literal> {
+    let (lhs, rhs) = (lhs.promote(), rhs.promote());
+
+    // Integer arithmetic wraps, like the i64 instructions it replaces.
+    if let (Literal::Int(lhs), Literal::Int(rhs)) = (lhs, rhs) {
+        return match op {
+            "+" => Some(Literal::Int(lhs.wrapping_add(rhs))),
+            "-" => Some(Literal::Int(lhs.wrapping_sub(rhs))),
+            "*" => Some(Literal::Int(lhs.wrapping_mul(rhs))),
+            // Leave a zero divisor to fail at run time, as a variable one would.
+            "/" | "%" if rhs == 0 => None,
+            "/" => Some(Literal::Int(lhs.wrapping_div(rhs))),
+            "%" => Some(Literal::Int(lhs.wrapping_rem(rhs))),
+            "<" => Some(Literal::Bool(lhs < rhs)),
+            ">" => Some(Literal::Bool(lhs > rhs)),
+            "&" => Some(Literal::Int(lhs & rhs)),
+            "|" => Some(Literal::Int(lhs | rhs)),
+            // A power of integers is a double, as `llvm.pow` gives.
+            "^" => Some(Literal::Float((lhs as f64).powf(rhs as f64))),
+            // The shift amount is taken modulo 64, as in the generated code.
+            "<<" => Some(Literal::Int(lhs.wrapping_shl(rhs as u32))),
+            ">>" => Some(Literal::Int(lhs.wrapping_shr(rhs as u32))),
+            _ => None,
+        };
+    }
+
+    let (lhs, rhs) = (lhs.as_f64(), rhs.as_f64());
+    match op {
+        "+" => Some(Literal::Float(lhs + rhs)),
+        "-" => Some(Literal::Float(lhs - rhs)),
+        "*" => Some(Literal::Float(lhs * rhs)),
+        // Dividing by zero is left to run time, like the integer case.
+        "/" if rhs == 0.0 => None,
+        "/" => Some(Literal::Float(lhs / rhs)),
+        "%" => Some(Literal::Float(lhs % rhs)),
+        "^" => Some(Literal::Float(lhs.powf(rhs))),
+        // Matches the ULT/UGT (or OLT/OGT) comparisons emitted for non-constant operands.
+        "<" => Some(Literal::Bool(
+            lhs < rhs || (!ordered && (lhs.is_nan() || rhs.is_nan())),
+        )),
+        ">" => Some(Literal::Bool(
+            lhs > rhs || (!ordered && (lhs.is_nan() || rhs.is_nan())),
+        )),
+        _ => None,
+    }
+}
+
+// Evaluate a constant expression: literals combined with unary '-' and the builtin
+// operators.
+fn eval_constant(node: &AST, ordered: bool) -> Option<Literal> {
+    match node {
+        AST::Number(num) => Some(num.val),
+        AST::Unary(unary) if unary.op == '-' => {
+            Some(eval_constant(&unary.operand, ordered)?.negate())
+        }
+        AST::Binary(bin) => fold_literals(
+            &bin.op,
+            eval_constant(&bin.lhs, ordered)?,
+            eval_constant(&bin.rhs, ordered)?,
+            ordered,
+        ),
+        _ => None,
+    }
+}
+
+// Report a zero integer divisor `rhs`, whose result LLVM leaves undefined, through the
+// runtime's `kaleidoscope.divzero`.  A compiled program stops there; the JIT records
+// the error and lets the expression finish, dividing by 1.  `i64::MIN / -1` overflows,
+// which LLVM leaves undefined too, so a divisor of -1 is replaced by 1 as well.
+// Returns whether `rhs` was -1, and the divisor to use.
+fn checked_divisor<'ctx>(
+    state: &Compiler<'ctx>,
+    rhs: IntValue<'ctx>,
+) -> (IntValue<'ctx>, IntValue<'ctx>) {
+    let is_zero = state
+        .builder
+        .build_int_compare(EQ, rhs, rhs.get_type().const_zero(), "divzero");
+    let func_value = state
+        .builder
+        .get_insert_block()
+        .unwrap()
+        .get_parent()
+        .unwrap();
+    let zero_bb = state.context.append_basic_block(func_value, "divzero");
+    let cont_bb = state.context.append_basic_block(func_value, "divok");
+    state
+        .builder
+        .build_conditional_branch(is_zero, zero_bb, cont_bb);
+
+    state.builder.position_at_end(zero_bb);
+    let divzero = match state.module.get_function("kaleidoscope.divzero") {
+        Some(divzero) => divzero,
+        None => {
+            let fn_type = state.context.void_type().fn_type(&[], false);
+            state
+                .module
+                .add_function("kaleidoscope.divzero", fn_type, None)
         }
+    };
+    state.builder.build_call(divzero, &[], "");
+    state.builder.build_unconditional_branch(cont_bb);
+
+    state.builder.position_at_end(cont_bb);
+    let minus_one = rhs.get_type().const_int(-1i64 as u64, true);
+    let is_minus_one = state
+        .builder
+        .build_int_compare(EQ, rhs, minus_one, "divminusone");
+    let replaced = state.builder.build_or(is_zero, is_minus_one, "divreplaced");
+    let one = rhs.get_type().const_int(1, false);
+    let divisor = state
+        .builder
+        .build_select(replaced, one, rhs, "divisor")
+        .into_int_value();
+    return (is_minus_one, divisor);
+}
+
+// The shift amount `rhs` modulo 64, like Rust's `wrapping_shl`.  LLVM gives no result
+// for shifting an i64 by a negative amount or by 64 or more.
+fn shift_amount<'ctx>(state: &Compiler<'ctx>, rhs: IntValue<'ctx>) -> IntValue<'ctx> {
+    let mask = rhs.get_type().const_int(63, false);
+    return state.builder.build_and(rhs, mask, "shiftamt");
+}
+
+// The value of an operand when it is known at compile time: a literal, or a variable
+// bound to one that is never assigned.
+fn constant_operand(state: &Compiler, node: &AST) -> Option<Literal> {
+    match node {
+        AST::Number(num) => Some(num.val),
+        AST::Variable(var) => state.lookup_constant(&var.name),
+        _ => None,
     }
 }
 
 // BinaryExprAST - Expression class for a binary operator.
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct BinaryExprAST {
-    op: char,
+    op: String,
     lhs: Box<AST>, // #TODO: Should be an ExprAST
     rhs: Box<AST>,
 }
 
 // TODO: Limit this to ExprAST types using generics, marker traits, etc..
 impl BinaryExprAST {
-    pub fn new(op: char, lhs: AST, rhs: AST) -> Self {
+    pub fn new(op: String, lhs: AST, rhs: AST) -> Self {
         return BinaryExprAST {
             op: op,
             lhs: Box::new(lhs),
             rhs: Box::new(rhs),
         };
     }
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
+    // Evaluate the operator at compile time when both operands are number literals, or
+    // variables known to hold one.  Only builtin operators are folded; user defined ones
+    // keep their call.
+    fn fold_constants(&self, state: &Compiler) -> Option<Literal> {
+        let lhs = constant_operand(state, &self.lhs)?;
+        let rhs = constant_operand(state, &self.rhs)?;
+        fold_literals(&self.op, lhs, rhs, state.ordered_compare)
+    }
+
+    // Emit a builtin operator on two integers.  Comparisons yield a bool.
+    fn codegen_int<'ctx>(
+        &self,
+        state: &Compiler<'ctx>,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> Option<AnyValueEnum<'ctx>> {
+        match self.op.as_str() {
+            "+" => Some(state.builder.build_int_add(lhs, rhs, "addtmp").into()),
+            "-" => Some(state.builder.build_int_sub(lhs, rhs, "subtmp").into()),
+            "*" => Some(state.builder.build_int_mul(lhs, rhs, "multmp").into()),
+            "/" => {
+                // Dividing by -1 negates, wrapping `i64::MIN` around to itself.
+                let (is_minus_one, rhs) = checked_divisor(state, rhs);
+                let quotient = state.builder.build_int_signed_div(lhs, rhs, "divtmp");
+                let negated = state.builder.build_int_neg(lhs, "negtmp");
+                Some(
+                    state
+                        .builder
+                        .build_select(is_minus_one, negated, quotient, "divtmp")
+                        .into(),
+                )
+            }
+            "%" => {
+                // Any remainder by -1 is 0, as it is by 1.
+                let (_, rhs) = checked_divisor(state, rhs);
+                Some(
+                    state
+                        .builder
+                        .build_int_signed_rem(lhs, rhs, "modtmp")
+                        .into(),
+                )
+            }
+            "<" | ">" => {
+                let predicate = if self.op == "<" { SLT } else { SGT };
+                Some(
+                    state
+                        .builder
+                        .build_int_compare(predicate, lhs, rhs, "cmptmp")
+                        .into(),
+                )
+            }
+            "&" => Some(state.builder.build_and(lhs, rhs, "andtmp").into()),
+            "|" => Some(state.builder.build_or(lhs, rhs, "ortmp").into()),
+            "<<" => {
+                let rhs = shift_amount(state, rhs);
+                Some(state.builder.build_left_shift(lhs, rhs, "shltmp").into())
+            }
+            // Arithmetic shift, keeping the sign of the signed i64.
+            ">>" => {
+                let rhs = shift_amount(state, rhs);
+                Some(
+                    state
+                        .builder
+                        .build_right_shift(lhs, rhs, true, "shrtmp")
+                        .into(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
         // Special case '=' because we don't want to emit the LHS as an expression.
-        if let '=' = self.op {
+        if self.op == "=" {
             // Assignment requires the LHS to be an identifier.
             let lhse = match self.lhs.as_ref() {
                 AST::Variable(val) => val,
-                _ => panic!("destination of '=' must be a variable"),
+                _ => return Err(CodegenError::bad_assignment()),
             };
 
-            // Codegen the RHS.
-            let val = codegen(state, self.rhs.as_ref()).into_float_value();
+            // Codegen the RHS, converted to the type of the variable.
+            let val = codegen(state, self.rhs.as_ref())?;
+            let (var, var_type) = lhse.lookup(state)?;
+            // A variable holding a tuple keeps it.
+            if var_type.is_struct_type() {
+                return Err(CodegenError::misplaced_tuple());
+            }
+            let val = coerce_to(state, val, var_type);
+
+            state.builder.build_store(var, val);
+            return Ok(val.into());
+        }
+
+        // Sequencing: evaluate the LHS for its side effects and yield the RHS.
+        if self.op == ":" {
+            codegen(state, self.lhs.as_ref())?;
+            return codegen(state, self.rhs.as_ref());
+        }
+
+        // A user defined bitwise, power or division operator replaces the builtin one.
+        let builtin = !is_overridable_op(&self.op)
+            || !state
+                .function_protos
+                .contains_key(&format!("binary{}", self.op));
 
-            let var = state.named_values.get(&(lhse.name)).unwrap();
+        // Two literal operands of a builtin operator fold into a single constant.
+        if builtin {
+            if let Some(val) = self.fold_constants(state) {
+                return Ok(val.codegen(state));
+            }
+        }
 
-            state.builder.build_store(*var, val);
-            return val.into();
+        let lhs = codegen(state, self.lhs.as_ref())?;
+        let rhs = codegen(state, self.rhs.as_ref())?;
+
+        // Arithmetic on two integers stays integer.
+        if let (true, AnyValueEnum::IntValue(lhs), AnyValueEnum::IntValue(rhs)) =
+            (builtin, lhs, rhs)
+        {
+            let lhs = to_int(state, lhs);
+            let rhs = to_int(state, rhs);
+            if let Some(val) = self.codegen_int(state, lhs, rhs) {
+                return Ok(val);
+            }
         }
 
-        let lhs = codegen(state, self.lhs.as_ref()).into_float_value();
-        let rhs = codegen(state, self.rhs.as_ref()).into_float_value();
+        // The bits of a double mean nothing to these.
+        if builtin && is_bitwise_op(&self.op) {
+            return Err(CodegenError::new(format!(
+                "BinaryExprAST code generation failure. `{}` needs integer operands, not doubles.",
+                self.op
+            )));
+        }
 
-        match self.op {
-            '+' => return state.builder.build_float_add(lhs, rhs, "addtmp").into(),
-            '-' => return state.builder.build_float_sub(lhs, rhs, "subtmp").into(),
-            '*' => return state.builder.build_float_mul(lhs, rhs, "multmp").into(),
-            '<' => {
-                let l = state.builder.build_float_compare(ULT, lhs, rhs, "cmptmp");
-                return state
+        // Otherwise an integer operand is promoted and the operator works on doubles.
+        let lhs = to_float(state, lhs);
+        let rhs = to_float(state, rhs);
+
+        match self.op.as_str() {
+            "+" => return Ok(state.builder.build_float_add(lhs, rhs, "addtmp").into()),
+            "-" => return Ok(state.builder.build_float_sub(lhs, rhs, "subtmp").into()),
+            "*" => return Ok(state.builder.build_float_mul(lhs, rhs, "multmp").into()),
+            "/" if builtin => return Ok(state.builder.build_float_div(lhs, rhs, "divtmp").into()),
+            "%" => return Ok(state.builder.build_float_rem(lhs, rhs, "modtmp").into()),
+            "^" if builtin => {
+                let pow = declare_intrinsic(state, "llvm.pow.f64", 2);
+                let call_site_val =
+                    state
+                        .builder
+                        .build_call(pow, &[lhs.into(), rhs.into()], "powtmp");
+                return Ok(call_site_val.try_as_basic_value().unwrap_left().into());
+            }
+            "<" | ">" => {
+                // Unordered comparisons are true when either side is NaN, ordered
+                // ones are false.  Either way the result is a bool.
+                let predicate = match (self.op.as_str(), state.ordered_compare) {
+                    ("<", false) => ULT,
+                    ("<", true) => OLT,
+                    (_, false) => UGT,
+                    (_, true) => OGT,
+                };
+                return Ok(state
                     .builder
-                    .build_unsigned_int_to_float(l, state.context.f64_type(), "booltmp")
-                    .into();
+                    .build_float_compare(predicate, lhs, rhs, "cmptmp")
+                    .into());
             }
             _ => (),
         };
@@ -111,8 +679,8 @@ impl BinaryExprAST {
         // If it wasn't a builtin binary operator, it must be a user defined one. Emit
         // a call to it.
         let mut func_name = String::from("binary");
-        func_name.push_str(&self.op.to_string());
-        let func_val = get_function(state, &func_name);
+        func_name.push_str(&self.op);
+        let func_val = get_function(state, &func_name)?;
 
         let mut args_v = Vec::new();
         args_v.push(lhs.into());
@@ -122,16 +690,13 @@ impl BinaryExprAST {
             .builder
             .build_call(func_val, args_v.as_slice(), "binop");
 
-        call_site_val
-            .try_as_basic_value()
-            .unwrap_left()
-            .into_float_value()
-            .into()
+        Ok(call_site_val.try_as_basic_value().unwrap_left().into())
     }
 }
 
 // UnaryExprAST - Expression class for a unary operator.
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct UnaryExprAST {
     op: char,
     operand: Box<AST>,
@@ -144,62 +709,406 @@ impl UnaryExprAST {
             operand: Box::new(operand),
         };
     }
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
-        let operand_val = codegen(state, self.operand.as_ref()).into_float_value();
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        // Negating a literal, or a variable known to hold one, folds into a single
+        // negative constant, so `-3.0` is just that and no `fneg`.
+        if self.op == '-' {
+            if let Some(val) = constant_operand(state, &self.operand) {
+                return Ok(val.negate().codegen(state));
+            }
+        }
+
+        let operand_val = codegen(state, self.operand.as_ref())?;
+
+        // Negation is builtin, so negative steps and literals like `-1` work out of the box.
+        if let '-' = self.op {
+            match operand_val {
+                AnyValueEnum::IntValue(int_val) => {
+                    let int_val = to_int(state, int_val);
+                    return Ok(state.builder.build_int_neg(int_val, "negtmp").into());
+                }
+                _ => {
+                    let float_val = operand_val.into_float_value();
+                    return Ok(state.builder.build_float_neg(float_val, "negtmp").into());
+                }
+            }
+        }
+
+        let operand_val = to_float(state, operand_val);
         let mut args_v = Vec::new();
         args_v.push(operand_val.into());
 
         let mut func_name = String::from("unary");
         func_name.push_str(&self.op.to_string());
-        let func_val = get_function(state, &func_name);
+        let func_val = get_function(state, &func_name)?;
         let call_site_val = state
             .builder
             .build_call(func_val, args_v.as_slice(), "binop");
 
-        call_site_val
-            .try_as_basic_value()
-            .unwrap_left()
-            .into_float_value()
-            .into()
+        Ok(call_site_val.try_as_basic_value().unwrap_left().into())
     }
 }
 
 // CallExprAST - Expression class for function calls.
 // TODO: Limit args to ExprAST types using generics, marker traits, etc..
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct CallExprAST {
     callee: String,
     args: Vec<Box<AST>>,
+    // A self-recursive call whose value the function returns.
+    is_tail: bool,
+    loc: SourceLocation,
 }
 
 impl CallExprAST {
-    pub fn new(callee: String, args: Vec<Box<AST>>) -> Self {
-        return CallExprAST { callee, args };
+    pub fn new(callee: String, args: Vec<Box<AST>>, loc: SourceLocation) -> Self {
+        return CallExprAST {
+            callee,
+            args,
+            is_tail: false,
+            loc,
+        };
     }
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
-        let func_val = get_function(state, self.callee.as_str());
-        if func_val.count_params() != self.args.len().try_into().unwrap() {
-            panic!("CallExprAST code generation failure. Incorrect # of arguments passed.");
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        // `invoke` is builtin, unless the program has a function of that name.
+        if self.callee == "invoke" && !state.function_protos.contains_key("invoke") {
+            return self.codegen_invoke(state);
+        }
+        if let Some(intrinsic) = get_intrinsic(state, &self.callee) {
+            return self.codegen_intrinsic(state, intrinsic);
         }
 
-        let mut args_v = Vec::new();
+        let func_val = get_function(state, self.callee.as_str())?;
+        let num_params = func_val.count_params() as usize;
+        let is_var_arg = func_val.get_type().is_var_arg();
+        // A variadic function takes any number of arguments after its named ones.
+        if self.args.len() < num_params || (!is_var_arg && self.args.len() != num_params) {
+            return Err(CodegenError::wrong_arg_count(
+                &self.callee,
+                num_params,
+                is_var_arg,
+                self.args.len(),
+            ));
+        }
+
+        let mut args_v: Vec<BasicMetadataValueEnum> = Vec::new();
+        let mut arg_types: Vec<BasicMetadataTypeEnum> = Vec::new();
+        let mut has_string = false;
+        for (i, arg) in self.args.iter().enumerate() {
+            match arg.as_ref() {
+                AST::String(string) => {
+                    // Variadic arguments have no declared type to disagree with.
+                    has_string |= i < num_params;
+                    let str_val = string.codegen(state);
+                    arg_types.push(str_val.get_type().into());
+                    args_v.push(str_val.into());
+                }
+                _ => {
+                    let arg_val = codegen(state, arg)?;
+                    arg_types.push(state.context.f64_type().into());
+                    args_v.push(to_float(state, arg_val).into());
+                }
+            }
+        }
+
+        // Every named parameter is declared as a double, so an extern taking a string
+        // is called through a pointer cast to the argument types actually passed.
+        let callee: CallableValue = if has_string {
+            if func_val.count_basic_blocks() != 0 {
+                return Err(CodegenError::new(format!(
+                    "CallExprAST code generation failure. Strings can only be passed to extern functions, not `{}`.",
+                    self.callee
+                )));
+            }
+            let return_type = func_val.get_type().get_return_type().unwrap();
+            let fn_type = return_type.fn_type(&arg_types[..num_params], is_var_arg);
+            let fn_ptr = state.builder.build_bitcast(
+                func_val.as_global_value().as_pointer_value(),
+                fn_type.ptr_type(AddressSpace::Generic),
+                "calleecast",
+            );
+            CallableValue::try_from(fn_ptr.into_pointer_value()).unwrap()
+        } else {
+            func_val.into()
+        };
+
+        let call_site_val = state
+            .builder
+            .build_call(callee, args_v.as_slice(), "calltmp");
+        // Arguments are plain values, so the callee can't touch this frame.
+        call_site_val.set_tail_call(self.is_tail);
+        // The call has to follow the callee's convention, or the behavior is undefined.
+        call_site_val.set_call_convention(func_val.get_call_conventions());
+        Ok(call_site_val.try_as_basic_value().unwrap_left().into())
+    }
+}
+
+impl CallExprAST {
+    // invoke(f, args...) - Call the function whose address `f` holds, as made by
+    // `&name`, with the remaining arguments.  Nothing records what `f` points to, so the
+    // call has to trust it: a function returning a double that takes exactly that many
+    // doubles.
+    fn codegen_invoke<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        let (target, args) = match self.args.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(CodegenError::new(String::from(
+                    "CallExprAST code generation failure. `invoke` needs a function address as its first argument.",
+                )))
+            }
+        };
+
+        let f64_type = state.context.f64_type();
+        let target = codegen(state, target)?;
+        let target = to_float(state, target);
+        let mut args_v: Vec<BasicMetadataValueEnum> = Vec::new();
+        for arg in args {
+            let arg_val = codegen(state, arg)?;
+            args_v.push(to_float(state, arg_val).into());
+        }
+
+        // Decode the address and call through it as a pointer to the assumed type.
+        let param_types: Vec<BasicMetadataTypeEnum> = vec![f64_type.into(); args.len()];
+        let fn_type = f64_type.fn_type(&param_types, false);
+        let address =
+            state
+                .builder
+                .build_float_to_signed_int(target, state.context.i64_type(), "fnaddr");
+        let fn_ptr = state.builder.build_int_to_ptr(
+            address,
+            fn_type.ptr_type(AddressSpace::Generic),
+            "fnptr",
+        );
+        let callee = CallableValue::try_from(fn_ptr).unwrap();
+
+        let call_site_val = state
+            .builder
+            .build_call(callee, args_v.as_slice(), "invoketmp");
+        Ok(call_site_val.try_as_basic_value().unwrap_left().into())
+    }
+
+    // Call one of the math builtins, which is the LLVM intrinsic `intrinsic` on doubles.
+    // LLVM knows what these compute, so it can fold them and use the CPU's own
+    // instructions, where a call to a C function would be opaque.
+    fn codegen_intrinsic<'ctx>(
+        &self,
+        state: &mut Compiler<'ctx>,
+        intrinsic: &str,
+    ) -> CodegenResult<'ctx> {
+        let num_params = state.function_protos[&self.callee].args.len();
+        if self.args.len() != num_params {
+            return Err(CodegenError::wrong_arg_count(
+                &self.callee,
+                num_params,
+                false,
+                self.args.len(),
+            ));
+        }
+
+        // Xor works on the bits of integers, which a double has no use for.
+        if intrinsic == "xor" {
+            let mut args_v: Vec<IntValue> = Vec::new();
+            for arg in &self.args {
+                match codegen(state, arg)? {
+                    AnyValueEnum::IntValue(arg_val) => args_v.push(to_int(state, arg_val)),
+                    _ => {
+                        return Err(CodegenError::new(String::from(
+                            "CallExprAST code generation failure. `xor` needs integer arguments, not doubles.",
+                        )))
+                    }
+                }
+            }
+            let xor = state.builder.build_xor(args_v[0], args_v[1], "xortmp");
+            return Ok(xor.into());
+        }
+
+        let mut args_v: Vec<FloatValue> = Vec::new();
         for arg in &self.args {
-            args_v.push(codegen(state, arg).into_float_value().into())
+            let arg_val = codegen(state, arg)?;
+            args_v.push(to_float(state, arg_val));
+        }
+
+        // The remainder is an instruction of its own, the one `%` compiles to.
+        if intrinsic == "frem" {
+            let rem = state
+                .builder
+                .build_float_rem(args_v[0], args_v[1], "remtmp");
+            return Ok(rem.into());
         }
 
+        let func_val = declare_intrinsic(state, intrinsic, num_params);
+
+        let args_v: Vec<BasicMetadataValueEnum> = args_v.into_iter().map(Into::into).collect();
         let call_site_val = state
             .builder
             .build_call(func_val, args_v.as_slice(), "calltmp");
-        call_site_val
-            .try_as_basic_value()
-            .unwrap_left()
-            .into_float_value()
-            .into()
+        Ok(call_site_val.try_as_basic_value().unwrap_left().into())
+    }
+}
+
+// The LLVM intrinsic `name`, taking `num_params` doubles and returning one, declared
+// in the current module if it isn't yet.
+fn declare_intrinsic<'ctx>(
+    state: &Compiler<'ctx>,
+    name: &str,
+    num_params: usize,
+) -> FunctionValue<'ctx> {
+    if let Some(func_val) = state.module.get_function(name) {
+        return func_val;
+    }
+    let f64_type = state.context.f64_type();
+    let param_types: Vec<BasicMetadataTypeEnum> = vec![f64_type.into(); num_params];
+    let fn_type = f64_type.fn_type(&param_types, false);
+    return state.module.add_function(name, fn_type, None);
+}
+
+// The LLVM intrinsic that a call to `name` stands for: sqrt, min, max and the rounding
+// functions, unless the program has defined or declared its own.  `rem` and `xor` are
+// the `frem` and `xor` instructions instead.
+fn get_intrinsic(state: &Compiler, name: &str) -> Option<&'static str> {
+    let intrinsic = match name {
+        "sqrt" => "llvm.sqrt.f64",
+        "min" => "llvm.minnum.f64",
+        "max" => "llvm.maxnum.f64",
+        "floor" => "llvm.floor.f64",
+        "ceil" => "llvm.ceil.f64",
+        "round" => "llvm.round.f64",
+        "rem" => "frem",
+        "xor" => "xor",
+        _ => return None,
+    };
+    match state.function_protos.get(name) {
+        Some(proto) if proto.is_builtin() => Some(intrinsic),
+        _ => None,
+    }
+}
+
+// FunctionRefExprAST - Expression class for taking a function's address, like "&f".
+// The address is carried as a double, which holds it exactly, so it can be stored in
+// variables and passed to functions like any other value, then called with `invoke`.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct FunctionRefExprAST {
+    name: String,
+    loc: SourceLocation,
+}
+
+impl FunctionRefExprAST {
+    pub fn new(name: String, loc: SourceLocation) -> Self {
+        return FunctionRefExprAST { name, loc };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        // An intrinsic is not a function in its own right, so it has no address.
+        if get_intrinsic(state, &self.name).is_some() {
+            return Err(CodegenError::new(format!(
+                "FunctionRefExprAST code generation failure. Cannot take the address of the builtin `{}` at {}.",
+                self.name, self.loc
+            )));
+        }
+        let func_val = get_function(state, &self.name)?;
+
+        // `invoke` can only call C functions of a fixed number of doubles returning one.
+        let f64_type = state.context.f64_type();
+        let fn_type = func_val.get_type();
+        if fn_type.is_var_arg()
+            || fn_type.get_return_type() != Some(f64_type.into())
+            || func_val.get_call_conventions() != CallConv::C.get_id()
+        {
+            return Err(CodegenError::new(format!(
+                "FunctionRefExprAST code generation failure. Cannot take the address of `{}` at {}: only functions of the C calling convention returning a double with a fixed number of arguments can be invoked.",
+                self.name, self.loc
+            )));
+        }
+
+        let address = state.builder.build_ptr_to_int(
+            func_val.as_global_value().as_pointer_value(),
+            state.context.i64_type(),
+            "fnaddr",
+        );
+        Ok(state
+            .builder
+            .build_signed_int_to_float(address, f64_type, "fnref")
+            .into())
+    }
+}
+
+// TupleExprAST - Expression class for a tuple of doubles, like "(a, b)".  A function
+// can return one, to give back more than one value.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct TupleExprAST {
+    elems: Vec<AST>,
+}
+
+impl TupleExprAST {
+    pub fn new(elems: Vec<AST>) -> Self {
+        return TupleExprAST { elems };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        let mut tuple = tuple_type(state, self.elems.len()).get_undef();
+        for (i, elem) in self.elems.iter().enumerate() {
+            let elem_val = codegen(state, elem)?;
+            let elem_val = to_float(state, elem_val);
+            tuple = state
+                .builder
+                .build_insert_value(tuple, elem_val, i as u32, "tuple")
+                .unwrap()
+                .into_struct_value();
+        }
+        return Ok(tuple.into());
+    }
+}
+
+// ProjectionExprAST - Expression class for taking an element out of a tuple, like
+// "x.1".  Elements count from 0.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ProjectionExprAST {
+    tuple: Box<AST>,
+    index: usize,
+    loc: SourceLocation,
+}
+
+impl ProjectionExprAST {
+    pub fn new(tuple: AST, index: usize, loc: SourceLocation) -> Self {
+        return ProjectionExprAST {
+            tuple: Box::new(tuple),
+            index,
+            loc,
+        };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        let tuple = match codegen_any(state, &self.tuple)? {
+            AnyValueEnum::StructValue(tuple) => tuple,
+            _ => {
+                return Err(CodegenError::new(format!(
+                    "ProjectionExprAST code generation failure. `.{}` at {} needs a tuple.",
+                    self.index, self.loc
+                )))
+            }
+        };
+        let size = tuple.get_type().count_fields() as usize;
+        if self.index >= size {
+            return Err(CodegenError::new(format!(
+                "ProjectionExprAST code generation failure. `.{}` at {} is out of range for a tuple of {} elements.",
+                self.index, self.loc, size
+            )));
+        }
+        let elem = state
+            .builder
+            .build_extract_value(tuple, self.index as u32, "elem")
+            .unwrap();
+        return Ok(elem.into());
     }
 }
 
 // IfExprAST - Expression class for if/then/else.
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct IfExprAST {
     cond: Box<AST>,
     then: Box<AST>,
@@ -215,15 +1124,10 @@ impl IfExprAST {
         };
     }
 
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
-        let condv = codegen(state, self.cond.as_ref());
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        let condv = codegen(state, self.cond.as_ref())?;
 
-        let condv_out = state.builder.build_float_compare(
-            ONE,
-            condv.into_float_value(),
-            state.context.f64_type().const_float(0.0),
-            "ifcond",
-        );
+        let condv_out = to_bool(state, condv, "ifcond");
 
         // Needed because in the LLVM context, we are within a function, so let's grab that
         // function object.
@@ -242,34 +1146,79 @@ impl IfExprAST {
 
         // Emit then block
         state.builder.position_at_end(then_bb);
-        let thenv = codegen(state, self.then.as_ref());
-        state.builder.build_unconditional_branch(merge_bb);
+        let thenv = codegen_any(state, self.then.as_ref())?;
         // codegen of 'Then' can change the current block, update ThenBB for the PHI.
         then_bb = state.builder.get_insert_block().unwrap();
 
         // Emit else block
         else_bb.move_after(then_bb).unwrap();
         state.builder.position_at_end(else_bb);
-        let elsev = codegen(state, self.els.as_ref());
-        state.builder.build_unconditional_branch(merge_bb);
+        let elsev = codegen_any(state, self.els.as_ref())?;
         // codegen of 'Else' can change the current block, update ElseBB for the PHI.
         else_bb = state.builder.get_insert_block().unwrap();
 
+        // Only now that both values are known can each branch convert its value to
+        // the type they have in common, before jumping to the merge block.
+        let phi_type = if_result_type(state, thenv, elsev)?;
+        state.builder.position_at_end(then_bb);
+        let thenv = coerce_to(state, thenv, phi_type);
+        state.builder.build_unconditional_branch(merge_bb);
+        state.builder.position_at_end(else_bb);
+        let elsev = coerce_to(state, elsev, phi_type);
+        state.builder.build_unconditional_branch(merge_bb);
+
         // Emit merge block
         merge_bb.move_after(else_bb).unwrap();
         state.builder.position_at_end(merge_bb);
-        let phi_node = state.builder.build_phi(state.context.f64_type(), "iftmp");
-        phi_node.add_incoming(&[
-            (&thenv.into_float_value(), then_bb),
-            (&elsev.into_float_value(), else_bb),
-        ]);
+        let phi_node = state.builder.build_phi(phi_type, "iftmp");
+        phi_node.add_incoming(&[(&thenv, then_bb), (&elsev, else_bb)]);
+
+        return Ok(phi_node.as_basic_value().into());
+    }
+}
 
-        return phi_node.as_basic_value().into();
+// The type of an if whose branches have the values `thenv` and `elsev`: their own type
+// when they agree, or else the wider one, as in arithmetic.  A bool widens to an
+// integer, and an integer to a double.  Tuples must agree exactly.
+fn if_result_type<'ctx>(
+    state: &Compiler<'ctx>,
+    thenv: AnyValueEnum<'ctx>,
+    elsev: AnyValueEnum<'ctx>,
+) -> Result<BasicTypeEnum<'ctx>, CodegenError> {
+    if let (AnyValueEnum::StructValue(then_tuple), AnyValueEnum::StructValue(else_tuple)) =
+        (thenv, elsev)
+    {
+        if then_tuple.get_type() == else_tuple.get_type() {
+            return Ok(then_tuple.get_type().into());
+        }
     }
+
+    // Rank the types from narrowest to widest.
+    let rank = |val: AnyValueEnum<'ctx>| match val {
+        AnyValueEnum::IntValue(int_val) if int_val.get_type().get_bit_width() == 1 => Some(0),
+        AnyValueEnum::IntValue(int_val) if int_val.get_type().get_bit_width() == 64 => Some(1),
+        AnyValueEnum::FloatValue(_) => Some(2),
+        _ => None,
+    };
+    let widest = match (rank(thenv), rank(elsev)) {
+        (Some(then_rank), Some(else_rank)) => then_rank.max(else_rank),
+        _ => {
+            return Err(CodegenError::new(String::from(
+                "IfExprAST code generation failure. The branches have incompatible types: both must be tuples of the same size, or else each a double, an integer or a bool.",
+            )))
+        }
+    };
+    let phi_type = match widest {
+        0 => state.context.bool_type().into(),
+        1 => state.context.i64_type().into(),
+        _ => state.context.f64_type().into(),
+    };
+    return Ok(phi_type);
 }
 
 // ForExprAST - Expression class for for/in.
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct ForExprAST {
     name: String,
     start: Box<AST>,
@@ -289,20 +1238,40 @@ impl ForExprAST {
         };
     }
 
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
         let preheader_bb = state.builder.get_insert_block().unwrap();
         let func_value = preheader_bb.get_parent().unwrap();
 
-        let alloca = create_entry_block_alloca(state, func_value, &self.name);
+        let f64_type = state.context.f64_type().into();
 
         // Emit the start code first, without 'variable' in scope.
-        let start_val = codegen(state, self.start.as_ref());
+        let start_val = codegen(state, self.start.as_ref())?;
+
+        // The variable takes the type of its start, as a `var` does: it counts in
+        // integers from an integer, in doubles from a double.  A bool counts as an integer.
+        let start_val: BasicValueEnum = match start_val {
+            AnyValueEnum::IntValue(int_val) => to_int(state, int_val).into(),
+            AnyValueEnum::StructValue(_) => return Err(CodegenError::misplaced_tuple()),
+            val => to_float(state, val).into(),
+        };
+        let var_type = start_val.get_type();
+        let alloca = create_entry_block_alloca(state, func_value, &self.name, var_type);
 
         // Store the value into alloca
-        state
-            .builder
-            .build_store(alloca, start_val.into_float_value());
-
+        state.builder.build_store(alloca, start_val);
+
+        // When the loop yields its body value, keep the latest one in an alloca. It
+        // starts at 0.0, which is also the result if the body never gets to run.
+        let result_alloca = if state.for_returns_body {
+            let result_alloca = create_entry_block_alloca(state, func_value, "forval", f64_type);
+            state
+                .builder
+                .build_store(result_alloca, state.context.f64_type().const_float(0.0));
+            Some(result_alloca)
+        } else {
+            None
+        };
+
         // Make the new basic block for the loop header, inserting after current
         let loop_bb = state.context.append_basic_block(func_value, "loop");
 
@@ -312,46 +1281,88 @@ impl ForExprAST {
         // start insertion in loop_bb
         state.builder.position_at_end(loop_bb);
 
-        // Within the loop, the variable is defined equal to the PHI node.  If it
-        // shadows an existing variable, we have to restore it, so save it now.
-        let old_val = state.named_values.insert(self.name.clone(), alloca);
+        // Within the loop, the variable is defined equal to the PHI node.  It lives in
+        // its own scope, so any variable it shadows comes back once the loop is done.
+        state.push_scope();
+        state.insert_var(&self.name, alloca, var_type);
+
+        // `continue` jumps to the step, `break` past the loop.
+        let step_bb = state.context.append_basic_block(func_value, "forstep");
+        let after_bb = state.context.append_basic_block(func_value, "afterloop");
 
         // Emit the body of the loop.  This, like any other expr, can change the
-        // current BB.  Note that we ignore the value computed by the body.
-        codegen(state, self.body.as_ref());
+        // current BB.  Note that we ignore the value computed by the body unless the
+        // loop yields it.
+        state.loops.push((step_bb, after_bb));
+        let body_val = codegen(state, self.body.as_ref());
+        state.loops.pop();
+        let body_val = body_val?;
+        if let Some(result_alloca) = result_alloca {
+            let body_val = to_float(state, body_val);
+            state.builder.build_store(result_alloca, body_val);
+        }
 
-        // Emit the step value
-        let step_val: AnyValueEnum;
-        if !matches!(self.step.as_ref(), AST::Null) {
-            step_val = codegen(state, self.step.as_ref());
+        // The body is done; the step and the end condition follow it.
+        state.builder.build_unconditional_branch(step_bb);
+        step_bb
+            .move_after(state.builder.get_insert_block().unwrap())
+            .unwrap();
+        state.builder.position_at_end(step_bb);
+
+        // A body that assigns to the variable may be left to do all the stepping.
+        let manual_step = state.for_manual_step && assigns_to(&self.body, &self.name);
+
+        // Emit the step value, of the variable's type.  An integer variable can't take
+        // a fractional step, which would be truncated to nothing.
+        let step_val: Option<BasicValueEnum> = if manual_step {
+            None
+        } else if !matches!(self.step.as_ref(), AST::Null) {
+            let val = codegen(state, self.step.as_ref())?;
+            if var_type.is_int_type() && !val.is_int_value() {
+                return Err(CodegenError::new(format!(
+                    "ForExprAST code generation failure. The integer loop variable `{}` needs an integer step; start it at a double, such as `0.0`, to count in doubles.",
+                    self.name
+                )));
+            }
+            Some(coerce_to(state, val, var_type))
+        } else if var_type.is_int_type() {
+            // If not specified, use 1.
+            Some(state.context.i64_type().const_int(1, false).into())
         } else {
-            // If not specified, use 1.0.
-            step_val = state.context.f64_type().const_float(1.0).into();
+            Some(state.context.f64_type().const_float(1.0).into())
         };
 
         // Compute the end condition.
-        let end_cond = codegen(state, self.end.as_ref());
+        let end_cond = codegen(state, self.end.as_ref())?;
 
         // Reload, increment, and restore the alloca.  This handles the case where
-        // the body of the loop mutates the variable.
-        let cur_var = state.builder.build_load(alloca, &self.name);
-        let next_var = state.builder.build_float_add(
-            cur_var.into_float_value(),
-            step_val.into_float_value(),
-            "nextvar",
-        );
-        state.builder.build_store(alloca, next_var);
+        // the body of the loop mutates the variable: the step is added on top of
+        // whatever the body stored, so `for i = 0, i < 10 in i = i + 1` counts by two.
+        if let Some(step_val) = step_val {
+            let cur_var = state.builder.build_load(alloca, &self.name);
+            let next_var: BasicValueEnum = match (cur_var, step_val) {
+                (BasicValueEnum::IntValue(cur), BasicValueEnum::IntValue(step)) => {
+                    state.builder.build_int_add(cur, step, "nextvar").into()
+                }
+                _ => state
+                    .builder
+                    .build_float_add(
+                        cur_var.into_float_value(),
+                        step_val.into_float_value(),
+                        "nextvar",
+                    )
+                    .into(),
+            };
+            state.builder.build_store(alloca, next_var);
+        }
 
         // Convert condition to a bool by comparing non-equal to 0.0.
-        let end_cond_val = state.builder.build_float_compare(
-            ONE,
-            end_cond.into_float_value(),
-            state.context.f64_type().const_float(0.0).into(),
-            "loopcond",
-        );
+        let end_cond_val = to_bool(state, end_cond, "loopcond");
 
-        // Create the "after loop" block and insert it.
-        let after_bb = state.context.append_basic_block(func_value, "afterloop");
+        // The "after loop" block comes next.
+        after_bb
+            .move_after(state.builder.get_insert_block().unwrap())
+            .unwrap();
 
         // Insert the conditional branch into the end of LoopEndBB.
         state
@@ -362,72 +1373,245 @@ impl ForExprAST {
         state.builder.position_at_end(after_bb);
 
         // Restore the unshadowed variable
-        if let Some(val) = old_val {
-            state.named_values.insert(self.name.clone(), val);
-        } else {
-            state.named_values.remove(&self.name);
+        state.pop_scope();
+
+        // for expr returns the last body value if asked to, 0.0 otherwise.
+        if let Some(result_alloca) = result_alloca {
+            return Ok(state.builder.build_load(result_alloca, "forval").into());
+        }
+        return Ok(state.context.f64_type().const_float(0.0).into());
+    }
+}
+
+// Jump out of the innermost loop, or to its next iteration.  Code after the jump is
+// unreachable, so it goes in a fresh block that nothing branches to.
+fn codegen_loop_jump<'ctx>(
+    state: &mut Compiler<'ctx>,
+    is_break: bool,
+    loc: SourceLocation,
+) -> CodegenResult<'ctx> {
+    let keyword = if is_break { "break" } else { "continue" };
+    let (continue_bb, break_bb) = match state.loops.last() {
+        Some(&blocks) => blocks,
+        None => {
+            return Err(CodegenError::new(format!(
+                "LoopJump code generation failure. `{keyword}` outside of a loop at {loc}"
+            )))
+        }
+    };
+    let target = if is_break { break_bb } else { continue_bb };
+    state.builder.build_unconditional_branch(target);
+
+    let func_value = target.get_parent().unwrap();
+    let dead_bb = state
+        .context
+        .append_basic_block(func_value, &format!("after{keyword}"));
+    state.builder.position_at_end(dead_bb);
+    return Ok(state.context.f64_type().const_float(0.0).into());
+}
+
+// BreakExprAST - Expression class for `break`, which leaves the innermost loop.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct BreakExprAST {
+    loc: SourceLocation,
+}
+
+impl BreakExprAST {
+    pub fn new(loc: SourceLocation) -> Self {
+        return BreakExprAST { loc };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        codegen_loop_jump(state, true, self.loc)
+    }
+}
+
+// ContinueExprAST - Expression class for `continue`, which starts the next iteration
+// of the innermost loop.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ContinueExprAST {
+    loc: SourceLocation,
+}
+
+impl ContinueExprAST {
+    pub fn new(loc: SourceLocation) -> Self {
+        return ContinueExprAST { loc };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        codegen_loop_jump(state, false, self.loc)
+    }
+}
+
+// WhileExprAST - Expression class for while/do.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct WhileExprAST {
+    cond: Box<AST>,
+    body: Box<AST>,
+}
+
+impl WhileExprAST {
+    pub fn new(cond: AST, body: AST) -> Self {
+        return WhileExprAST {
+            cond: Box::new(cond),
+            body: Box::new(body),
         };
+    }
 
-        // for expr always returns 0.0.
-        return state.context.f64_type().const_float(0.0).into();
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        let preheader_bb = state.builder.get_insert_block().unwrap();
+        let func_value = preheader_bb.get_parent().unwrap();
+
+        // The condition gets its own block, so it is re-evaluated on every iteration.
+        let cond_bb = state.context.append_basic_block(func_value, "whilecond");
+        let body_bb = state.context.append_basic_block(func_value, "whilebody");
+        let after_bb = state.context.append_basic_block(func_value, "afterwhile");
+
+        // Insert an explicit fall through from the current block to the cond_bb.
+        state.builder.build_unconditional_branch(cond_bb);
+
+        // Emit the condition, converting it to a bool by comparing non-equal to 0.0.
+        state.builder.position_at_end(cond_bb);
+        let condv = codegen(state, self.cond.as_ref())?;
+        let cond_val = to_bool(state, condv, "whilecond");
+        state
+            .builder
+            .build_conditional_branch(cond_val, body_bb, after_bb);
+
+        // Emit the body of the loop, ignoring its value, then jump back to the condition.
+        // `continue` jumps to the condition too, `break` past the loop.
+        state.builder.position_at_end(body_bb);
+        state.loops.push((cond_bb, after_bb));
+        let body_val = codegen(state, self.body.as_ref());
+        state.loops.pop();
+        body_val?;
+        state.builder.build_unconditional_branch(cond_bb);
+        after_bb
+            .move_after(state.builder.get_insert_block().unwrap())
+            .unwrap();
+
+        // Any new code will be inserted in after_bb.
+        state.builder.position_at_end(after_bb);
+
+        // while expr always returns 0.0.
+        return Ok(state.context.f64_type().const_float(0.0).into());
     }
 }
 
 // VarExprAST - Expression class for var/in
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct VarExprAST {
-    names: HashMap<String, AST>,
+    // The variables in order, each with its initializer or AST::Null.
+    names: Vec<(String, AST)>,
     body: Box<AST>,
 }
 
 impl VarExprAST {
-    pub fn new(names: HashMap<String, AST>, body: AST) -> Self {
+    pub fn new(names: Vec<(String, AST)>, body: AST) -> Self {
         return VarExprAST {
             names,
             body: Box::new(body),
         };
     }
 
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
         let preheader_bb = state.builder.get_insert_block().unwrap();
         let func_value = preheader_bb.get_parent().unwrap();
 
-        let mut old_bindings: HashMap<String, PointerValue<'ctx>> = HashMap::new();
+        // The variables live in their own scope, shadowing any outer bindings.
+        state.push_scope();
+
+        // A variable initialized to a literal and never assigned always holds that
+        // literal, so its reads can use the literal directly.
+        let assigned = assigned_names(
+            self.names
+                .iter()
+                .map(|(_, init)| init)
+                .chain([self.body.as_ref()]),
+        );
 
-        // Register all variables and emit their initializer.
+        // Register the variables in order, emitting each initializer with the variables
+        // before it bound.  A variable takes the type of its initializer, and is a double
+        // when it has none.
         for (var_name, init) in &self.names {
-            let init_val = match init {
-                AST::Null => state.context.f64_type().const_float(0.0),
-                _ => codegen(state, &init).into_float_value(),
+            let init_val: BasicValueEnum = match init {
+                AST::Null => state.context.f64_type().const_float(0.0).into(),
+                _ => match codegen_any(state, &init)? {
+                    AnyValueEnum::IntValue(int_val) => int_val.into(),
+                    AnyValueEnum::StructValue(tuple) => tuple.into(),
+                    val => to_float(state, val).into(),
+                },
             };
+            let var_type = init_val.get_type();
 
-            let alloca = create_entry_block_alloca(state, func_value, var_name);
+            let alloca = create_entry_block_alloca(state, func_value, var_name, var_type);
 
             // Store the value into alloca
             state.builder.build_store(alloca, init_val);
 
             // Remember this binding
-            let old_val = state.named_values.insert(var_name.to_string(), alloca);
-
-            // Remember the old variable binding so that we can restore the binding when
-            // we unrecurse
-            if let Some(val) = old_val {
-                old_bindings.insert(var_name.to_string(), val);
+            state.insert_var(var_name, alloca, var_type);
+            if let AST::Number(num) = init {
+                if !assigned.contains(var_name) {
+                    state.insert_constant(var_name, num.val);
+                }
             }
         }
 
-        let body_val = codegen(state, &self.body);
+        let body_val = codegen_any(state, &self.body)?;
 
         // Pop all our variables from scope.
-        for (var_name, _) in &self.names {
-            let old_val = old_bindings.get(&var_name.to_string());
-            match old_val {
-                None => state.named_values.remove(var_name),
-                Some(val) => state.named_values.insert(var_name.to_string(), *val),
+        state.pop_scope();
+
+        return Ok(body_val);
+    }
+}
+
+// The names assigned to with '=' anywhere in `nodes`.  Doesn't tell apart variables of
+// the same name in different scopes, which only makes it err on the safe side.
+fn assigned_names<'a>(nodes: impl Iterator<Item = &'a AST>) -> HashSet<String> {
+    let mut assigned = HashSet::new();
+    for node in nodes {
+        assigned = fold(node, assigned, |mut assigned, node| {
+            if let AST::Binary(bin) = node {
+                if let (true, AST::Variable(var)) = (bin.op == "=", bin.lhs.as_ref()) {
+                    assigned.insert(var.name.clone());
+                }
+            }
+            assigned
+        });
+    }
+    return assigned;
+}
+
+// BlockExprAST - Expression class for a block of expressions evaluated in order, like
+// "{ a; b }".  Its value is the last one's, or 0.0 when empty.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct BlockExprAST {
+    exprs: Vec<AST>,
+}
+
+impl BlockExprAST {
+    pub fn new(exprs: Vec<AST>) -> Self {
+        return BlockExprAST { exprs };
+    }
+
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        let mut value = state.context.f64_type().const_float(0.0).into();
+        // Only the last expression, which is the block's value, may be a tuple.
+        for (i, expr) in self.exprs.iter().enumerate() {
+            value = if i + 1 < self.exprs.len() {
+                codegen(state, expr)?
+            } else {
+                codegen_any(state, expr)?
             };
         }
-
-        return body_val;
+        return Ok(value);
     }
 }
 
@@ -435,11 +1619,21 @@ impl VarExprAST {
 // which captures its name, and its argument names (thus implicitly the number
 // of arguments the function takes).
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct PrototypeAST {
     name: String,
     args: Vec<String>,
     is_operator: bool,
     precedence: i32,
+    // Takes more arguments after the named ones, like C's `printf`.
+    is_var_arg: bool,
+    return_type: Type,
+    // Where the prototype starts, which is where the function is defined.
+    loc: SourceLocation,
+    call_conv: CallConv,
+    // One of the prototypes the compiler starts out with, rather than one from the
+    // program.
+    builtin: bool,
 }
 
 impl PrototypeAST {
@@ -447,35 +1641,132 @@ impl PrototypeAST {
         &self.name
     }
 
-    pub fn new(name: String, args: Vec<String>, is_operator: bool, precedence: i32) -> Self {
+    pub fn new(
+        name: String,
+        args: Vec<String>,
+        is_operator: bool,
+        precedence: i32,
+        is_var_arg: bool,
+        return_type: Type,
+        loc: SourceLocation,
+    ) -> Self {
         PrototypeAST {
             name,
             args,
             is_operator,
             precedence,
+            is_var_arg,
+            return_type,
+            loc,
+            call_conv: CallConv::C,
+            builtin: false,
         }
     }
 
-    pub fn codegen<'ctx>(&self, state: &State<'ctx>) -> AnyValueEnum<'ctx> {
+    pub fn set_call_conv(&mut self, call_conv: CallConv) {
+        self.call_conv = call_conv;
+    }
+
+    pub fn get_call_conv(&self) -> CallConv {
+        self.call_conv
+    }
+
+    pub fn set_builtin(&mut self, builtin: bool) {
+        self.builtin = builtin;
+    }
+
+    pub fn is_var_arg(&self) -> bool {
+        self.is_var_arg
+    }
+
+    // Whether this is one of the prototypes the compiler starts out with, rather than
+    // one from the program.
+    pub fn is_builtin(&self) -> bool {
+        self.builtin
+    }
+
+    pub fn get_return_type(&self) -> Type {
+        self.return_type
+    }
+
+    pub fn get_args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn get_loc(&self) -> SourceLocation {
+        self.loc
+    }
+
+    pub fn codegen<'ctx>(&self, state: &Compiler<'ctx>) -> AnyValueEnum<'ctx> {
+        // Declaring a function that already exists, e.g. an extern for an earlier def,
+        // reuses it instead of adding a renamed duplicate.
+        if let Some(func) = state.module.get_function(self.name.as_str()) {
+            return func.into();
+        }
+        return self.declare(state).into();
+    }
+
+    // Generate an `extern`.  Declaring a function that already exists is fine, as long
+    // as the declaration matches it.
+    pub fn codegen_extern<'ctx>(&self, state: &Compiler<'ctx>) -> CodegenResult<'ctx> {
+        if let Some(func) = state.module.get_function(self.name.as_str()) {
+            self.check_matches(state, func, "PrototypeAST", "Redeclaration")?;
+            return Ok(func.into());
+        }
+        return Ok(self.declare(state).into());
+    }
+
+    // Check that `func`, the function of the same name already in the module, takes the
+    // same arguments, returns the same type and is called the same way.  `node` and
+    // `what` say what is being checked, for the error.
+    fn check_matches<'ctx>(
+        &self,
+        state: &Compiler<'ctx>,
+        func: FunctionValue<'ctx>,
+        node: &str,
+        what: &str,
+    ) -> Result<(), CodegenError> {
+        let fn_type = func.get_type();
+        let difference = if func.count_params() as usize != self.args.len() {
+            "a different # of arguments"
+        } else if fn_type.is_var_arg() != self.is_var_arg {
+            "a different variable argument list"
+        } else if fn_type.get_return_type() != Some(self.return_type.codegen(state)) {
+            "a different return type"
+        } else if func.get_call_conventions() != self.call_conv.get_id() {
+            "a different calling convention"
+        } else {
+            return Ok(());
+        };
+        return Err(CodegenError::new(format!(
+            "{node} code generation failure. {what} of `{}` with {difference}.",
+            self.name
+        )));
+    }
+
+    // Add a new function for this prototype to the module.  If one of the same name
+    // is there already, LLVM gives the new one a name of its own.
+    fn declare<'ctx>(&self, state: &Compiler<'ctx>) -> FunctionValue<'ctx> {
         let mut param_types = Vec::new();
         for _ in &self.args {
             param_types.push(state.context.f64_type().into())
         }
 
-        let func_type = state
-            .context
-            .f64_type()
-            .fn_type(param_types.as_slice(), false);
+        let func_type = self
+            .return_type
+            .codegen(state)
+            .fn_type(param_types.as_slice(), self.is_var_arg);
 
         let func = state
             .module
             .add_function(self.name.as_str(), func_type, None);
+        func.set_call_conventions(self.call_conv.get_id());
 
         for (i, arg) in func.get_param_iter().enumerate() {
             arg.into_float_value().set_name(self.args[i].as_str());
         }
 
-        return func.into();
+        return func;
     }
 
     pub fn is_unary_op(&self) -> bool {
@@ -488,147 +1779,1166 @@ impl PrototypeAST {
 
     pub fn get_operator_name(&self) -> &str {
         assert!(self.is_unary_op() || self.is_binary_op());
-        &self.name[&self.name.len() - 1..]
+        if self.is_binary_op() {
+            &self.name["binary".len()..]
+        } else {
+            &self.name["unary".len()..]
+        }
     }
 
     pub fn get_binary_precedence(&self) -> i32 {
         self.precedence
     }
+
+    // Make an operator known to the parser, with its precedence if it is binary.
+    pub fn install_operator(&self, parser: &mut ParserState) {
+        if self.is_unary_op() {
+            parser.unary_ops.insert(self.get_operator_name().into());
+        } else if self.is_binary_op() {
+            parser.bin_op_precedence.insert(
+                self.get_operator_name().into(),
+                self.get_binary_precedence(),
+            );
+        }
+    }
 }
 
 // FunctionAST - This class represents a function definition itself.
 // TODO: Limit proto and body to specific subsets using generics, marker traits, etc.. rather
 // than checking at run-time.
 #[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct FunctionAST {
     proto: Box<AST>,
     body: Box<AST>,
 }
 
 impl FunctionAST {
-    pub fn new(proto: AST, body: AST) -> Self {
+    pub fn new(proto: AST, mut body: AST) -> Self {
         assert!(matches!(proto, AST::Prototype(_)));
         // body must be an ExprAST type
-        assert!(matches!(
-            body,
-            AST::Number(_)
-                | AST::Variable(_)
-                | AST::Binary(_)
-                | AST::Unary(_)
-                | AST::Call(_)
-                | AST::If(_)
-                | AST::For(_)
-                | AST::Var(_)
-        ), "Unexpected variable {:?}", body);
+        assert!(
+            matches!(
+                body,
+                AST::Number(_)
+                    | AST::String(_)
+                    | AST::Variable(_)
+                    | AST::Binary(_)
+                    | AST::Unary(_)
+                    | AST::Call(_)
+                    | AST::FunctionRef(_)
+                    | AST::Tuple(_)
+                    | AST::Projection(_)
+                    | AST::If(_)
+                    | AST::For(_)
+                    | AST::While(_)
+                    | AST::Break(_)
+                    | AST::Continue(_)
+                    | AST::Var(_)
+                    | AST::Block(_)
+            ),
+            "Unexpected variable {:?}",
+            body
+        );
+        if let AST::Prototype(proto) = &proto {
+            mark_tail_calls(&mut body, &proto.name);
+        }
         FunctionAST {
             proto: Box::new(proto),
             body: Box::new(body),
         }
     }
 
-    pub fn codegen<'ctx>(&self, state: &mut State<'ctx>) -> AnyValueEnum<'ctx> {
-        // Get the proto body
-        let proto = match self.proto.as_ref() {
+    pub fn get_proto(&self) -> &PrototypeAST {
+        match self.proto.as_ref() {
             AST::Prototype(val) => val,
-            _ => panic!(
-                "FunctionAST code generation failure, expected a ProtoTypeAST for proto field."
-            ),
-        };
-
-        // Transfer ownership of the prototype to the FunctionProtos map
-        state
-            .function_protos
-            .insert(proto.get_name().to_string(), proto.clone());
-
-        let func_value = get_function(state, proto.get_name());
+            _ => panic!("FunctionAST failure, expected a ProtoTypeAST for proto field."),
+        }
+    }
 
-        // If this is an operator, install it.
-        if proto.is_binary_op() {
-            state.bin_op_precedence.insert(
-                proto.get_operator_name().into(),
-                proto.get_binary_precedence(),
-            );
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        // Get the proto body
+        let proto = self.get_proto();
+        let name = proto.get_name();
+
+        // The function may already exist, either declared by an extern or defined
+        // earlier.  Either way it must take the same arguments.
+        let old_func = state.module.get_function(name);
+        if let Some(func_value) = old_func {
+            proto.check_matches(state, func_value, "FunctionAST", "Redefinition")?;
         }
 
+        // The definition is compiled into a new function.  One that already exists keeps
+        // its body, its callers and its prototype until the new one has compiled, so a
+        // definition that fails leaves everything as it was.
+        let old_proto = state
+            .function_protos
+            .insert(name.to_string(), proto.clone());
+        let func_value = proto.declare(state);
+
         // Create a new basic block to start insertion into.
         let basic_block = state.context.append_basic_block(func_value, "entry");
         state.builder.position_at_end(basic_block);
 
-        // Record the function arguments in the NamedValues map.
-        state.named_values.clear();
+        // With debug info, the function's code starts out at the prototype.
+        if let Some(debug_info) = state.debug_info.as_mut() {
+            debug_info.begin_function(state.context, &state.builder, func_value, proto);
+        }
+        let result = self.codegen_body(state, func_value);
+        if let Some(debug_info) = state.debug_info.as_mut() {
+            debug_info.end_function(&state.builder);
+        }
+
+        if let Err(err) = result {
+            // Nothing uses the new function yet, so it can simply go.
+            unsafe { func_value.delete() };
+            match old_proto {
+                Some(old_proto) => state.function_protos.insert(name.to_string(), old_proto),
+                None => state.function_protos.remove(name),
+            };
+            return Err(err);
+        }
+
+        // Redefinition: move the callers of the old function over to the new one, which
+        // then takes its name.
+        if let Some(old_func) = old_func {
+            old_func.replace_all_uses_with(func_value);
+            unsafe { old_func.delete() };
+            set_value_name(func_value, name);
+            state.redefine(name);
+        }
+
+        check_module(state, proto.loc);
+        return Ok(func_value.into());
+    }
+
+    // Generate the body of the function into its fresh entry block.
+    fn codegen_body<'ctx>(
+        &self,
+        state: &mut Compiler<'ctx>,
+        func_value: FunctionValue<'ctx>,
+    ) -> CodegenResult<'ctx> {
+        let proto = self.get_proto();
+
+        // Record the function arguments in a fresh NamedValues scope stack.
+        state.clear_scopes();
+        state.loops.clear();
+        state.push_scope();
         for arg in func_value.get_param_iter() {
             // Create an alloca for this variable.
             let arg_float_val = arg.into_float_value();
             let arg_name = arg_float_val.get_name().to_str().unwrap();
-            let alloca = create_entry_block_alloca(state, func_value, arg_name);
+            let f64_type = state.context.f64_type().into();
+            let alloca = create_entry_block_alloca(state, func_value, arg_name, f64_type);
 
             // Store the initial value into the alloca.
             state.builder.build_store(alloca, arg);
 
             // Add arguments to variable symbol table.
-            state.named_values.insert(arg_name.to_string(), alloca);
+            state.insert_var(arg_name, alloca, f64_type);
         }
 
-        let retval = codegen(state, &*self.body).into_float_value();
+        let retval =
+            codegen_any(state, &*self.body).and_then(|val| coerce_return(state, val, proto))?;
         state.builder.build_return(Some(&retval));
 
-        assert!(
-            func_value.verify(false),
-            "FunctionAST code generation failure. LLVM could not verify function."
-        );
+        // Validate the generated code.  LLVM only describes the problems of a whole
+        // module, which are this function's own as long as the rest of the module is
+        // valid, as check_module keeps it.
+        if !func_value.verify(false) {
+            let reason = match state.module.verify() {
+                Err(reason) => reason.to_string(),
+                Ok(()) => String::new(),
+            };
+            return Err(CodegenError::new(format!(
+                "FunctionAST code generation failure. LLVM could not verify function `{}`: {}",
+                proto.get_name(),
+                reason.trim_end()
+            )));
+        }
 
         state.fpm.run_on(&func_value);
+        self.warn_unused_params(state);
+
+        return Ok(func_value.into());
+    }
+
+    // Warn about the parameters the body never mentions, which are often typos.  A
+    // name starting with '_' says the parameter is meant to go unused.
+    fn warn_unused_params(&self, state: &mut Compiler) {
+        let proto = self.get_proto();
+        let used = fold(&self.body, HashSet::new(), |mut used, node| {
+            if let AST::Variable(var) = node {
+                used.insert(var.name.clone());
+            }
+            used
+        });
+        for arg in &proto.args {
+            if !arg.starts_with('_') && !used.contains(arg) {
+                let message = format!("Unused parameter `{arg}` of `{}`", proto.get_name());
+                state.warn(message, Span::at(proto.loc));
+            }
+        }
+    }
+}
+
+// Convert the value of a function's body to the type its prototype returns.  Scalars
+// convert to each other, but a tuple must be returned by a function declared to return
+// a tuple of that size, and only by one.
+fn coerce_return<'ctx>(
+    state: &Compiler<'ctx>,
+    val: AnyValueEnum<'ctx>,
+    proto: &PrototypeAST,
+) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+    let return_type = proto.return_type.codegen(state);
+    match (proto.return_type, val) {
+        (Type::Tuple(_), AnyValueEnum::StructValue(tuple))
+            if BasicTypeEnum::from(tuple.get_type()) == return_type =>
+        {
+            Ok(tuple.into())
+        }
+        (Type::Tuple(_), _) | (_, AnyValueEnum::StructValue(_)) => {
+            Err(CodegenError::new(format!(
+                "FunctionAST code generation failure. The body of `{}` does not give the {} it returns.",
+                proto.get_name(),
+                proto.return_type
+            )))
+        }
+        _ => Ok(coerce_to(state, val, return_type)),
+    }
+}
+
+// Mark the calls to `name` whose value is the value of `node`: the node itself, either
+// branch of an if, the right side of ':', the body of a var and the end of a block.
+fn mark_tail_calls(node: &mut AST, name: &str) {
+    match node {
+        AST::Call(call) => call.is_tail = call.callee == name,
+        AST::If(if_expr) => {
+            mark_tail_calls(&mut if_expr.then, name);
+            mark_tail_calls(&mut if_expr.els, name);
+        }
+        AST::Binary(bin) if bin.op == ":" => mark_tail_calls(&mut bin.rhs, name),
+        AST::Var(var_expr) => mark_tail_calls(&mut var_expr.body, name),
+        AST::Block(block) => {
+            if let Some(last) = block.exprs.last_mut() {
+                mark_tail_calls(last, name);
+            }
+        }
+        _ => (),
+    }
+}
+
+// GlobalAST - This class represents a global variable definition, which lives for
+// the whole program and is visible from every function.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct GlobalAST {
+    name: String,
+    init: Box<AST>,
+}
+
+impl GlobalAST {
+    pub fn new(name: String, init: AST) -> Self {
+        return GlobalAST {
+            name,
+            init: Box::new(init),
+        };
+    }
 
-        return func_value.into();
+    pub fn codegen<'ctx>(&self, state: &mut Compiler<'ctx>) -> CodegenResult<'ctx> {
+        // The initial value is part of the global itself, so it must be known now.
+        let init = eval_constant(&self.init, state.ordered_compare)
+            .ok_or_else(|| CodegenError::non_constant_global(&self.name))?;
+
+        // An integer stays an integer; anything else is stored as a double, as in var.
+        let (var_type, init_val): (BasicTypeEnum, BasicValueEnum) = match init.promote() {
+            Literal::Int(val) => {
+                let i64_type = state.context.i64_type();
+                (i64_type.into(), i64_type.const_int(val as u64, true).into())
+            }
+            val => {
+                let f64_type = state.context.f64_type();
+                (f64_type.into(), f64_type.const_float(val.as_f64()).into())
+            }
+        };
+
+        if state.module.get_function(&self.name).is_some() {
+            return Err(CodegenError::new(format!(
+                "GlobalAST code generation failure. `{}` is already a function.",
+                self.name
+            )));
+        }
+
+        // Redefining a global only changes its initial value.
+        if let Some(&old_type) = state.globals.get(&self.name) {
+            if old_type != var_type {
+                return Err(CodegenError::new(format!(
+                    "GlobalAST code generation failure. Redefinition of `{}` with a different type.",
+                    self.name
+                )));
+            }
+        }
+
+        let global = match state.module.get_global(&self.name) {
+            Some(global) => {
+                state.redefine(&self.name);
+                global
+            }
+            None => state.module.add_global(var_type, None, &self.name),
+        };
+        global.set_initializer(&init_val);
+        state.globals.insert(self.name.clone(), var_type);
+
+        return Ok(global.as_pointer_value().into());
     }
 }
 
-// General code generation function
+// Serialize a tree to JSON, e.g. for editor tooling or golden-file tests of the parser.
+#[cfg(feature = "json")]
+pub fn ast_to_json(node: &AST) -> Result<String, serde_json::Error> {
+    return serde_json::to_string(node);
+}
+
+// General code generation function, for a single value.  Tuples can only be returned,
+// bound with var and projected, and those places use codegen_any instead.
+pub fn codegen<'ctx>(state: &mut Compiler<'ctx>, node: &AST) -> CodegenResult<'ctx> {
+    let val = codegen_any(state, node)?;
+    if let AnyValueEnum::StructValue(_) = val {
+        return Err(CodegenError::misplaced_tuple());
+    }
+    return Ok(val);
+}
+
+// Generate code for any node, whose value may be a tuple.
+pub fn codegen_any<'ctx>(state: &mut Compiler<'ctx>, node: &AST) -> CodegenResult<'ctx> {
+    // With debug info, a node that knows where it is in the source gives its position
+    // to its instructions, and the enclosing node's comes back afterwards.
+    let outer_loc = match node.get_loc() {
+        Some(loc) => state.set_debug_location(loc),
+        None => None,
+    };
+    let result = codegen_node(state, node);
+    if let Some(outer_loc) = outer_loc {
+        state.set_debug_location(outer_loc);
+    }
+    return result;
+}
+
 // TODO: There's got to be a better way -- presumably with anonymous functions
-pub fn codegen<'ctx>(state: &mut State<'ctx>, node: &AST) -> AnyValueEnum<'ctx> {
+fn codegen_node<'ctx>(state: &mut Compiler<'ctx>, node: &AST) -> CodegenResult<'ctx> {
     match node {
-        AST::Number(inner_val) => inner_val.codegen(state),
+        AST::Number(inner_val) => Ok(inner_val.codegen(state)),
+        // Call arguments generate their strings directly; anywhere else is an error.
+        AST::String(_) => Err(CodegenError::misplaced_string()),
         AST::Variable(inner_val) => inner_val.codegen(state),
         AST::Binary(inner_val) => inner_val.codegen(state),
         AST::Unary(inner_val) => inner_val.codegen(state),
         AST::Call(inner_val) => inner_val.codegen(state),
+        AST::FunctionRef(inner_val) => inner_val.codegen(state),
+        AST::Tuple(inner_val) => inner_val.codegen(state),
+        AST::Projection(inner_val) => inner_val.codegen(state),
         AST::If(inner_val) => inner_val.codegen(state),
         AST::For(inner_val) => inner_val.codegen(state),
+        AST::While(inner_val) => inner_val.codegen(state),
+        AST::Break(inner_val) => inner_val.codegen(state),
+        AST::Continue(inner_val) => inner_val.codegen(state),
         AST::Var(inner_val) => inner_val.codegen(state),
-        AST::Prototype(inner_val) => inner_val.codegen(state),
+        AST::Block(inner_val) => inner_val.codegen(state),
+        AST::Prototype(inner_val) => inner_val.codegen_extern(state),
         AST::Function(inner_val) => inner_val.codegen(state),
-        _ => panic!(
-            "General code generation failure. Could not find key `{:?}`",
-            node
-        ),
+        AST::Global(inner_val) => inner_val.codegen(state),
+        // Only stands in for a missing part, such as a `for` without a step, which
+        // its parent handles itself.
+        AST::Null => Err(CodegenError::new(String::from(
+            "General code generation failure. An empty expression has no value.",
+        ))),
     }
 }
 
-// General helper to get function
-pub fn get_function<'ctx>(state: &mut State<'ctx>, name: &str) -> FunctionValue<'ctx> {
+// Find the global variable `name` and its type.  A global defined in another module is
+// declared in the current one, leaving the JIT to link it.
+pub fn get_global<'ctx>(
+    state: &Compiler<'ctx>,
+    name: &str,
+) -> Option<(PointerValue<'ctx>, BasicTypeEnum<'ctx>)> {
+    let var_type = *state.globals.get(name)?;
+    let global = match state.module.get_global(name) {
+        Some(global) => global,
+        None => state.module.add_global(var_type, None, name),
+    };
+    return Some((global.as_pointer_value(), var_type));
+}
+
+// Synthesize the program entry point `i32 main()`, which evaluates the collected
+// top-level expressions in order and returns 0.
+pub fn build_main<'ctx>(state: &mut Compiler<'ctx>) -> Result<FunctionValue<'ctx>, CodegenError> {
+    // Only one `main` may exist, whether the user wrote it or it was built before.
+    if state.module.get_function("main").is_some() {
+        return Err(CodegenError::new(String::from(
+            "build_main failure. `main` is already defined.",
+        )));
+    }
+
+    let i32_type = state.context.i32_type();
+    let main_value = state
+        .module
+        .add_function("main", i32_type.fn_type(&[], false), None);
+    let basic_block = state.context.append_basic_block(main_value, "entry");
+    state.builder.position_at_end(basic_block);
+
+    for name in &state.toplevel_exprs {
+        let func_value = state.module.get_function(name).unwrap();
+        state.builder.build_call(func_value, &[], "toplevel");
+    }
+    state
+        .builder
+        .build_return(Some(&i32_type.const_int(0, false)));
+
+    return Ok(main_value);
+}
+
+// General helper to get function.  A function is declared in a module at most once:
+// the module is searched first, and PrototypeAST::codegen reuses a declaration already
+// there, so calling the same extern from several functions shares one declaration.
+pub fn get_function<'ctx>(
+    state: &mut Compiler<'ctx>,
+    name: &str,
+) -> Result<FunctionValue<'ctx>, CodegenError> {
     let val = state.module.get_function(name);
     if let Some(func_val) = val {
-        return func_val;
+        return Ok(func_val);
     };
 
     let proto_some = state.function_protos.get(&name.to_string());
     match proto_some {
-        Some(proto) => return proto.codegen(state).into_function_value(),
-        None => panic!("get_function failure. Could not find key `{name}`",),
+        Some(proto) => return Ok(proto.codegen(state).into_function_value()),
+        None => return Err(CodegenError::unknown_function(name)),
+    }
+}
+
+// Verify the whole module after a definition has changed it.  The new function has
+// been verified already, so anything still wrong is in some other function, such as
+// one an embedder added.  Each of those is reported by name, as a warning at `loc`,
+// and removed unless something calls it, which would leave the module broken anyway.
+fn check_module(state: &mut Compiler, loc: SourceLocation) {
+    let reason = match state.module.verify() {
+        Ok(()) => return,
+        Err(reason) => reason.to_string(),
+    };
+    let broken: Vec<FunctionValue> = state
+        .module
+        .get_functions()
+        .filter(|func| !func.verify(false))
+        .collect();
+    for func in broken {
+        let name = func.get_name().to_string_lossy().into_owned();
+        let used = unsafe { !LLVMGetFirstUse(func.as_value_ref()).is_null() };
+        let message = if used {
+            format!(
+                "LLVM could not verify function `{name}`, which is kept as it is called: {}",
+                reason.trim_end()
+            )
+        } else {
+            unsafe { func.delete() };
+            if !state
+                .function_protos
+                .get(&name)
+                .map_or(true, PrototypeAST::is_builtin)
+            {
+                state.function_protos.remove(&name);
+            }
+            format!(
+                "LLVM could not verify function `{name}`, which was removed: {}",
+                reason.trim_end()
+            )
+        };
+        state.warn(message, Span::at(loc));
+    }
+}
+
+// Rename a function or global.  inkwell can only name one when adding it, so this goes
+// to LLVM directly.
+pub fn set_value_name(value: impl AsValueRef, name: &str) {
+    unsafe {
+        LLVMSetValueName2(
+            value.as_value_ref(),
+            name.as_ptr() as *const c_char,
+            name.len(),
+        );
+    }
+}
+
+// Coerce a numeric value to a double.  Integers convert implicitly wherever a double is
+// expected: mixed arithmetic, variables, call arguments and return values.
+pub fn to_float<'ctx>(state: &Compiler<'ctx>, val: AnyValueEnum<'ctx>) -> FloatValue<'ctx> {
+    match val {
+        // A boolean is 1.0 or 0.0.
+        AnyValueEnum::IntValue(int_val) if int_val.get_type().get_bit_width() == 1 => state
+            .builder
+            .build_unsigned_int_to_float(int_val, state.context.f64_type(), "booltofp"),
+        AnyValueEnum::IntValue(int_val) => {
+            state
+                .builder
+                .build_signed_int_to_float(int_val, state.context.f64_type(), "inttofp")
+        }
+        _ => val.into_float_value(),
+    }
+}
+
+// Convert a value to the type of the variable it is stored into.  Doubles assigned to
+// an integer are truncated towards zero.
+pub fn coerce_to<'ctx>(
+    state: &Compiler<'ctx>,
+    val: AnyValueEnum<'ctx>,
+    var_type: BasicTypeEnum<'ctx>,
+) -> BasicValueEnum<'ctx> {
+    match (var_type, val) {
+        // Tuples only ever meet tuples of their own type.
+        (BasicTypeEnum::StructType(_), _) => val.into_struct_value().into(),
+        (BasicTypeEnum::IntType(int_type), _) if int_type.get_bit_width() == 1 => {
+            to_bool(state, val, "tobool").into()
+        }
+        (BasicTypeEnum::IntType(_), AnyValueEnum::IntValue(int_val)) => {
+            to_int(state, int_val).into()
+        }
+        (BasicTypeEnum::IntType(int_type), _) => state
+            .builder
+            .build_float_to_signed_int(val.into_float_value(), int_type, "fptoint")
+            .into(),
+        _ => to_float(state, val).into(),
+    }
+}
+
+// Widen a boolean to a 64-bit integer, leaving integers as they are.
+pub fn to_int<'ctx>(state: &Compiler<'ctx>, val: IntValue<'ctx>) -> IntValue<'ctx> {
+    if val.get_type().get_bit_width() == 1 {
+        return state
+            .builder
+            .build_int_z_extend(val, state.context.i64_type(), "booltoint");
+    }
+    return val;
+}
+
+// Convert a condition to a bool by comparing non-equal to zero.  Booleans are used as is.
+pub fn to_bool<'ctx>(
+    state: &Compiler<'ctx>,
+    val: AnyValueEnum<'ctx>,
+    name: &str,
+) -> IntValue<'ctx> {
+    match val {
+        AnyValueEnum::IntValue(int_val) if int_val.get_type().get_bit_width() == 1 => int_val,
+        AnyValueEnum::IntValue(int_val) => {
+            state
+                .builder
+                .build_int_compare(NE, int_val, int_val.get_type().const_zero(), name)
+        }
+        _ => state.builder.build_float_compare(
+            ONE,
+            val.into_float_value(),
+            state.context.f64_type().const_float(0.0),
+            name,
+        ),
     }
 }
 
-// func_value.get_first_basic_block();
-// create a builder and position at basic block
-// use the builder to generate an alloca
-// state.builder.build_alloca(state.context.f64_type(), name);
+// Create an alloca at the top of the function's entry block, before its first
+// instruction, so that all allocas sit together where mem2reg looks for them.  The
+// shared builder is moved there for the alloca and then put back at the end of the
+// block it was filling.
 pub fn create_entry_block_alloca<'ctx>(
-    state: &mut State<'ctx>,
+    state: &mut Compiler<'ctx>,
     func_value: FunctionValue<'ctx>,
     name: &str,
+    var_type: BasicTypeEnum<'ctx>,
 ) -> PointerValue<'ctx> {
     let entry_bb = func_value.get_first_basic_block().unwrap();
-    let builder = state.context.create_builder();
-    builder.position_at_end(entry_bb);
-    builder.build_alloca(state.context.f64_type(), name)
+    let current_bb = state.builder.get_insert_block();
+
+    match entry_bb.get_first_instruction() {
+        Some(first_instr) => state.builder.position_before(&first_instr),
+        None => state.builder.position_at_end(entry_bb),
+    }
+    let alloca = state.builder.build_alloca(var_type, name);
+
+    if let Some(current_bb) = current_bb {
+        state.builder.position_at_end(current_bb);
+    }
+    return alloca;
+}
+
+// Checker - The names visible to the `--check` pass, which reports the errors codegen
+// would run into without generating any code.
+pub struct Checker {
+    // Number of named arguments of every function defined or declared so far, and
+    // whether it takes more.
+    functions: HashMap<String, (usize, bool)>,
+    // Variables in scope, one list per nested scope with the innermost last.
+    scopes: Vec<Vec<String>>,
+    // Global variables defined so far.
+    globals: HashSet<String>,
+    // Number of loops around the current node, for `break` and `continue`.
+    loop_depth: usize,
+    pub errors: Vec<CodegenError>,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        let functions = crate::builtin_protos()
+            .into_iter()
+            .map(|(name, proto)| (name, (proto.args.len(), proto.is_var_arg)))
+            .collect();
+        return Checker {
+            functions,
+            scopes: Vec::new(),
+            globals: HashSet::new(),
+            loop_depth: 0,
+            errors: Vec::new(),
+        };
+    }
+
+    fn is_bound(&self, name: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| scope.iter().any(|var| var == name))
+            || self.globals.contains(name)
+    }
+
+    fn check_variable(&mut self, var: &VariableExprAST) {
+        if !self.is_bound(&var.name) {
+            self.errors
+                .push(CodegenError::unknown_variable(&var.name, var.loc));
+        }
+    }
+
+    fn check_call(&mut self, callee: &str, arg_count: usize) {
+        match self.functions.get(callee) {
+            None => self.errors.push(CodegenError::unknown_function(callee)),
+            Some(&(expected, is_var_arg))
+                if arg_count < expected || (!is_var_arg && arg_count != expected) =>
+            {
+                self.errors.push(CodegenError::wrong_arg_count(
+                    callee, expected, is_var_arg, arg_count,
+                ));
+            }
+            Some(_) => (),
+        }
+    }
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Checker::new()
+    }
+}
+
+// Visitor - A pass over the tree.  There is one method per kind of node, and by default
+// each just visits the node's children, so a pass only overrides the nodes it cares
+// about.  `visit` is the entry point; it dispatches on the kind of node.
+pub trait Visitor {
+    fn visit(&mut self, node: &AST) {
+        walk(self, node);
+    }
+
+    fn visit_number(&mut self, _num: &NumberExprAST) {}
+
+    fn visit_string(&mut self, _string: &StringExprAST) {}
+
+    fn visit_variable(&mut self, _var: &VariableExprAST) {}
+
+    fn visit_binary(&mut self, bin: &BinaryExprAST) {
+        self.visit(&bin.lhs);
+        self.visit(&bin.rhs);
+    }
+
+    fn visit_unary(&mut self, unary: &UnaryExprAST) {
+        self.visit(&unary.operand);
+    }
+
+    fn visit_call(&mut self, call: &CallExprAST) {
+        for arg in &call.args {
+            self.visit(arg);
+        }
+    }
+
+    fn visit_function_ref(&mut self, _func_ref: &FunctionRefExprAST) {}
+
+    fn visit_tuple(&mut self, tuple: &TupleExprAST) {
+        for elem in &tuple.elems {
+            self.visit(elem);
+        }
+    }
+
+    fn visit_projection(&mut self, projection: &ProjectionExprAST) {
+        self.visit(&projection.tuple);
+    }
+
+    fn visit_if(&mut self, if_expr: &IfExprAST) {
+        self.visit(&if_expr.cond);
+        self.visit(&if_expr.then);
+        self.visit(&if_expr.els);
+    }
+
+    fn visit_for(&mut self, for_expr: &ForExprAST) {
+        self.visit(&for_expr.start);
+        self.visit(&for_expr.end);
+        self.visit(&for_expr.step);
+        self.visit(&for_expr.body);
+    }
+
+    fn visit_while(&mut self, while_expr: &WhileExprAST) {
+        self.visit(&while_expr.cond);
+        self.visit(&while_expr.body);
+    }
+
+    fn visit_break(&mut self, _break_expr: &BreakExprAST) {}
+
+    fn visit_continue(&mut self, _continue_expr: &ContinueExprAST) {}
+
+    fn visit_var(&mut self, var_expr: &VarExprAST) {
+        for (_, init) in &var_expr.names {
+            self.visit(init);
+        }
+        self.visit(&var_expr.body);
+    }
+
+    fn visit_block(&mut self, block: &BlockExprAST) {
+        for expr in &block.exprs {
+            self.visit(expr);
+        }
+    }
+
+    fn visit_prototype(&mut self, _proto: &PrototypeAST) {}
+
+    fn visit_function(&mut self, function: &FunctionAST) {
+        self.visit_prototype(function.get_proto());
+        self.visit(&function.body);
+    }
+
+    fn visit_global(&mut self, global: &GlobalAST) {
+        self.visit(&global.init);
+    }
+}
+
+// Hand a node to the visitor method for its kind.
+pub fn walk<V: Visitor + ?Sized>(visitor: &mut V, node: &AST) {
+    match node {
+        AST::Null => (),
+        AST::Number(num) => visitor.visit_number(num),
+        AST::String(string) => visitor.visit_string(string),
+        AST::Variable(var) => visitor.visit_variable(var),
+        AST::Binary(bin) => visitor.visit_binary(bin),
+        AST::Unary(unary) => visitor.visit_unary(unary),
+        AST::Call(call) => visitor.visit_call(call),
+        AST::FunctionRef(func_ref) => visitor.visit_function_ref(func_ref),
+        AST::Tuple(tuple) => visitor.visit_tuple(tuple),
+        AST::Projection(projection) => visitor.visit_projection(projection),
+        AST::If(if_expr) => visitor.visit_if(if_expr),
+        AST::For(for_expr) => visitor.visit_for(for_expr),
+        AST::While(while_expr) => visitor.visit_while(while_expr),
+        AST::Break(break_expr) => visitor.visit_break(break_expr),
+        AST::Continue(continue_expr) => visitor.visit_continue(continue_expr),
+        AST::Var(var_expr) => visitor.visit_var(var_expr),
+        AST::Block(block) => visitor.visit_block(block),
+        AST::Prototype(proto) => visitor.visit_prototype(proto),
+        AST::Function(function) => visitor.visit_function(function),
+        AST::Global(global) => visitor.visit_global(global),
+    }
+}
+
+// Fold `f` over every node of the tree, parents before children.  For example, the
+// number of calls in a program is
+//   fold(&node, 0, |count, node| count + matches!(node, AST::Call(_)) as usize)
+pub fn fold<T, F: FnMut(T, &AST) -> T>(node: &AST, init: T, f: F) -> T {
+    struct Folder<T, F> {
+        acc: Option<T>,
+        f: F,
+    }
+
+    impl<T, F: FnMut(T, &AST) -> T> Visitor for Folder<T, F> {
+        fn visit(&mut self, node: &AST) {
+            let acc = self.acc.take().unwrap();
+            self.acc = Some((self.f)(acc, node));
+            walk(self, node);
+        }
+    }
+
+    let mut folder = Folder { acc: Some(init), f };
+    folder.visit(node);
+    return folder.acc.unwrap();
+}
+
+// Whether `node` assigns to the variable `name` it sees.  The parts where an inner `var`
+// or `for` binds its own `name` assign to that one instead, so they are skipped.
+fn assigns_to(node: &AST, name: &str) -> bool {
+    struct AssignmentFinder<'a> {
+        name: &'a str,
+        found: bool,
+    }
+
+    impl Visitor for AssignmentFinder<'_> {
+        fn visit_binary(&mut self, bin: &BinaryExprAST) {
+            if let (true, AST::Variable(var)) = (bin.op == "=", bin.lhs.as_ref()) {
+                self.found |= var.name == self.name;
+            }
+            self.visit(&bin.lhs);
+            self.visit(&bin.rhs);
+        }
+
+        fn visit_for(&mut self, for_expr: &ForExprAST) {
+            // Only the start is generated outside the loop variable's scope.
+            self.visit(&for_expr.start);
+            if for_expr.name != self.name {
+                self.visit(&for_expr.end);
+                self.visit(&for_expr.step);
+                self.visit(&for_expr.body);
+            }
+        }
+
+        fn visit_var(&mut self, var_expr: &VarExprAST) {
+            // Each initializer runs with the variables before it bound, so the search
+            // stops at a new variable of the same name.
+            for (name, init) in &var_expr.names {
+                self.visit(init);
+                if name == self.name {
+                    return;
+                }
+            }
+            self.visit(&var_expr.body);
+        }
+    }
+
+    let mut finder = AssignmentFinder { name, found: false };
+    finder.visit(node);
+    return finder.found;
+}
+
+// The check pass records a problem for each unbound name and wrong argument count.
+// Scoping nodes push and pop the names they bind around their children.
+impl Visitor for Checker {
+    fn visit_string(&mut self, _string: &StringExprAST) {
+        self.errors.push(CodegenError::misplaced_string());
+    }
+
+    fn visit_variable(&mut self, var: &VariableExprAST) {
+        self.check_variable(var);
+    }
+
+    fn visit_binary(&mut self, bin: &BinaryExprAST) {
+        match (bin.op.as_str(), bin.lhs.as_ref()) {
+            ("=", AST::Variable(var)) => self.check_variable(var),
+            ("=", _) => self.errors.push(CodegenError::bad_assignment()),
+            (":" | "<" | ">" | "+" | "-" | "*" | "%", lhs) => self.visit(lhs),
+            (op, lhs) if is_overridable_op(op) => self.visit(lhs),
+            (op, lhs) => {
+                self.check_call(&format!("binary{op}"), 2);
+                self.visit(lhs);
+            }
+        }
+        self.visit(&bin.rhs);
+    }
+
+    fn visit_unary(&mut self, unary: &UnaryExprAST) {
+        if unary.op != '-' {
+            self.check_call(&format!("unary{}", unary.op), 1);
+        }
+        self.visit(&unary.operand);
+    }
+
+    fn visit_call(&mut self, call: &CallExprAST) {
+        if call.callee == "invoke" && !self.functions.contains_key("invoke") {
+            if call.args.is_empty() {
+                self.errors.push(CodegenError::new(String::from(
+                    "CallExprAST code generation failure. `invoke` needs a function address as its first argument.",
+                )));
+            }
+        } else {
+            self.check_call(&call.callee, call.args.len());
+        }
+        for arg in &call.args {
+            if !matches!(arg.as_ref(), AST::String(_)) {
+                self.visit(arg);
+            }
+        }
+    }
+
+    fn visit_function_ref(&mut self, func_ref: &FunctionRefExprAST) {
+        if !self.functions.contains_key(&func_ref.name) {
+            self.errors
+                .push(CodegenError::unknown_function(&func_ref.name));
+        }
+    }
+
+    fn visit_for(&mut self, for_expr: &ForExprAST) {
+        self.visit(&for_expr.start);
+        self.scopes.push(vec![for_expr.name.clone()]);
+        self.visit(&for_expr.end);
+        self.visit(&for_expr.step);
+        self.loop_depth += 1;
+        self.visit(&for_expr.body);
+        self.loop_depth -= 1;
+        self.scopes.pop();
+    }
+
+    fn visit_while(&mut self, while_expr: &WhileExprAST) {
+        self.visit(&while_expr.cond);
+        self.loop_depth += 1;
+        self.visit(&while_expr.body);
+        self.loop_depth -= 1;
+    }
+
+    fn visit_break(&mut self, break_expr: &BreakExprAST) {
+        if self.loop_depth == 0 {
+            self.errors.push(CodegenError::new(format!(
+                "LoopJump code generation failure. `break` outside of a loop at {}",
+                break_expr.loc
+            )));
+        }
+    }
+
+    fn visit_continue(&mut self, continue_expr: &ContinueExprAST) {
+        if self.loop_depth == 0 {
+            self.errors.push(CodegenError::new(format!(
+                "LoopJump code generation failure. `continue` outside of a loop at {}",
+                continue_expr.loc
+            )));
+        }
+    }
+
+    fn visit_var(&mut self, var_expr: &VarExprAST) {
+        self.scopes.push(Vec::new());
+        for (name, init) in &var_expr.names {
+            self.visit(init);
+            self.scopes.last_mut().unwrap().push(name.clone());
+        }
+        self.visit(&var_expr.body);
+        self.scopes.pop();
+    }
+
+    fn visit_prototype(&mut self, proto: &PrototypeAST) {
+        self.functions
+            .insert(proto.name.clone(), (proto.args.len(), proto.is_var_arg));
+    }
+
+    fn visit_function(&mut self, function: &FunctionAST) {
+        // Known before the body, so the function may call itself.
+        let proto = function.get_proto();
+        self.visit_prototype(proto);
+        self.scopes = vec![proto.args.clone()];
+        self.visit(&function.body);
+        self.scopes.clear();
+    }
+
+    fn visit_global(&mut self, global: &GlobalAST) {
+        self.visit(&global.init);
+        if eval_constant(&global.init, false).is_none() {
+            self.errors
+                .push(CodegenError::non_constant_global(&global.name));
+        }
+        self.globals.insert(global.name.clone());
+    }
+}
+
+// Pretty-printing - each node prints as Kaleidoscope source that parses back into the
+// same tree.  Binary expressions are fully parenthesized so precedence survives.
+impl fmt::Display for AST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AST::Null => Ok(()),
+            AST::Number(inner_val) => inner_val.fmt(f),
+            AST::String(inner_val) => inner_val.fmt(f),
+            AST::Variable(inner_val) => inner_val.fmt(f),
+            AST::Binary(inner_val) => inner_val.fmt(f),
+            AST::Unary(inner_val) => inner_val.fmt(f),
+            AST::Call(inner_val) => inner_val.fmt(f),
+            AST::FunctionRef(inner_val) => inner_val.fmt(f),
+            AST::Tuple(inner_val) => inner_val.fmt(f),
+            AST::Projection(inner_val) => inner_val.fmt(f),
+            AST::If(inner_val) => inner_val.fmt(f),
+            AST::For(inner_val) => inner_val.fmt(f),
+            AST::While(inner_val) => inner_val.fmt(f),
+            AST::Break(inner_val) => inner_val.fmt(f),
+            AST::Continue(inner_val) => inner_val.fmt(f),
+            AST::Var(inner_val) => inner_val.fmt(f),
+            AST::Block(inner_val) => inner_val.fmt(f),
+            // A prototype on its own is an extern declaration.
+            AST::Prototype(inner_val) if inner_val.call_conv != CallConv::C => write!(
+                f,
+                "extern \"{}\" {inner_val}",
+                inner_val.call_conv.get_name()
+            ),
+            AST::Prototype(inner_val) => write!(f, "extern {inner_val}"),
+            AST::Function(inner_val) => inner_val.fmt(f),
+            AST::Global(inner_val) => inner_val.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for NumberExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.val {
+            Literal::Bool(val) => write!(f, "{val}"),
+            Literal::Int(val) => write!(f, "{val}"),
+            // Keep a '.' so the literal reads back as a double.
+            Literal::Float(val) if val.is_finite() && !val.to_string().contains('.') => {
+                write!(f, "{val}.0")
+            }
+            Literal::Float(val) => write!(f, "{val}"),
+        }
+    }
+}
+
+impl fmt::Display for StringExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.val.chars() {
+            match c {
+                '\n' => write!(f, "\\n")?,
+                '\t' => write!(f, "\\t")?,
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+impl fmt::Display for VariableExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl fmt::Display for BinaryExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({} {} {})", self.lhs, self.op, self.rhs)
+    }
+}
+
+impl fmt::Display for UnaryExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op, self.operand)
+    }
+}
+
+impl fmt::Display for CallExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+        write!(f, "{}({})", self.callee, args.join(", "))
+    }
+}
+
+impl fmt::Display for IfExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "if {} then {} else {}", self.cond, self.then, self.els)
+    }
+}
+
+impl fmt::Display for ForExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "for {} = {}, {}", self.name, self.start, self.end)?;
+        if !matches!(self.step.as_ref(), AST::Null) {
+            write!(f, ", {}", self.step)?;
+        }
+        write!(f, " in {}", self.body)
+    }
+}
+
+impl fmt::Display for WhileExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "while {} do {}", self.cond, self.body)
+    }
+}
+
+impl fmt::Display for FunctionRefExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "&{}", self.name)
+    }
+}
+
+impl fmt::Display for TupleExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elems: Vec<String> = self.elems.iter().map(|elem| elem.to_string()).collect();
+        write!(f, "({})", elems.join(", "))
+    }
+}
+
+impl fmt::Display for ProjectionExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.tuple, self.index)
+    }
+}
+
+impl fmt::Display for BreakExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "break")
+    }
+}
+
+impl fmt::Display for ContinueExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "continue")
+    }
+}
+
+impl fmt::Display for VarExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Vec::new();
+        for (var_name, init) in &self.names {
+            match init {
+                AST::Null => names.push(var_name.to_string()),
+                _ => names.push(format!("{var_name} = {init}")),
+            }
+        }
+        write!(f, "var {} in {}", names.join(", "), self.body)
+    }
+}
+
+impl fmt::Display for BlockExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let exprs: Vec<String> = self.exprs.iter().map(|expr| expr.to_string()).collect();
+        write!(f, "{{ {} }}", exprs.join("; "))
+    }
+}
+
+impl fmt::Display for PrototypeAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_binary_op() {
+            write!(f, "binary{} {} ", self.get_operator_name(), self.precedence)?;
+        } else if self.is_unary_op() {
+            write!(f, "unary{}", self.get_operator_name())?;
+        } else {
+            write!(f, "{}", self.name)?;
+        }
+        if self.is_var_arg {
+            let mut args = self.args.clone();
+            args.push(String::from("..."));
+            write!(f, "({})", args.join(" "))?;
+        } else {
+            write!(f, "({})", self.args.join(" "))?;
+        }
+        if self.return_type != Type::Double {
+            write!(f, ": {}", self.return_type)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for GlobalAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "global {} = {}", self.name, self.init)
+    }
+}
+
+impl fmt::Display for FunctionAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.proto.as_ref() {
+            // Top-level expressions are wrapped in a function whose name can't be written
+            // as an identifier.
+            AST::Prototype(proto) if proto.get_name().contains('.') => write!(f, "{}", self.body),
+            AST::Prototype(proto) => write!(f, "def {} {}", proto, self.body),
+            _ => write!(f, "def {} {}", self.proto, self.body),
+        }
+    }
 }
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{set_value_name, Literal, PrototypeAST};
+use crate::debuginfo::DebugInfo;
+use crate::lexer::{SourceLocation, Span};
+use crate::runtime::add_runtime_mappings;
+use crate::{builtin_protos, create_fpm, Diagnostic, Error, Pass, Severity};
+use inkwell::basic_block::BasicBlock;
+use inkwell::builder::Builder;
+use inkwell::context::Context;
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::module::Module;
+use inkwell::passes::PassManager;
+use inkwell::types::BasicTypeEnum;
+use inkwell::values::{FunctionValue, PointerValue};
+use inkwell::OptimizationLevel;
+
+// Compiler - The code generation half of the compiler: the LLVM context, module and
+// builder, and what code generation keeps track of as it goes.  It knows nothing of
+// the lexer or the parser, so an AST built by other means compiles just the same.
+pub struct Compiler<'ctx> {
+    pub context: &'ctx Context,
+    pub builder: Builder<'ctx>,
+    pub module: Module<'ctx>,
+    pub fpm: PassManager<FunctionValue<'ctx>>,
+    // Selects the FPM passes and the JIT code generation level.
+    pub opt_level: OptimizationLevel,
+    // The FPM passes to run, in order, instead of the ones `opt_level` picks.
+    pub passes: Option<Vec<Pass>>,
+    // Variable bindings, one map per nested scope with the innermost last.  Each
+    // binding keeps the type its alloca holds.
+    pub named_values: Vec<HashMap<String, (PointerValue<'ctx>, BasicTypeEnum<'ctx>)>>,
+    // The bindings of each scope known to hold a constant: bound by `var` to a literal
+    // and never assigned.  Reading one yields the literal instead of a load.
+    pub constants: Vec<HashMap<String, Literal>>,
+    // The loops being generated, innermost last: the blocks `continue` and `break`
+    // jump to.
+    pub loops: Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>,
+    pub function_protos: HashMap<String, PrototypeAST>,
+    // Type of each global variable, so modules other than the defining one can
+    // declare it.
+    pub globals: HashMap<String, BasicTypeEnum<'ctx>>,
+    // Whether `for` loops yield their last body value instead of 0.0.
+    pub for_returns_body: bool,
+    // Whether a `for` loop whose body assigns to the loop variable leaves the stepping
+    // to the body, instead of adding the step on top of what the body stored.
+    pub for_manual_step: bool,
+    // Whether float `<` and `>` are ordered (false when either side is NaN) instead of
+    // unordered (true when either side is NaN).
+    pub ordered_compare: bool,
+    // Names of the functions holding the collected top-level expressions, in order.
+    pub toplevel_exprs: Vec<String>,
+    // The JIT for the REPL.  Definitions are added to it in modules of their own as they
+    // are first needed, and it is kept until one it has is defined anew, so each is
+    // compiled only once.
+    pub jit: Option<ExecutionEngine<'ctx>>,
+    // Names of the functions and globals defined in `jit`.
+    pub jitted: HashSet<String>,
+    // DWARF debug info for the module, when asked for.
+    pub debug_info: Option<DebugInfo<'ctx>>,
+    // Warnings from code generation that have not been reported yet.
+    pub warnings: Vec<Diagnostic>,
+    // Whether warnings are reported as errors, failing the compile.
+    pub warnings_as_errors: bool,
+}
+
+impl<'ctx> Compiler<'ctx> {
+    pub fn new(context: &'ctx Context) -> Compiler<'ctx> {
+        let module = context.create_module("kaleidoscope");
+        let opt_level = OptimizationLevel::Default;
+        let fpm = create_fpm(&module, opt_level, None);
+
+        Compiler {
+            context,
+            builder: context.create_builder(),
+            module,
+            fpm,
+            opt_level,
+            passes: None,
+            named_values: Vec::new(),
+            constants: Vec::new(),
+            loops: Vec::new(),
+            function_protos: builtin_protos(),
+            globals: HashMap::new(),
+            for_returns_body: false,
+            for_manual_step: false,
+            ordered_compare: false,
+            toplevel_exprs: Vec::new(),
+            jit: None,
+            jitted: HashSet::new(),
+            debug_info: None,
+            warnings: Vec::new(),
+            warnings_as_errors: false,
+        }
+    }
+
+    // Forget every definition, starting over with an empty module.
+    pub fn clear_definitions(&mut self) {
+        self.jit = None;
+        self.jitted.clear();
+        self.module = self.context.create_module("kaleidoscope");
+        self.fpm = create_fpm(&self.module, self.opt_level, self.passes.as_deref());
+        if let Some(debug_info) = self.debug_info.take() {
+            self.enable_debug_info(debug_info.get_path());
+        }
+        self.function_protos = builtin_protos();
+        self.globals.clear();
+        self.toplevel_exprs.clear();
+    }
+
+    // Bring the JIT up to date with the module, creating it if there is none.  The
+    // definitions it doesn't have yet go in as a module of their own: a copy of `module`
+    // in which those it has are only declared, for the JIT to find where it compiled
+    // them.
+    pub fn update_jit(&mut self) -> Result<(), Error> {
+        let defined: Vec<String> = self
+            .module
+            .get_functions()
+            .filter(|func| func.count_basic_blocks() > 0)
+            .map(|func| func.get_name().to_string_lossy().into_owned())
+            .chain(self.globals.keys().cloned())
+            .collect();
+        if self.jit.is_some() && defined.iter().all(|name| self.jitted.contains(name)) {
+            return Ok(());
+        }
+
+        let module = self.module.clone();
+        for name in &self.jitted {
+            declare_only(&module, name, self.globals.get(name).copied());
+        }
+        match &self.jit {
+            Some(ee) => ee.add_module(&module).map_err(|()| {
+                Error::Jit(String::from("Could not add the new definitions to the JIT"))
+            })?,
+            None => {
+                let ee = module
+                    .create_jit_execution_engine(self.opt_level)
+                    .map_err(|err| Error::Jit(err.to_string()))?;
+                self.jit = Some(ee);
+            }
+        }
+        add_runtime_mappings(self.jit.as_ref().unwrap(), &module);
+        self.jitted.extend(defined);
+        return Ok(());
+    }
+
+    // Called when `name` is defined anew.  The JIT can't replace code it has compiled, so
+    // if it has the old definition it is dropped, and the next evaluation starts a new
+    // one with the whole module.
+    pub fn redefine(&mut self, name: &str) {
+        if self.jitted.contains(name) {
+            self.jit = None;
+            self.jitted.clear();
+        }
+    }
+
+    // Change the optimization level, rebuilding the FPM to match.
+    pub fn set_opt_level(&mut self, opt_level: OptimizationLevel) {
+        self.opt_level = opt_level;
+        self.fpm = create_fpm(&self.module, opt_level, self.passes.as_deref());
+    }
+
+    // Run exactly `passes` on each function, whatever the optimization level, or go
+    // back to the level's own passes with None.
+    pub fn set_passes(&mut self, passes: Option<Vec<Pass>>) {
+        self.passes = passes;
+        self.fpm = create_fpm(&self.module, self.opt_level, self.passes.as_deref());
+    }
+
+    // Generate DWARF debug info for the module, as compiled from the source file at
+    // `path`.
+    pub fn enable_debug_info(&mut self, path: &str) {
+        let is_optimized = self.opt_level != OptimizationLevel::None;
+        self.debug_info = Some(DebugInfo::new(
+            self.context,
+            &self.module,
+            path,
+            is_optimized,
+        ));
+    }
+
+    // Give the instructions generated from now on the position `loc`, returning the
+    // position they had before.  Does nothing without debug info.
+    pub fn set_debug_location(&mut self, loc: SourceLocation) -> Option<SourceLocation> {
+        let debug_info = self.debug_info.as_mut()?;
+        debug_info.set_location(self.context, &self.builder, loc)
+    }
+
+    // Finish the debug info, if any, so the module can be emitted.
+    pub fn finalize_debug_info(&self) {
+        if let Some(debug_info) = &self.debug_info {
+            debug_info.finalize();
+        }
+    }
+
+    // Warn about the code at `span`, with an error instead when warnings are errors.
+    pub fn warn(&mut self, message: String, span: Span) {
+        let mut warning = Diagnostic::warning(message, span);
+        if self.warnings_as_errors {
+            warning.severity = Severity::Error;
+        }
+        self.warnings.push(warning);
+    }
+
+    // Open a new innermost scope for variable bindings.
+    pub fn push_scope(&mut self) {
+        self.named_values.push(HashMap::new());
+        self.constants.push(HashMap::new());
+    }
+
+    // Close the innermost scope, bringing back any bindings it shadowed.
+    pub fn pop_scope(&mut self) {
+        self.named_values.pop();
+        self.constants.pop();
+    }
+
+    // Drop every scope, for a new function.
+    pub fn clear_scopes(&mut self) {
+        self.named_values.clear();
+        self.constants.clear();
+    }
+
+    // Bind `name` in the innermost scope.
+    pub fn insert_var(
+        &mut self,
+        name: &str,
+        ptr_val: PointerValue<'ctx>,
+        var_type: BasicTypeEnum<'ctx>,
+    ) {
+        self.named_values
+            .last_mut()
+            .expect("insert_var called outside of any scope")
+            .insert(name.to_string(), (ptr_val, var_type));
+    }
+
+    // Record that the binding of `name` in the innermost scope always holds `val`.
+    pub fn insert_constant(&mut self, name: &str, val: Literal) {
+        self.constants
+            .last_mut()
+            .expect("insert_constant called outside of any scope")
+            .insert(name.to_string(), val);
+    }
+
+    // The constant the innermost binding of `name` holds, if it is known to hold one.
+    pub fn lookup_constant(&self, name: &str) -> Option<Literal> {
+        let depth = self
+            .named_values
+            .iter()
+            .rposition(|scope| scope.contains_key(name))?;
+        self.constants[depth].get(name).copied()
+    }
+
+    // Find the innermost binding of `name`.
+    pub fn lookup(&self, name: &str) -> Option<(PointerValue<'ctx>, BasicTypeEnum<'ctx>)> {
+        self.named_values
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .copied()
+    }
+}
+
+// Replace the definition of the function or global `name` in `module` with a declaration.
+// `global_type` is the type of a global's value.
+fn declare_only<'ctx>(module: &Module<'ctx>, name: &str, global_type: Option<BasicTypeEnum<'ctx>>) {
+    if let Some(func) = module.get_function(name) {
+        let decl = module.add_function("", func.get_type(), None);
+        func.replace_all_uses_with(decl);
+        unsafe { func.delete() };
+        set_value_name(decl, name);
+    } else if let (Some(global), Some(global_type)) = (module.get_global(name), global_type) {
+        let decl = module.add_global(global_type, None, "");
+        global
+            .as_pointer_value()
+            .replace_all_uses_with(decl.as_pointer_value());
+        unsafe { global.delete() };
+        set_value_name(decl, name);
+    }
+}
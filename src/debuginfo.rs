@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use crate::ast::{PrototypeAST, Type};
+use crate::lexer::SourceLocation;
+use inkwell::builder::Builder;
+use inkwell::context::Context;
+use inkwell::debug_info::{
+    AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DIScope, DIType, DWARFEmissionKind,
+    DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::{FlagBehavior, Module};
+use inkwell::values::FunctionValue;
+
+// DWARF encodings of the basic types (DW_ATE_*).
+const DW_ATE_BOOLEAN: u32 = 0x02;
+const DW_ATE_FLOAT: u32 = 0x04;
+const DW_ATE_SIGNED: u32 = 0x05;
+
+// DebugInfo - The DWARF debug info of a module: one compile unit for the source file,
+// a subprogram per function, and a line and column for the instructions.
+pub struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    compile_unit: DICompileUnit<'ctx>,
+    path: String,
+    // The function being generated and the position its instructions get now.  None
+    // between functions, when instructions get no position at all.
+    scope: Option<DIScope<'ctx>>,
+    loc: Option<SourceLocation>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    // Start the debug info of `module`, compiled from the source file at `path`.
+    pub fn new(
+        context: &'ctx Context,
+        module: &Module<'ctx>,
+        path: &str,
+        is_optimized: bool,
+    ) -> Self {
+        // Without this flag LLVM drops the debug info as being of an unknown version.
+        module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            context.i32_type().const_int(3, false),
+        );
+
+        let file = Path::new(path);
+        let filename = file
+            .file_name()
+            .map_or(path.into(), |name| name.to_string_lossy());
+        let directory = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy(),
+            _ => ".".into(),
+        };
+
+        // Kaleidoscope has no DWARF language code of its own, so it passes as C.
+        let (builder, compile_unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "Kaleidoscope Compiler",
+            is_optimized,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+
+        DebugInfo {
+            builder,
+            compile_unit,
+            path: path.to_string(),
+            scope: None,
+            loc: None,
+        }
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    // The debug info type of a Kaleidoscope value type.  Tuples are not described, so
+    // a debugger sees no type for them.
+    fn create_type(&self, value_type: Type) -> Option<DIType<'ctx>> {
+        let (size, encoding) = match value_type {
+            Type::Double => (64, DW_ATE_FLOAT),
+            Type::Int => (64, DW_ATE_SIGNED),
+            Type::Bool => (8, DW_ATE_BOOLEAN),
+            Type::Tuple(_) => return None,
+        };
+        let basic_type = self
+            .builder
+            .create_basic_type(&value_type.to_string(), size, encoding, DIFlags::PUBLIC)
+            .unwrap();
+        Some(basic_type.as_type())
+    }
+
+    // Describe `func`, defined by `proto`, and make it the scope of the instructions
+    // generated from now on, starting at the prototype.  Its arguments are all doubles.
+    pub fn begin_function(
+        &mut self,
+        context: &'ctx Context,
+        builder: &Builder<'ctx>,
+        func: FunctionValue<'ctx>,
+        proto: &PrototypeAST,
+    ) {
+        let loc = proto.get_loc();
+        let file = self.compile_unit.get_file();
+        let arg_types = vec![self.create_type(Type::Double).unwrap(); proto.get_args().len()];
+        let subroutine_type = self.builder.create_subroutine_type(
+            file,
+            self.create_type(proto.get_return_type()),
+            &arg_types,
+            DIFlags::PUBLIC,
+        );
+        let subprogram = self.builder.create_function(
+            self.compile_unit.as_debug_info_scope(),
+            proto.get_name(),
+            None,
+            file,
+            loc.line as u32,
+            subroutine_type,
+            false,
+            true,
+            loc.line as u32,
+            DIFlags::PUBLIC,
+            false,
+        );
+        func.set_subprogram(subprogram);
+        self.scope = Some(subprogram.as_debug_info_scope());
+        self.set_location(context, builder, loc);
+    }
+
+    // Leave the current function; instructions get no position until the next one.
+    pub fn end_function(&mut self, builder: &Builder<'ctx>) {
+        builder.unset_current_debug_location();
+        self.scope = None;
+        self.loc = None;
+    }
+
+    // Make `loc` the position of the instructions `builder` generates from now on,
+    // returning the position they had before.  Outside a function, nothing changes.
+    pub fn set_location(
+        &mut self,
+        context: &'ctx Context,
+        builder: &Builder<'ctx>,
+        loc: SourceLocation,
+    ) -> Option<SourceLocation> {
+        let scope = self.scope?;
+        let location = self.builder.create_debug_location(
+            context,
+            loc.line as u32,
+            loc.col as u32,
+            scope,
+            None,
+        );
+        builder.set_current_debug_location(context, location);
+        return self.loc.replace(loc);
+    }
+
+    // Resolve what is still pending.  Must be done before the module is emitted.
+    pub fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
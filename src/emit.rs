@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use crate::compiler::Compiler;
+use inkwell::targets::{
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+};
+use inkwell::OptimizationLevel;
+
+// Build a target machine for `triple`, or for the host when no triple is given.
+fn create_target_machine(
+    opt_level: OptimizationLevel,
+    triple: Option<&str>,
+) -> Result<TargetMachine, String> {
+    // Host code is tuned for the host CPU; other targets get a generic one.
+    let (triple, cpu, features) = match triple {
+        None => {
+            Target::initialize_native(&InitializationConfig::default())?;
+            (
+                TargetMachine::get_default_triple(),
+                TargetMachine::get_host_cpu_name().to_string(),
+                TargetMachine::get_host_cpu_features().to_string(),
+            )
+        }
+        Some(triple) => {
+            Target::initialize_all(&InitializationConfig::default());
+            (
+                TargetTriple::create(triple),
+                String::from("generic"),
+                String::new(),
+            )
+        }
+    };
+    let target = Target::from_triple(&triple)
+        .map_err(|err| format!("Unsupported target `{triple}`: {err}"))?;
+
+    target
+        .create_target_machine(
+            &triple,
+            &cpu,
+            &features,
+            opt_level,
+            RelocMode::PIC,
+            CodeModel::Default,
+        )
+        .ok_or(format!("Could not create a target machine for `{triple}`"))
+}
+
+// Compile the module to native code for `triple` (the host by default), written to
+// `path` as `file_type`.
+fn emit_machine_code(
+    state: &Compiler,
+    path: &Path,
+    triple: Option<&str>,
+    file_type: FileType,
+) -> Result<(), String> {
+    let machine = create_target_machine(state.opt_level, triple)?;
+
+    state.module.set_triple(&machine.get_triple());
+    state
+        .module
+        .set_data_layout(&machine.get_target_data().get_data_layout());
+
+    machine
+        .write_to_file(&state.module, file_type, path)
+        .map_err(|err| err.to_string())
+}
+
+// Compile the module to a native object file at `path`.
+pub fn emit_object_file(state: &Compiler, path: &Path, triple: Option<&str>) -> Result<(), String> {
+    emit_machine_code(state, path, triple, FileType::Object)
+}
+
+// Compile the module to textual assembly at `path`.
+pub fn emit_asm_file(state: &Compiler, path: &Path, triple: Option<&str>) -> Result<(), String> {
+    emit_machine_code(state, path, triple, FileType::Assembly)
+}
+
+// Write the textual LLVM IR of the module to `path`.
+pub fn emit_ir_file(state: &Compiler, path: &Path) -> Result<(), String> {
+    state
+        .module
+        .print_to_file(path)
+        .map_err(|err| err.to_string())
+}
+
+// Write the module as LLVM bitcode to `path`, for other LLVM tools to read.
+pub fn emit_bitcode_file(state: &Compiler, path: &Path) -> Result<(), String> {
+    // LLVM only reports whether the write worked, not why it did not.
+    if state.module.write_bitcode_to_path(path) {
+        Ok(())
+    } else {
+        Err(format!("Could not write `{}`", path.display()))
+    }
+}
@@ -1,5 +1,7 @@
-use crate::State;
-use libc;
+use std::fmt;
+use std::io::{BufReader, Cursor, ErrorKind, IsTerminal, Read, Write};
+
+use crate::parser::ParserState;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
@@ -12,63 +14,376 @@ pub enum Token {
     // commands
     TokDef,
     TokExtern,
+    TokGlobal,
 
     // control
     TokIf,
     TokThen,
     TokElse,
+    TokElif,
     TokFor,
     TokIn,
+    TokWhile,
+    TokDo,
+    TokBreak,
+    TokContinue,
     TokVar,
 
+    // literals
+    TokTrue,
+    TokFalse,
+
     // operators
     TokBinary,
     TokUnary,
+    // a declared binary operator of more than one character, like `:=`
+    TokOp(String),
+
+    // variable arguments in a prototype
+    TokEllipsis,
 
     // primary
     TokIdentifier(String),
     TokNumber(f64),
+    TokInt(i64),
+    TokString(String),
+    // a tuple element right after a value, like the `.1` of `x.1`
+    TokProject(usize),
+
+    // directives
+    TokDump,
+    // a REPL command: a line starting with ':', holding the rest of the line
+    TokCommand(String),
+
+    // malformed input, with a description of the problem
+    TokError(String),
 
     // catch-all
     TokChar(char),
 }
 
-fn getchar() -> char {
-    char::from_u32(unsafe { libc::getchar() } as u32).unwrap()
+// A position in the input.  Lines and columns start at 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct SourceLocation {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+// A stretch of the input, from `start` up to but not including `end`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Span {
+    pub start: SourceLocation,
+    pub end: SourceLocation,
+}
+
+impl Span {
+    // The single position `loc`.
+    pub fn at(loc: SourceLocation) -> Self {
+        Span {
+            start: loc,
+            end: loc,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            return self.start.fmt(f);
+        }
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+// LexerConfig - The parts of the lexical syntax an embedder can choose.
+#[derive(Clone, Debug)]
+pub struct LexerConfig {
+    // What starts a comment running to the end of the line: any one of these.
+    pub line_comments: Vec<String>,
+}
+
+impl LexerConfig {
+    // Comments start with `prefix` alone, such as "//" or ";".  Other characters,
+    // '#' included, are then lexed as usual.
+    pub fn with_line_comment(prefix: &str) -> Self {
+        LexerConfig {
+            line_comments: vec![prefix.to_string()],
+        }
+    }
+}
+
+impl Default for LexerConfig {
+    // Both '#' and "//" comments.
+    fn default() -> Self {
+        LexerConfig {
+            line_comments: vec![String::from("#"), String::from("//")],
+        }
+    }
+}
+
+// Sentinel character returned once the input is exhausted.
+pub const EOF: char = '\0';
+
+// Where the lexer reads its characters from: any UTF-8 byte stream.
+pub struct Source {
+    // Read a byte at a time, so it is buffered unless it buffers itself.
+    reader: Box<dyn Read>,
+    // Characters given back by the lexer, to be read again before `reader`.  The next
+    // one is last.
+    pending: Vec<char>,
+    // Whether a person is typing the input, and so should be prompted for it.
+    interactive: bool,
+}
+
+impl Source {
+    // Read from stdin, prompting for each line when it is a terminal.
+    pub fn stdin() -> Self {
+        let interactive = std::io::stdin().is_terminal();
+        Source {
+            // Stdin buffers itself.  A buffer of our own would also take input meant for
+            // `readnumber` and `readchar`, which read stdin too.
+            reader: Box::new(std::io::stdin()),
+            pending: Vec::new(),
+            interactive,
+        }
+    }
+
+    // Read from a file, a socket, a byte buffer, ... without prompting.  The reader is
+    // buffered, as reading it a byte at a time would otherwise take a read call per
+    // byte.  (Stdin has a buffer of its own.)
+    pub fn from_reader(reader: impl Read + 'static) -> Self {
+        Source {
+            reader: Box::new(BufReader::new(reader)),
+            pending: Vec::new(),
+            interactive: false,
+        }
+    }
+
+    // Read from `reader` as from a person at a terminal, prompting for each line.  Each
+    // item is handled as soon as its line is in, before the next is read.
+    pub fn interactive(reader: impl Read + 'static) -> Self {
+        Source {
+            reader: Box::new(BufReader::new(reader)),
+            pending: Vec::new(),
+            interactive: true,
+        }
+    }
+
+    pub fn from_text(text: &str) -> Self {
+        Source::from_reader(Cursor::new(text.to_owned().into_bytes()))
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    // Give back characters that were read too far, so they are read again in order.
+    fn unread(&mut self, chars: &[char]) {
+        self.pending.extend(chars.iter().rev());
+    }
+
+    // Read the next byte, or None at the end of the input or on a read error.
+    fn next_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return None,
+                Ok(_) => return Some(byte[0]),
+                // A signal arrived before anything was read; try again.
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // Decode the next UTF-8 character.  Malformed input reads as U+FFFD, and a read
+    // error ends the input.
+    fn next_char(&mut self) -> char {
+        if let Some(c) = self.pending.pop() {
+            return c;
+        }
+        let first = match self.next_byte() {
+            Some(byte) => byte,
+            None => return EOF,
+        };
+        let len = match first {
+            0x00..=0x7f => return first as char,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return char::REPLACEMENT_CHARACTER,
+        };
+        let mut buf = vec![first];
+        while buf.len() < len {
+            match self.next_byte() {
+                Some(byte) => buf.push(byte),
+                None => return EOF,
+            }
+        }
+        match std::str::from_utf8(&buf) {
+            Ok(s) => s.chars().next().unwrap(),
+            Err(_) => char::REPLACEMENT_CHARACTER,
+        }
+    }
+}
+
+fn read_char(state: &mut ParserState) -> char {
+    // Prompt before reading the first character of a new line.
+    if state.source.is_interactive() && state.last_char == '\n' {
+        print!("In [#]: ");
+        std::io::stdout().flush().unwrap();
+    }
+    let c = state.source.next_char();
+
+    // Windows "\r\n" and old Mac "\r" line endings read as a single '\n', so the rest of
+    // the lexer only ever sees one kind of line ending.
+    if c == '\r' {
+        let next = state.source.next_char();
+        if next != '\n' {
+            state.source.unread(&[next]);
+        }
+        return '\n';
+    }
+    return c;
+}
+
+// Read the next character, keeping `lex_loc` on its position.
+fn getchar(state: &mut ParserState) -> char {
+    let c = read_char(state);
+    if c == '\n' {
+        state.lex_loc.line += 1;
+        state.lex_loc.col = 0;
+    } else {
+        state.lex_loc.col += 1;
+    }
+    return c;
+}
+
+// Read the rest of the line, stopping at the newline or the end of the input.
+fn read_comment(state: &mut ParserState) -> String {
+    let mut comment = String::from("");
+    while state.last_char != EOF && state.last_char != '\n' {
+        comment.push(state.last_char);
+        state.last_char = getchar(state);
+    }
+    return comment;
+}
+
+// Whether a line comment starts at `last_char`.  If one does, `last_char` is left on the
+// last character of its prefix; if not, the input is as it was.
+fn at_line_comment(state: &mut ParserState) -> bool {
+    let prefixes = state.lexer_config.line_comments.clone();
+    for prefix in prefixes {
+        let mut chars = prefix.chars();
+        if chars.next() != Some(state.last_char) {
+            continue;
+        }
+        let loc = state.lex_loc;
+        let mut read = Vec::new();
+        for expected in chars {
+            let c = getchar(state);
+            read.push(c);
+            if c != expected {
+                break;
+            }
+        }
+        if prefix.chars().skip(1).eq(read.iter().copied()) {
+            if let Some(&c) = read.last() {
+                state.last_char = c;
+            }
+            return true;
+        }
+        // Not this one: give the characters back.
+        state.source.unread(&read);
+        state.lex_loc = loc;
+    }
+    return false;
+}
+
+fn get_token_after_comment(state: &mut ParserState) -> Token {
+    // A comment on the last line ends the input.
+    if state.last_char == EOF {
+        return Token::TokEOF;
+    }
+    return get_token(state);
 }
 
 // Grab the next token from the stream
-fn get_token(state: &mut State) -> Token {
+fn get_token(state: &mut ParserState) -> Token {
+    // The line the previous token ended on.  `last_char` is the character after it,
+    // which is already on the next line when it is a newline.
+    let prev_line = match state.last_char {
+        '\n' => state.lex_loc.line - 1,
+        _ => state.lex_loc.line,
+    };
+
     // Skip any whitespace.
+    let after_space = state.last_char.is_whitespace();
     while state.last_char.is_whitespace() || state.last_char == '\n' {
-        state.last_char = getchar();
+        state.last_char = getchar(state);
+    }
+
+    // The token starts at the current character.
+    state.cur_loc = state.lex_loc;
+
+    // In the REPL, a line whose first non-blank character is ':' is a command.  The
+    // whole line is read here, so it can't be taken for the ':' operator continuing
+    // the item on the line before.
+    let first_on_line = state.cur_tok == Token::TokUndef || state.cur_loc.line > prev_line;
+    if state.commands && first_on_line && state.last_char == ':' {
+        let line = read_comment(state);
+        return Token::TokCommand(line[1..].trim().to_string());
     }
 
-    // identifier: [a-zA-Z][a-zA-Z0-9]*
-    if state.last_char.is_alphabetic() {
+    // identifier: [a-zA-Z_][a-zA-Z0-9_]*
+    if state.last_char.is_alphabetic() || state.last_char == '_' {
         let mut identifier_str = state.last_char.to_string();
-        state.last_char = getchar();
-        while (state.last_char).is_alphanumeric() {
+        state.last_char = getchar(state);
+        while state.last_char.is_alphanumeric() || state.last_char == '_' {
             identifier_str.push_str(&state.last_char.to_string());
-            state.last_char = getchar();
+            state.last_char = getchar(state);
         }
 
         if identifier_str == "def" {
             return Token::TokDef;
         } else if identifier_str == "extern" {
             return Token::TokExtern;
+        } else if identifier_str == "global" {
+            return Token::TokGlobal;
         } else if identifier_str == "if" {
             return Token::TokIf;
         } else if identifier_str == "then" {
             return Token::TokThen;
         } else if identifier_str == "else" {
             return Token::TokElse;
+        } else if identifier_str == "elif" {
+            return Token::TokElif;
         } else if identifier_str == "for" {
             return Token::TokFor;
         } else if identifier_str == "in" {
             return Token::TokIn;
+        } else if identifier_str == "while" {
+            return Token::TokWhile;
+        } else if identifier_str == "do" {
+            return Token::TokDo;
+        } else if identifier_str == "break" {
+            return Token::TokBreak;
+        } else if identifier_str == "continue" {
+            return Token::TokContinue;
         } else if identifier_str == "var" {
             return Token::TokVar;
+        } else if identifier_str == "true" {
+            return Token::TokTrue;
+        } else if identifier_str == "false" {
+            return Token::TokFalse;
         } else if identifier_str == "binary" {
             return Token::TokBinary;
         } else if identifier_str == "unary" {
@@ -80,33 +395,247 @@ fn get_token(state: &mut State) -> Token {
         }
     }
 
-    // Number: [0-9.]+
+    // Projection: '.' [0-9]+ right after a name, a ')' or another projection, with no
+    // space before it.  Anywhere else `.1` is a number.
+    let after_value = matches!(
+        state.cur_tok,
+        Token::TokIdentifier(_) | Token::TokChar(')') | Token::TokProject(_)
+    );
+    if state.last_char == '.' && after_value && !after_space {
+        let loc = state.lex_loc;
+        let next = getchar(state);
+        if next.is_digit(10) {
+            let mut index_str = String::from("");
+            state.last_char = next;
+            while state.last_char.is_digit(10) {
+                index_str.push(state.last_char);
+                state.last_char = getchar(state);
+            }
+            return match index_str.parse() {
+                Ok(index) => Token::TokProject(index),
+                Err(_) => Token::TokError(format!("Tuple index `{index_str}` is out of range")),
+            };
+        }
+        // Not a projection: give the character back and lex the '.' as usual.
+        state.source.unread(&[next]);
+        state.lex_loc = loc;
+    }
+
+    // Number: [0-9.]+, integer: [0-9]+, ellipsis: ...
     if state.last_char.is_digit(10) || state.last_char == '.' {
         let mut num_str = String::from("");
         while state.last_char.is_digit(10) || state.last_char == '.' {
             num_str.push_str(&state.last_char.to_string());
-            state.last_char = getchar();
+            state.last_char = getchar(state);
         }
-        return Token::TokNumber(num_str.parse().unwrap());
+        // A lone '.' is not a number, so leave it free for use as an operator.
+        if num_str == "." {
+            return Token::TokChar('.');
+        }
+        if num_str == "..." {
+            return Token::TokEllipsis;
+        }
+        // Without a '.' the literal is an integer.
+        if !num_str.contains('.') {
+            return match num_str.parse() {
+                Ok(num) => Token::TokInt(num),
+                Err(_) => Token::TokError(format!("Integer literal `{num_str}` is out of range")),
+            };
+        }
+        return match num_str.parse() {
+            Ok(num) => Token::TokNumber(num),
+            Err(_) => Token::TokError(format!("Invalid number literal `{num_str}`")),
+        };
     }
 
-    // Comment until end of line.
-    if state.last_char == '#' {
-        // TODO: !state.last_char.is_whitespace() -> check for != EOF
-        while state.last_char != '\n' && state.last_char != '\r'
-        {
-            state.last_char = getchar();
+    // Comment until end of line.  With '#' comments, a line holding just `#dump` is a
+    // directive instead: nothing but whitespace may come before it on the line.
+    if at_line_comment(state) {
+        let comment = read_comment(state);
+        if first_on_line && comment.trim_end() == "#dump" {
+            return Token::TokDump;
+        }
+        return get_token_after_comment(state);
+    }
+
+    // String: '"' [^"]* '"', with \n, \t, \", \\, \xNN and \u{NNNN} escapes.
+    if state.last_char == '"' {
+        let mut string = String::from("");
+        let mut error = None;
+        state.last_char = getchar(state);
+        while state.last_char != '"' {
+            let c = match state.last_char {
+                EOF => return Token::TokError(String::from("Unterminated string literal")),
+                '\\' => {
+                    state.last_char = getchar(state);
+                    if state.last_char == EOF {
+                        return Token::TokError(String::from("Unterminated string literal"));
+                    }
+                    match read_escape(state) {
+                        Ok(c) => c,
+                        Err(message) => {
+                            // Keep reading to the closing '"' so the rest isn't lexed as code.
+                            error.get_or_insert(message);
+                            char::REPLACEMENT_CHARACTER
+                        }
+                    }
+                }
+                c => c,
+            };
+            string.push(c);
+            state.last_char = getchar(state);
         }
+        state.last_char = getchar(state); // eat the closing '"'
+        return match error {
+            Some(message) => Token::TokError(message),
+            None => Token::TokString(string),
+        };
+    }
+
+    // Check for end of file.  Don't eat the EOF.
+    if state.last_char == EOF {
+        return Token::TokEOF;
+    }
 
-        // TODO: !state.last_char.is_whitespace() -> check for != EOF
-        return get_token(state);
+    // Operator: the longest declared operator, or a single character.  Right after
+    // `binary` it is a new operator, made of the whole run of operator characters.
+    if is_operator_char(state.last_char) {
+        let declaring = state.cur_tok == Token::TokBinary;
+        let op = lex_operator(state, declaring);
+        if op.chars().count() > 1 {
+            return Token::TokOp(op);
+        }
     }
 
     let this_char = state.last_char;
-    state.last_char = getchar();
+    state.last_char = getchar(state);
     return Token::TokChar(this_char);
 }
 
-pub fn get_next_token(state: &mut State) {
+// Read the escape after a '\' in a string literal, starting at `last_char`, and leave
+// `last_char` on its last character.
+fn read_escape(state: &mut ParserState) -> Result<char, String> {
+    match state.last_char {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        '"' => Ok('"'),
+        '\\' => Ok('\\'),
+        // \xNN: an ASCII character by its code, as in Rust.  A string holds characters,
+        // not bytes, so anything past \x7f would be ambiguous.
+        'x' => {
+            let digits = read_hex_digits(state, 2);
+            if digits.len() != 2 {
+                return Err(format!(
+                    "Expected two hex digits in escape `\\x{digits}` in string literal"
+                ));
+            }
+            let code = u32::from_str_radix(&digits, 16).unwrap();
+            if code > 0x7f {
+                return Err(format!(
+                    "Escape `\\x{digits}` in string literal is out of range: must be at most \\x7f"
+                ));
+            }
+            Ok(char::from_u32(code).unwrap())
+        }
+        // \u{NNNN}: any Unicode character by its code point, in 1 to 6 hex digits.
+        'u' => {
+            if !advance_if(state, |c| c == '{') {
+                return Err(String::from("Expected '{' after `\\u` in string literal"));
+            }
+            let digits = read_hex_digits(state, 6);
+            if digits.is_empty() || !advance_if(state, |c| c == '}') {
+                return Err(format!(
+                    "Expected 1 to 6 hex digits and a '}}' in escape `\\u{{{digits}` in string literal"
+                ));
+            }
+            let code = u32::from_str_radix(&digits, 16).unwrap();
+            char::from_u32(code).ok_or_else(|| {
+                format!("Escape `\\u{{{digits}}}` in string literal is not a valid character")
+            })
+        }
+        other => Err(format!("Unknown escape `\\{other}` in string literal")),
+    }
+}
+
+// Read up to `max` hex digits after `last_char`, leaving `last_char` on the last one.
+fn read_hex_digits(state: &mut ParserState, max: usize) -> String {
+    let mut digits = String::from("");
+    while digits.len() < max && advance_if(state, |c| c.is_ascii_hexdigit()) {
+        digits.push(state.last_char);
+    }
+    return digits;
+}
+
+// Move `last_char` on to the next character if `accept` takes it.  Otherwise it is
+// given back, to be read again.
+fn advance_if(state: &mut ParserState, accept: impl Fn(char) -> bool) -> bool {
+    let loc = state.lex_loc;
+    let next = getchar(state);
+    if accept(next) {
+        state.last_char = next;
+        return true;
+    }
+    state.source.unread(&[next]);
+    state.lex_loc = loc;
+    return false;
+}
+
+// Characters that can be part of a multi-character operator.
+pub fn is_operator_char(c: char) -> bool {
+    c.is_ascii_punctuation() && !matches!(c, '(' | ')' | '{' | '}' | ',' | ';' | '"' | '#' | '_')
+}
+
+// Read an operator starting at `last_char`.  Characters read past its end are given
+// back, leaving `last_char` on the first character of the operator when it is a
+// single one, or just after it otherwise.
+fn lex_operator(state: &mut ParserState, declaring: bool) -> String {
+    // Each character read, with its position for when it is given back.
+    let mut read = vec![(state.last_char, state.lex_loc)];
+    let mut len = 1;
+    loop {
+        let c = getchar(state);
+        read.push((c, state.lex_loc));
+        if !is_operator_char(c) {
+            break;
+        }
+        let candidate: String = read.iter().map(|(c, _)| c).collect();
+        if declaring {
+            len = read.len();
+            continue;
+        }
+        if !state
+            .bin_op_precedence
+            .keys()
+            .any(|op| op.starts_with(&candidate))
+        {
+            break;
+        }
+        if state.bin_op_precedence.contains_key(&candidate) {
+            len = read.len();
+        }
+    }
+
+    let op: String = read[..len].iter().map(|(c, _)| c).collect();
+    // A single character is lexed as before, from `last_char`.
+    let keep = if len == 1 { 0 } else { len };
+    let rest: Vec<char> = read[keep + 1..].iter().map(|(c, _)| *c).collect();
+    state.source.unread(&rest);
+    (state.last_char, state.lex_loc) = read[keep];
+    return op;
+}
+
+pub fn get_next_token(state: &mut ParserState) {
     state.cur_tok = get_token(state);
 }
+
+// Lex the rest of the input, returning every token up to and including TokEOF.
+pub fn tokenize(state: &mut ParserState) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    loop {
+        get_next_token(state);
+        tokens.push(state.cur_tok.clone());
+        if state.cur_tok == Token::TokEOF {
+            return tokens;
+        }
+    }
+}
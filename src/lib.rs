@@ -0,0 +1,434 @@
+pub mod ast;
+pub mod compiler;
+pub mod debuginfo;
+pub mod emit;
+pub mod lexer;
+#[cfg(feature = "orc")]
+pub mod orc;
+pub mod parser;
+pub mod runtime;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use ast::{CodegenError, PrototypeAST, Type, AST};
+use compiler::Compiler;
+use inkwell::context::Context;
+use inkwell::module::Module;
+use inkwell::passes::PassManager;
+use inkwell::values::FunctionValue;
+use inkwell::OptimizationLevel;
+use lexer::{tokenize, Source, SourceLocation, Span, Token};
+use parser::{compile_loop, parse_items, ParseError, ParserState};
+use runtime::call_top_level;
+
+// State - Everything the compiler keeps between top-level items: the parser's place
+// in the input, and the compiler the items are compiled with.  Codegen only needs the
+// `Compiler`, which `State` dereferences to.
+pub struct State<'ctx> {
+    pub parser: ParserState,
+    pub compiler: Compiler<'ctx>,
+    // Significant digits when showing a REPL result.  None means the shortest exact
+    // form.
+    pub result_precision: Option<usize>,
+    // Number of anonymous top-level expressions evaluated so far.
+    pub anon_count: usize,
+    // Ahead-of-time mode: top-level expressions are kept for a synthesized `main`
+    // instead of being run by the JIT.
+    pub aot_main: bool,
+}
+
+impl<'ctx> State<'ctx> {
+    pub fn new(context: &'ctx Context) -> State<'ctx> {
+        State {
+            parser: ParserState::new(),
+            compiler: Compiler::new(context),
+            result_precision: None,
+            anon_count: 0,
+            aot_main: false,
+        }
+    }
+
+    // Forget every definition, starting over with an empty module.
+    pub fn clear_definitions(&mut self) {
+        self.compiler.clear_definitions();
+        self.parser.clear_operators();
+    }
+
+    // Get ready to compile another, unrelated program with the same context: forget
+    // its definitions, variables and lexer position.  Options such as the optimization
+    // level are kept.
+    pub fn reset(&mut self) {
+        // The builder must not point into the module about to be dropped.
+        self.builder.clear_insertion_position();
+        self.clear_definitions();
+        self.compiler.clear_scopes();
+        self.parser.rewind();
+        self.anon_count = 0;
+    }
+}
+
+impl<'ctx> Deref for State<'ctx> {
+    type Target = Compiler<'ctx>;
+
+    fn deref(&self) -> &Compiler<'ctx> {
+        &self.compiler
+    }
+}
+
+impl<'ctx> DerefMut for State<'ctx> {
+    fn deref_mut(&mut self) -> &mut Compiler<'ctx> {
+        &mut self.compiler
+    }
+}
+
+// The precedence of the builtin binary operators.
+fn builtin_precedence() -> HashMap<String, i32> {
+    let mut bin_op_precedence = HashMap::new();
+    bin_op_precedence.insert(String::from(":"), 1);
+    bin_op_precedence.insert(String::from("="), 2);
+    bin_op_precedence.insert(String::from("?"), 3);
+    bin_op_precedence.insert(String::from("<"), 10);
+    bin_op_precedence.insert(String::from(">"), 10);
+    bin_op_precedence.insert(String::from("|"), 11);
+    bin_op_precedence.insert(String::from("&"), 13);
+    bin_op_precedence.insert(String::from("<<"), 15);
+    bin_op_precedence.insert(String::from(">>"), 15);
+    bin_op_precedence.insert(String::from("+"), 20);
+    bin_op_precedence.insert(String::from("-"), 20);
+    bin_op_precedence.insert(String::from("*"), 40);
+    bin_op_precedence.insert(String::from("/"), 40);
+    bin_op_precedence.insert(String::from("%"), 40);
+    bin_op_precedence.insert(String::from("^"), 50);
+    bin_op_precedence
+}
+
+// The prototypes of the functions that need no `extern`: the runtime functions and
+// the math functions built on LLVM intrinsics.  They are at line 0, as they are not
+// anywhere in the source, and marked as builtin.
+fn builtin_protos() -> HashMap<String, PrototypeAST> {
+    let mut function_protos = HashMap::new();
+    let builtins: [(&str, &[&str]); 12] = [
+        ("print", &["x"]),
+        ("assert", &["x"]),
+        ("readnumber", &[]),
+        ("readchar", &[]),
+        ("sqrt", &["x"]),
+        ("min", &["a", "b"]),
+        ("max", &["a", "b"]),
+        ("rem", &["a", "b"]),
+        ("xor", &["a", "b"]),
+        ("floor", &["x"]),
+        ("ceil", &["x"]),
+        ("round", &["x"]),
+    ];
+    for (name, args) in builtins {
+        // Only `xor`, which takes integers, gives one back.
+        let return_type = if name == "xor" {
+            Type::Int
+        } else {
+            Type::Double
+        };
+        let mut proto = PrototypeAST::new(
+            String::from(name),
+            args.iter().map(|arg| String::from(*arg)).collect(),
+            false,
+            0,
+            false,
+            return_type,
+            SourceLocation { line: 0, col: 0 },
+        );
+        proto.set_builtin(true);
+        function_protos.insert(String::from(name), proto);
+    }
+    function_protos
+}
+
+// Pass - A function pass that can be picked by name for the FPM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    Mem2Reg,
+    InstCombine,
+    Reassociate,
+    Gvn,
+    SimplifyCfg,
+    TailCallElim,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 6] = [
+        Pass::Mem2Reg,
+        Pass::InstCombine,
+        Pass::Reassociate,
+        Pass::Gvn,
+        Pass::SimplifyCfg,
+        Pass::TailCallElim,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.into_iter().find(|pass| pass.get_name() == name)
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Pass::Mem2Reg => "mem2reg",
+            Pass::InstCombine => "instcombine",
+            Pass::Reassociate => "reassociate",
+            Pass::Gvn => "gvn",
+            Pass::SimplifyCfg => "simplifycfg",
+            Pass::TailCallElim => "tailcallelim",
+        }
+    }
+
+    // Parse a comma-separated list of pass names, such as "mem2reg,gvn".
+    pub fn parse_list(list: &str) -> Result<Vec<Pass>, String> {
+        list.split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Pass::from_name(name).ok_or_else(|| {
+                    let known: Vec<&str> = Pass::ALL.iter().map(Pass::get_name).collect();
+                    format!(
+                        "Unknown pass `{name}`, expected one of: {}",
+                        known.join(", ")
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn add_to(&self, fpm: &PassManager<FunctionValue>) {
+        match self {
+            Pass::Mem2Reg => fpm.add_promote_memory_to_register_pass(),
+            Pass::InstCombine => fpm.add_instruction_combining_pass(),
+            Pass::Reassociate => fpm.add_reassociate_pass(),
+            Pass::Gvn => fpm.add_gvn_pass(),
+            Pass::SimplifyCfg => fpm.add_cfg_simplification_pass(),
+            Pass::TailCallElim => fpm.add_tail_call_elimination_pass(),
+        }
+    }
+}
+
+// Build the per-function pass pipeline: the given passes when there are some, or
+// else the ones for the optimization level.
+fn create_fpm<'ctx>(
+    module: &Module<'ctx>,
+    opt_level: OptimizationLevel,
+    passes: Option<&[Pass]>,
+) -> PassManager<FunctionValue<'ctx>> {
+    let fpm: PassManager<FunctionValue> = PassManager::create(module);
+    if let Some(passes) = passes {
+        for pass in passes {
+            pass.add_to(&fpm);
+        }
+        fpm.initialize();
+        return fpm;
+    }
+    if opt_level != OptimizationLevel::None {
+        // Promote allocas to registers.
+        fpm.add_promote_memory_to_register_pass();
+        // Do simple "peephole" optimizations and bit-twiddling optzns.
+        fpm.add_instruction_combining_pass();
+    }
+    if matches!(
+        opt_level,
+        OptimizationLevel::Default | OptimizationLevel::Aggressive
+    ) {
+        // Reassociate expressions.
+        fpm.add_reassociate_pass();
+        // Eliminate Common SubExpressions.
+        fpm.add_gvn_pass();
+        // Simplify the control flow graph (deleting unreachable blocks, etc).
+        fpm.add_cfg_simplification_pass();
+        // Turn self-recursive tail calls into loops.
+        fpm.add_tail_call_elimination_pass();
+    }
+    fpm.initialize();
+    fpm
+}
+
+// Severity - How bad a diagnostic is.  Errors stop the program from being built;
+// warnings don't.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// Diagnostic - One problem found while compiling a file.  They are collected as the
+// compile goes on and reported together at the end.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    // An error from the item covering `item`.  A lex or parse error points at the
+    // token it stopped on; anything else covers the whole item.
+    pub fn from_error(err: Error, item: Span) -> Self {
+        let (message, span) = match err {
+            Error::Lex(err) | Error::Parse(err) => (err.message, Span::at(err.loc)),
+            Error::Codegen(err) => (err.message, item),
+            err => (err.to_string(), item),
+        };
+        return Diagnostic {
+            severity: Severity::Error,
+            message,
+            span,
+        };
+    }
+
+    // A problem that does not stop the compile.
+    pub fn warning(message: String, span: Span) -> Self {
+        return Diagnostic {
+            severity: Severity::Warning,
+            message,
+            span,
+        };
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}: {}", self.severity, self.span, self.message)
+    }
+}
+
+// Run only the lexer over a whole program, returning its tokens ending with TokEOF.
+pub fn lex(source: &str) -> Vec<Token> {
+    let mut state = ParserState::new();
+    state.source = Source::from_text(source);
+
+    return tokenize(&mut state);
+}
+
+// Run only the parser over a whole program, returning its top-level items in order.
+// Top-level expressions come wrapped in `__toplevel.N` functions, as for `compile_to_ir`.
+pub fn parse_str(source: &str) -> Result<Vec<AST>, Error> {
+    let mut state = ParserState::new();
+    state.source = Source::from_text(source);
+
+    let items = parse_items(&mut state, 0)?;
+    return Ok(items.into_iter().map(|(node, _)| node).collect());
+}
+
+// Compile a whole program to textual LLVM IR without running any of it.  Top-level
+// expressions are kept as `__toplevel.N` functions, as for `--main`.
+pub fn compile_to_ir(source: &str) -> Result<String, Error> {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text(source);
+    state.aot_main = true;
+
+    compile_loop(&mut state)?;
+
+    return Ok(state.module.print_to_string().to_string());
+}
+
+// Error - Why the library could not compile or run a program: what `parse_str`,
+// `compile_to_ir` and `jit_eval` return, and what stops a top-level item.
+#[derive(Debug)]
+pub enum Error {
+    // A malformed token, such as an unterminated string literal.
+    Lex(ParseError),
+    Parse(ParseError),
+    Codegen(CodegenError),
+    // The JIT could not be created, or could not find a compiled expression.
+    Jit(String),
+    // The program failed while running, by dividing an integer by zero.
+    Runtime(String),
+    // There was no top-level expression to evaluate.
+    NoExpression,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Lex(err) | Error::Parse(err) => err.fmt(f),
+            Error::Codegen(err) => err.fmt(f),
+            Error::Jit(message) | Error::Runtime(message) => write!(f, "{message}"),
+            Error::NoExpression => write!(f, "No top-level expression to evaluate"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        if err.is_lex {
+            return Error::Lex(err);
+        }
+        return Error::Parse(err);
+    }
+}
+
+impl From<CodegenError> for Error {
+    fn from(err: CodegenError) -> Self {
+        Error::Codegen(err)
+    }
+}
+
+// Compile a program and run its top-level expressions in order with the JIT, returning
+// the value of the last one.  Everything is dropped afterwards, anonymous functions
+// included.
+pub fn jit_eval(source: &str) -> Result<f64, Error> {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    return state.jit_eval(source);
+}
+
+impl<'ctx> State<'ctx> {
+    // `jit_eval` with this state's options, such as the optimization level and how
+    // comparisons work.  The program is compiled into the state's module, so it can use
+    // what earlier calls defined; only its own top-level expressions are run.
+    pub fn jit_eval(&mut self, source: &str) -> Result<f64, Error> {
+        self.parser.source = Source::from_text(source);
+        self.parser.rewind();
+        self.aot_main = true;
+
+        let first = self.toplevel_exprs.len();
+        compile_loop(self)?;
+
+        self.update_jit()?;
+        let ee = self.jit.as_ref().unwrap();
+        let mut result = None;
+        for name in &self.toplevel_exprs[first..] {
+            result = Some(call_top_level(ee, name)?);
+        }
+
+        return result.ok_or(Error::NoExpression);
+    }
+}
+
+// `jit_eval` on the ORC v2 JIT instead of MCJIT.  The program is compiled into one
+// module, whose functions are only compiled when the expressions call them.
+#[cfg(feature = "orc")]
+pub fn orc_eval(source: &str) -> Result<f64, Error> {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text(source);
+    state.aot_main = true;
+
+    compile_loop(&mut state)?;
+
+    let jit = orc::OrcJit::new().map_err(Error::Jit)?;
+    jit.add_module(&state.module).map_err(Error::Jit)?;
+    let mut result = None;
+    for name in &state.toplevel_exprs {
+        result = Some(jit.call_top_level(name)?);
+    }
+
+    return result.ok_or(Error::NoExpression);
+}
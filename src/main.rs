@@ -1,75 +1,365 @@
-mod ast;
-mod lexer;
-mod parser;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process;
 
-use std::collections::HashMap;
-
-use ast::PrototypeAST;
-use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module;
-use inkwell::passes::PassManager;
-use inkwell::values::{FunctionValue, PointerValue};
-use lexer::Token;
-use parser::main_loop;
-
-pub struct State<'ctx> {
-    pub cur_tok: Token,
-    pub last_char: char,
-    pub context: &'ctx Context,
-    pub builder: Builder<'ctx>,
-    pub module: Module<'ctx>,
-    pub fpm: PassManager<FunctionValue<'ctx>>,
-    pub named_values: HashMap<String, PointerValue<'ctx>>,
-    pub function_protos: HashMap<String, PrototypeAST>,
-    pub bin_op_precedence: HashMap<String, i32>,
+use inkwell::OptimizationLevel;
+use kaleidoscope::ast::build_main;
+use kaleidoscope::emit::{emit_asm_file, emit_bitcode_file, emit_ir_file, emit_object_file};
+use kaleidoscope::lexer::{tokenize, Source};
+use kaleidoscope::parser::{check_loop, file_loop, main_loop, parse_items};
+use kaleidoscope::runtime::format_value;
+use kaleidoscope::{Diagnostic, Pass, Severity, State};
+
+// Command-line options.
+struct Options {
+    // Source file to compile. When absent, run the interactive loop.
+    input: Option<String>,
+    // Program to run instead, given on the command line. Its last value is printed.
+    eval: Option<String>,
+    // Optimization level for the FPM and the JIT.
+    opt_level: OptimizationLevel,
+    // Run exactly these FPM passes instead of the ones the optimization level picks.
+    passes: Option<Vec<Pass>>,
+    // Make `for` loops yield their last body value.
+    for_value: bool,
+    // Skip the step of `for` loops whose body assigns to the loop variable.
+    for_manual_step: bool,
+    // Make float comparisons with NaN false instead of true.
+    ordered_compare: bool,
+    // Report warnings as errors, failing the compile.
+    warnings_as_errors: bool,
+    // Read `a < b < c` as `a < b` and `b < c`.
+    chain_compare: bool,
+    // Significant digits in REPL results.
+    precision: Option<usize>,
+    // Collect top-level expressions into a `main` function instead of running them.
+    main: bool,
+    // Run the top-level expressions and print their results, without printing the IR.
+    run: bool,
+    // Write the IR here instead of to stdout.
+    emit_ir: Option<PathBuf>,
+    // Write LLVM bitcode here.
+    emit_bc: Option<PathBuf>,
+    // Write a native object file here.
+    emit_obj: Option<PathBuf>,
+    // Write native assembly here.
+    emit_asm: Option<PathBuf>,
+    // Target triple for the object file and assembly. The host when absent.
+    target: Option<String>,
+    // Generate DWARF debug info for the emitted code.
+    debug_info: bool,
+    // Only report errors, without generating any code.
+    check: bool,
+    // Print the parsed program, without generating any code.
+    dump_ast: bool,
+    // Only print the tokens of the input.
+    tokens: bool,
+}
+
+const USAGE: &str = "usage: kaleidoscope [options] [file.kal]
+
+options:
+  -e, --stdin-eval <program>
+                     run <program> and print the value of its last expression
+  --opt <0-3>        optimization level (default 2)
+  --passes <list>    run only these function passes, comma-separated, in order:
+                     mem2reg, instcombine, reassociate, gvn, simplifycfg, tailcallelim
+  --for-value        make `for` loops yield their last body value
+  --for-manual-step  don't step a `for` loop whose body assigns to its variable
+  --ordered-compare  make `<` and `>` false, not true, when either side is NaN
+  --chain-compare    read `a < b < c` as `a < b` and `b < c`, not `(a < b) < c`
+  --warnings-as-errors
+                     report warnings as errors, failing the compile
+  --precision <n>    show results with <n> significant digits (default: shortest)
+  --main             build top-level expressions into `main` instead of running them
+  --run              run the top-level expressions without printing the IR
+  --emit-ir <path>   write the IR to <path> instead of stdout
+  --emit-bc <path>   write LLVM bitcode to <path>
+  --emit-obj <path>  write a native object file to <path>
+  --emit-asm <path>  write native assembly to <path>
+  --target <triple>  target triple for --emit-obj and --emit-asm (default: host)
+  -g, --debug-info   emit DWARF debug info for the input file (needs --main)
+  --check            report errors in the input (or stdin) without generating code
+  --dump-ast         print the parsed input (or stdin), one item per line, without
+                     generating code; with --check, check it afterwards
+  --tokens           print the tokens of the input (or stdin), one per line";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        input: None,
+        eval: None,
+        opt_level: OptimizationLevel::Default,
+        passes: None,
+        for_value: false,
+        for_manual_step: false,
+        ordered_compare: false,
+        warnings_as_errors: false,
+        chain_compare: false,
+        precision: None,
+        main: false,
+        run: false,
+        emit_ir: None,
+        emit_bc: None,
+        emit_obj: None,
+        emit_asm: None,
+        target: None,
+        debug_info: false,
+        check: false,
+        dump_ast: false,
+        tokens: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--stdin-eval" => options.eval = Some(args.next().unwrap_or_else(|| usage())),
+            "--opt" => {
+                options.opt_level = match args.next().as_deref() {
+                    Some("0") => OptimizationLevel::None,
+                    Some("1") => OptimizationLevel::Less,
+                    Some("2") => OptimizationLevel::Default,
+                    Some("3") => OptimizationLevel::Aggressive,
+                    _ => usage(),
+                }
+            }
+            "--passes" => {
+                let list = args.next().unwrap_or_else(|| usage());
+                match Pass::parse_list(&list) {
+                    Ok(passes) => options.passes = Some(passes),
+                    Err(err) => {
+                        eprintln!("{err}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--for-value" => options.for_value = true,
+            "--for-manual-step" => options.for_manual_step = true,
+            "--ordered-compare" => options.ordered_compare = true,
+            "--chain-compare" => options.chain_compare = true,
+            "--warnings-as-errors" => options.warnings_as_errors = true,
+            "--precision" => {
+                options.precision = match args.next().map(|arg| arg.parse()) {
+                    Some(Ok(precision)) => Some(precision),
+                    _ => usage(),
+                }
+            }
+            "--main" => options.main = true,
+            "--run" => options.run = true,
+            "--emit-ir" => options.emit_ir = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--emit-bc" | "--emit-bitcode" => {
+                options.emit_bc = Some(args.next().unwrap_or_else(|| usage()).into())
+            }
+            "--emit-obj" => options.emit_obj = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--emit-asm" => options.emit_asm = Some(args.next().unwrap_or_else(|| usage()).into()),
+            "--target" => options.target = Some(args.next().unwrap_or_else(|| usage())),
+            "-g" | "--debug-info" => options.debug_info = true,
+            "--check" => options.check = true,
+            "--dump-ast" => options.dump_ast = true,
+            "--tokens" => options.tokens = true,
+            _ if arg.starts_with('-') || options.input.is_some() => usage(),
+            _ => options.input = Some(arg),
+        }
+    }
+    // `--main` keeps the top-level expressions for later instead of running them.
+    if options.run && options.main {
+        usage();
+    }
+    // The program given with `-e` is all there is to run.
+    if options.eval.is_some() && (options.input.is_some() || options.main) {
+        usage();
+    }
+    // Debug info describes a source file, and the JIT does not use it.
+    if options.debug_info && (options.input.is_none() || !options.main) {
+        usage();
+    }
+    options
 }
 
-impl<'ctx> State<'ctx> {
-    pub fn new(context: &'ctx Context) -> State<'ctx> {
-        let module = context.create_module("kaleidoscope");
-        let fpm: PassManager<FunctionValue> = PassManager::create(&module);
-        // Promote allocas to registers.
-        fpm.add_promote_memory_to_register_pass();
-        // Do simple "peephole" optimizations and bit-twiddling optzns.
-        fpm.add_instruction_combining_pass();
-        // Reassociate expressions.
-        fpm.add_reassociate_pass();
-        // Eliminate Common SubExpressions.
-        fpm.add_gvn_pass();
-        // Simplify the control flow graph (deleting unreachable blocks, etc).
-        fpm.add_cfg_simplification_pass();
-        fpm.initialize();
-
-        // Populate the token precedence
-        let mut bin_op_precedence = HashMap::new();
-        bin_op_precedence.insert(String::from("="), 2);
-        bin_op_precedence.insert(String::from("<"), 10);
-        bin_op_precedence.insert(String::from("+"), 20);
-        bin_op_precedence.insert(String::from("-"), 20);
-        bin_op_precedence.insert(String::from("*"), 40);
-
-        State {
-            cur_tok: Token::TokUndef,
-            last_char: ' ',
-            context,
-            builder: context.create_builder(),
-            module,
-            fpm,
-            named_values: HashMap::new(),
-            function_protos: HashMap::new(),
-            bin_op_precedence,
+// Read the whole input file, or all of stdin.
+fn read_input(input: Option<&str>) -> String {
+    let text = match input {
+        Some(path) => std::fs::read_to_string(path),
+        None => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text).map(|_| text)
         }
+    };
+    text.unwrap_or_else(|err| {
+        eprintln!("Could not read input: {err}");
+        process::exit(1);
+    })
+}
+
+// Check `text`, printing every error found. Exits with status 1 when there were errors.
+fn check(state: &mut State, text: &str) -> ! {
+    state.parser.source = Source::from_text(text);
+
+    let diagnostics = check_loop(&mut state.parser);
+    report(&diagnostics);
+    process::exit(if diagnostics.is_empty() { 0 } else { 1 });
+}
+
+// Parse `text` and print each top-level item, as source. Returns false, after printing
+// the error, when it does not parse.
+fn dump_ast(state: &mut State, text: &str) -> bool {
+    state.parser.source = Source::from_text(text);
+
+    match parse_items(&mut state.parser, 0) {
+        Ok(items) => {
+            for (node, _) in items {
+                println!("{node};");
+            }
+            return true;
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            return false;
+        }
+    }
+}
+
+// Run the program given on the command line and print its value. Exits with status 1
+// when it cannot be compiled or run.
+fn eval(state: &mut State, program: &str) -> ! {
+    match state.jit_eval(program) {
+        Ok(value) => {
+            println!("{}", format_value(value, state.result_precision));
+            process::exit(0);
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            process::exit(1);
+        }
+    }
+}
+
+// Print the diagnostics of a compile, one per line.
+fn report(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{diagnostic}");
     }
 }
 
+// Print the tokens of the input file, or of stdin, one per line.
+fn print_tokens(state: &mut State, input: Option<&str>) -> ! {
+    state.parser.source = match input {
+        Some(path) => match File::open(path) {
+            Ok(file) => Source::from_reader(file),
+            Err(err) => {
+                eprintln!("Could not read `{path}`: {err}");
+                process::exit(1);
+            }
+        },
+        None => Source::from_reader(std::io::stdin()),
+    };
+
+    for token in tokenize(&mut state.parser) {
+        println!("{token:?}");
+    }
+    process::exit(0);
+}
+
 fn main() {
     // Statements here are executed when the compiled binary is called
+    let options = parse_args();
     let context = Context::create();
     let mut state = State::new(&context);
+    state.set_opt_level(options.opt_level);
+    state.set_passes(options.passes);
+    state.for_returns_body = options.for_value;
+    state.for_manual_step = options.for_manual_step;
+    state.ordered_compare = options.ordered_compare;
+    state.parser.chain_compare = options.chain_compare;
+    state.warnings_as_errors = options.warnings_as_errors;
+    state.result_precision = options.precision;
+    state.aot_main = options.main;
+
+    if let Some(program) = &options.eval {
+        eval(&mut state, program);
+    }
+    if options.check || options.dump_ast {
+        let text = read_input(options.input.as_deref());
+        if options.dump_ast {
+            let parsed = dump_ast(&mut state, &text);
+            if !options.check {
+                process::exit(if parsed { 0 } else { 1 });
+            }
+            // Check from the start, without the definitions the dump has seen.
+            state.reset();
+        }
+        check(&mut state, &text);
+    }
+    if options.tokens {
+        print_tokens(&mut state, options.input.as_deref());
+    }
 
-    // Run the main "interpreter loop" now.
-    main_loop(&mut state);
+    match &options.input {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Could not read `{path}`: {err}");
+                process::exit(1);
+            });
+            state.parser.source = Source::from_text(&text);
+            if options.debug_info {
+                state.enable_debug_info(path);
+            }
+            let diagnostics = file_loop(&mut state);
+            report(&diagnostics);
+            if diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error)
+            {
+                process::exit(1);
+            }
+        }
+        // Run the main "interpreter loop" now.
+        None => main_loop(&mut state),
+    }
 
-    println!("\n{}", state.module.print_to_string().to_string());
+    if options.main {
+        if let Err(err) = build_main(&mut state) {
+            eprintln!("Error: {err}");
+            process::exit(1);
+        }
+    }
+    state.finalize_debug_info();
+
+    match &options.emit_ir {
+        Some(path) => {
+            if let Err(err) = emit_ir_file(&state, path) {
+                eprintln!("Could not emit IR: {err}");
+                process::exit(1);
+            }
+        }
+        // When running, the results are the output.
+        None if options.run => (),
+        None => println!("\n{}", state.module.print_to_string().to_string()),
+    }
+
+    if let Some(path) = &options.emit_bc {
+        if let Err(err) = emit_bitcode_file(&state, path) {
+            eprintln!("Could not emit bitcode: {err}");
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &options.emit_obj {
+        if let Err(err) = emit_object_file(&state, path, options.target.as_deref()) {
+            eprintln!("Could not emit object file: {err}");
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &options.emit_asm {
+        if let Err(err) = emit_asm_file(&state, path, options.target.as_deref()) {
+            eprintln!("Could not emit assembly: {err}");
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,206 @@
+// An ORC v2 JIT, for the `orc` feature.  inkwell only wraps the legacy MCJIT
+// `ExecutionEngine`, so this goes straight to the LLJIT in the LLVM C API.
+//
+// Unlike MCJIT, LLJIT compiles lazily: adding a module only registers its definitions,
+// and the module is compiled the first time one of them is looked up.  Each module is
+// added with its own resource tracker, so it can be removed again and its names
+// defined anew by a later module.
+
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use inkwell::module::Module;
+use inkwell::targets::{InitializationConfig, Target};
+use llvm_sys::bit_reader::LLVMParseBitcodeInContext2;
+use llvm_sys::bit_writer::LLVMWriteBitcodeToMemoryBuffer;
+use llvm_sys::core::{LLVMDisposeMemoryBuffer, LLVMDisposeModule};
+use llvm_sys::error::{LLVMDisposeErrorMessage, LLVMErrorRef, LLVMGetErrorMessage};
+use llvm_sys::orc2::lljit::*;
+use llvm_sys::orc2::*;
+use llvm_sys::prelude::LLVMModuleRef;
+
+use crate::runtime::{
+    assert, jit_divzero, print, printd, putchard, readchar, readnumber, take_runtime_error,
+};
+use crate::Error;
+
+pub struct OrcJit {
+    jit: LLVMOrcLLJITRef,
+    // Modules are moved into this context before they are handed to the JIT.
+    context: LLVMOrcThreadSafeContextRef,
+}
+
+// OrcModule - A module added to an `OrcJit`.  One that is never removed stays in the
+// JIT until the JIT is dropped.
+pub struct OrcModule {
+    tracker: LLVMOrcResourceTrackerRef,
+}
+
+impl OrcJit {
+    // Create a JIT for the host, with the runtime functions defined in it.  Any other
+    // function a module declares, such as `sin`, is looked up in the process.
+    pub fn new() -> Result<OrcJit, String> {
+        Target::initialize_native(&InitializationConfig::default())?;
+        unsafe {
+            let mut jit = ptr::null_mut();
+            // A null builder gives the default LLJIT for the host.
+            check(LLVMOrcCreateLLJIT(&mut jit, ptr::null_mut()))?;
+            let orc = OrcJit {
+                jit,
+                context: LLVMOrcCreateNewThreadSafeContext(),
+            };
+            orc.add_process_symbols()?;
+            orc.add_runtime_symbols()?;
+            return Ok(orc);
+        }
+    }
+
+    unsafe fn add_process_symbols(&self) -> Result<(), String> {
+        let mut generator = ptr::null_mut();
+        check(LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+            &mut generator,
+            LLVMOrcLLJITGetGlobalPrefix(self.jit),
+            None,
+            ptr::null_mut(),
+        ))?;
+        LLVMOrcJITDylibAddGenerator(LLVMOrcLLJITGetMainJITDylib(self.jit), generator);
+        return Ok(());
+    }
+
+    // Define the runtime functions at their host addresses, as `add_runtime_mappings`
+    // does for MCJIT.
+    unsafe fn add_runtime_symbols(&self) -> Result<(), String> {
+        let builtins: [(&str, usize); 7] = [
+            ("putchard", putchard as *const () as usize),
+            ("printd", printd as *const () as usize),
+            ("print", print as *const () as usize),
+            ("assert", assert as *const () as usize),
+            ("readnumber", readnumber as *const () as usize),
+            ("readchar", readchar as *const () as usize),
+            ("kaleidoscope.divzero", jit_divzero as *const () as usize),
+        ];
+        let mut symbols = Vec::with_capacity(builtins.len());
+        for (name, address) in builtins {
+            let name = CString::new(name).unwrap();
+            symbols.push(LLVMJITCSymbolMapPair {
+                Name: LLVMOrcLLJITMangleAndIntern(self.jit, name.as_ptr()),
+                Sym: LLVMJITEvaluatedSymbol {
+                    Address: address as u64,
+                    Flags: LLVMJITSymbolFlags {
+                        GenericFlags: LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported
+                            as u8,
+                        TargetFlags: 0,
+                    },
+                },
+            });
+        }
+        // The materialization unit takes over the interned names.
+        let unit = LLVMOrcAbsoluteSymbols(symbols.as_mut_ptr(), symbols.len());
+        let err = LLVMOrcJITDylibDefine(LLVMOrcLLJITGetMainJITDylib(self.jit), unit);
+        if !err.is_null() {
+            LLVMOrcDisposeMaterializationUnit(unit);
+        }
+        return check(err);
+    }
+
+    // Add a copy of the module's definitions to the JIT.  Nothing is compiled until
+    // one of them is looked up.
+    pub fn add_module(&self, module: &Module) -> Result<OrcModule, String> {
+        unsafe {
+            let module = self.copy_module(module)?;
+            let tsm = LLVMOrcCreateNewThreadSafeModule(module, self.context);
+            let tracker =
+                LLVMOrcJITDylibCreateResourceTracker(LLVMOrcLLJITGetMainJITDylib(self.jit));
+            let err = LLVMOrcLLJITAddLLVMIRModuleWithRT(self.jit, tracker, tsm);
+            if !err.is_null() {
+                LLVMOrcDisposeThreadSafeModule(tsm);
+                LLVMOrcReleaseResourceTracker(tracker);
+                return Err(error_message(err));
+            }
+            return Ok(OrcModule { tracker });
+        }
+    }
+
+    // Remove a module's definitions, and its code if it was compiled.  Functions it
+    // defined can then be defined again by another module.
+    pub fn remove_module(&self, module: OrcModule) -> Result<(), String> {
+        unsafe {
+            let err = LLVMOrcResourceTrackerRemove(module.tracker);
+            LLVMOrcReleaseResourceTracker(module.tracker);
+            return check(err);
+        }
+    }
+
+    // The address of a defined function, compiling its module if it hasn't been.
+    pub fn lookup(&self, name: &str) -> Result<u64, String> {
+        let c_name = CString::new(name).map_err(|err| err.to_string())?;
+        let mut address = 0;
+        unsafe {
+            check(LLVMOrcLLJITLookup(self.jit, &mut address, c_name.as_ptr()))
+                .map_err(|err| format!("Could not find `{name}` in the JIT: {err}"))?;
+        }
+        return Ok(address);
+    }
+
+    // Run the compiled top-level expression `name`, which takes no arguments.  An error
+    // it ran into comes back as Error::Runtime, as with MCJIT.
+    pub fn call_top_level(&self, name: &str) -> Result<f64, Error> {
+        let address = self.lookup(name).map_err(Error::Jit)?;
+        let value = unsafe {
+            let func: unsafe extern "C" fn() -> f64 = std::mem::transmute(address as usize);
+            func()
+        };
+        if let Some(err) = take_runtime_error() {
+            return Err(err);
+        }
+        return Ok(value);
+    }
+
+    // The JIT's modules live in a context of their own, so the module is moved over
+    // as bitcode.
+    unsafe fn copy_module(&self, module: &Module) -> Result<LLVMModuleRef, String> {
+        let buffer = LLVMWriteBitcodeToMemoryBuffer(module.as_mut_ptr());
+        let mut copy = ptr::null_mut();
+        let failed = LLVMParseBitcodeInContext2(
+            LLVMOrcThreadSafeContextGetContext(self.context),
+            buffer,
+            &mut copy,
+        );
+        LLVMDisposeMemoryBuffer(buffer);
+        if failed != 0 {
+            if !copy.is_null() {
+                LLVMDisposeModule(copy);
+            }
+            return Err(String::from("Could not copy the module into the JIT"));
+        }
+        return Ok(copy);
+    }
+}
+
+impl Drop for OrcJit {
+    fn drop(&mut self) {
+        unsafe {
+            // Nothing to do with an error this late.
+            let err = LLVMOrcDisposeLLJIT(self.jit);
+            if !err.is_null() {
+                LLVMDisposeErrorMessage(LLVMGetErrorMessage(err));
+            }
+            LLVMOrcDisposeThreadSafeContext(self.context);
+        }
+    }
+}
+
+unsafe fn check(err: LLVMErrorRef) -> Result<(), String> {
+    if err.is_null() {
+        return Ok(());
+    }
+    return Err(error_message(err));
+}
+
+// Take the message out of an error, which frees it.
+unsafe fn error_message(err: LLVMErrorRef) -> String {
+    let message = LLVMGetErrorMessage(err);
+    let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeErrorMessage(message);
+    return text;
+}
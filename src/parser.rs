@@ -1,72 +1,234 @@
-use core::panic;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::Write;
 
 use crate::ast::{
-    codegen, BinaryExprAST, CallExprAST, ForExprAST, FunctionAST, IfExprAST, NumberExprAST,
-    PrototypeAST, UnaryExprAST, VarExprAST, VariableExprAST, AST,
+    codegen, BinaryExprAST, BlockExprAST, BreakExprAST, CallConv, CallExprAST, Checker,
+    CodegenError, ContinueExprAST, ForExprAST, FunctionAST, FunctionRefExprAST, GlobalAST,
+    IfExprAST, NumberExprAST, ProjectionExprAST, PrototypeAST, StringExprAST, TupleExprAST, Type,
+    UnaryExprAST, VarExprAST, VariableExprAST, Visitor, WhileExprAST, AST,
 };
-use crate::lexer::{get_next_token, Token};
-use crate::State;
-use inkwell::OptimizationLevel;
+use crate::lexer::{
+    get_next_token, is_operator_char, LexerConfig, Source, SourceLocation, Span, Token,
+};
+use crate::runtime::{add_runtime_mappings, call_top_level, format_value};
+use crate::{builtin_precedence, create_fpm, Diagnostic, Error, State};
+
+// ParserState - Where the lexer and the parser are in the input, and the binary
+// operators they know of.
+pub struct ParserState {
+    pub cur_tok: Token,
+    pub last_char: char,
+    pub source: Source,
+    pub lexer_config: LexerConfig,
+    // Position of `last_char`, and of the start of `cur_tok`.
+    pub lex_loc: SourceLocation,
+    pub cur_loc: SourceLocation,
+    pub bin_op_precedence: HashMap<String, i32>,
+    // The operators of `unary` definitions and externs.
+    pub unary_ops: HashSet<String>,
+    // Precedences given through `set_precedence`.  Unlike those of `binary`
+    // definitions, they survive clearing the definitions.
+    pub precedence_overrides: HashMap<String, i32>,
+    // How deeply expressions may nest before the parser gives up, and how deep it is
+    // now.  Keeps pathological input from overflowing the stack.
+    pub max_parse_depth: usize,
+    pub parse_depth: usize,
+    // Whether `a < b < c` means `a < b` and `b < c` instead of `(a < b) < c`.
+    pub chain_compare: bool,
+    // Whether a line starting with ':' is a REPL command.  Only the REPL sets it.
+    pub commands: bool,
+}
+
+impl ParserState {
+    pub fn new() -> ParserState {
+        ParserState {
+            cur_tok: Token::TokUndef,
+            last_char: ' ',
+            source: Source::stdin(),
+            lexer_config: LexerConfig::default(),
+            lex_loc: SourceLocation { line: 1, col: 0 },
+            cur_loc: SourceLocation { line: 1, col: 0 },
+            bin_op_precedence: builtin_precedence(),
+            unary_ops: HashSet::new(),
+            precedence_overrides: HashMap::new(),
+            max_parse_depth: 256,
+            parse_depth: 0,
+            chain_compare: false,
+            commands: false,
+        }
+    }
+
+    // Forget the operators of `unary` and `binary` definitions, keeping the builtin
+    // ones and the precedences given through `set_precedence`.
+    pub fn clear_operators(&mut self) {
+        self.unary_ops.clear();
+        self.bin_op_precedence = builtin_precedence();
+        self.bin_op_precedence
+            .extend(self.precedence_overrides.clone());
+    }
+
+    // Go back to the start of the input, with no token read yet.
+    pub fn rewind(&mut self) {
+        self.cur_tok = Token::TokUndef;
+        self.last_char = ' ';
+        self.lex_loc = SourceLocation { line: 1, col: 0 };
+        self.cur_loc = SourceLocation { line: 1, col: 0 };
+        self.parse_depth = 0;
+    }
+
+    // The precedence binary operator `op` parses with, or None if it is not one.
+    pub fn get_precedence(&self, op: &str) -> Option<i32> {
+        self.bin_op_precedence.get(op).copied()
+    }
+
+    // Give binary operator `op` the precedence `precedence`, overriding the builtin
+    // one or that of a `binary` definition.  An operator that is not builtin still
+    // needs a `binary` definition before expressions using it can be compiled.
+    pub fn set_precedence(&mut self, op: &str, precedence: i32) -> Result<(), String> {
+        if op.is_empty() || !op.chars().all(is_operator_char) {
+            return Err(format!("Invalid operator `{op}`"));
+        }
+        if !(1..=100).contains(&precedence) {
+            return Err(String::from("Invalid precedence: must be 1..100"));
+        }
+        self.bin_op_precedence.insert(op.to_string(), precedence);
+        self.precedence_overrides.insert(op.to_string(), precedence);
+        return Ok(());
+    }
+}
+
+impl Default for ParserState {
+    fn default() -> Self {
+        ParserState::new()
+    }
+}
 
-pub fn get_tok_precedence(state: &State) -> i32 {
-    // get the char of the token
+// ParseError - A syntax error and the position of the token it was found at.
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub loc: SourceLocation,
+    // Whether the lexer found the problem, in a malformed token, rather than the parser.
+    pub is_lex: bool,
+}
+
+impl ParseError {
+    pub fn new(state: &ParserState, message: &str) -> Self {
+        return ParseError {
+            message: message.to_string(),
+            loc: state.cur_loc,
+            is_lex: false,
+        };
+    }
+
+    // The error the lexer reported in a TokError token.
+    pub fn lex(state: &ParserState, message: &str) -> Self {
+        return ParseError {
+            is_lex: true,
+            ..ParseError::new(state, message)
+        };
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.loc)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type ParseResult = Result<AST, ParseError>;
+
+pub fn get_tok_precedence(state: &ParserState) -> i32 {
+    // get the operator of the token
     let bin_op = match &state.cur_tok {
-        Token::TokChar(this_char) => this_char,
+        Token::TokChar(this_char) => this_char.to_string(),
+        Token::TokOp(op) => op.clone(),
         _ => return -1,
     };
-    let precedence = state.bin_op_precedence.get(&bin_op.to_string());
-    match precedence {
-        Some(val) => *val,
-        None => -1,
-    }
+    state.get_precedence(&bin_op).unwrap_or(-1)
 }
 
 // numberexpr ::= number
-pub fn parse_number_expr(state: &mut State) -> AST {
+pub fn parse_number_expr(state: &mut ParserState) -> ParseResult {
     let result = match state.cur_tok {
         Token::TokNumber(num) => AST::Number(NumberExprAST::new(num)),
-        _ => AST::Null,
+        Token::TokInt(num) => AST::Number(NumberExprAST::new_int(num)),
+        _ => return Err(ParseError::new(state, "Expected a number")),
     };
     get_next_token(state); // consume the Number
-    return result;
+    return Ok(result);
+}
+
+// stringexpr ::= string
+pub fn parse_string_expr(state: &mut ParserState) -> ParseResult {
+    let result = match state.cur_tok {
+        Token::TokString(ref val) => AST::String(StringExprAST::new(val.clone())),
+        _ => return Err(ParseError::new(state, "Expected a string")),
+    };
+    get_next_token(state); // consume the string
+    return Ok(result);
+}
+
+// boolexpr ::= 'true' | 'false'
+pub fn parse_bool_expr(state: &mut ParserState) -> ParseResult {
+    let result = match state.cur_tok {
+        Token::TokTrue => AST::Number(NumberExprAST::new_bool(true)),
+        Token::TokFalse => AST::Number(NumberExprAST::new_bool(false)),
+        _ => return Err(ParseError::new(state, "Expected `true` or `false`")),
+    };
+    get_next_token(state); // consume the literal
+    return Ok(result);
 }
 
-// parenexpr ::= '(' expression ')'
-pub fn parse_paren_expr(state: &mut State) -> AST {
+// parenexpr
+//   ::= '(' expression ')'
+//   ::= '(' expression (',' expression)+ ')'
+pub fn parse_paren_expr(state: &mut ParserState) -> ParseResult {
     get_next_token(state); // eat (.
 
-    let v = parse_expression(state);
+    let v = parse_expression(state)?;
 
-    if matches!(v, AST::Null) {
-        return v;
+    // A ',' makes it a tuple.
+    if matches!(state.cur_tok, Token::TokChar(',')) {
+        let mut elems = vec![v];
+        while matches!(state.cur_tok, Token::TokChar(',')) {
+            get_next_token(state); // eat ,.
+            elems.push(parse_expression(state)?);
+        }
+        if !matches!(state.cur_tok, Token::TokChar(')')) {
+            return Err(ParseError::new(state, "Expected ')' or ',' in tuple"));
+        }
+        get_next_token(state); // eat ).
+        return Ok(AST::Tuple(TupleExprAST::new(elems)));
     }
 
-    // If we don't get a ")" then we should panic
     if !matches!(state.cur_tok, Token::TokChar(')')) {
-        panic!("Expected ')'");
+        return Err(ParseError::new(state, "Expected ')'"));
     }
 
     get_next_token(state); // eat ).
 
-    return v;
+    return Ok(v);
 }
 
 // identifierexpr
 //   ::= identifier
 //   ::= identifier '(' expression* ')'
-pub fn parse_identifier_expr(state: &mut State) -> AST {
+pub fn parse_identifier_expr(state: &mut ParserState) -> ParseResult {
     let id_name = match state.cur_tok.clone() {
         Token::TokIdentifier(a) => a,
-        _ => return AST::Null,
+        _ => return Err(ParseError::new(state, "Expected an identifier")),
     };
+    let loc = state.cur_loc;
 
     get_next_token(state); // eat the identifier
 
     // Handle simple variable reference
     if !matches!(state.cur_tok, Token::TokChar('(')) {
-        return AST::Variable(VariableExprAST::new(id_name));
+        return Ok(AST::Variable(VariableExprAST::new(id_name, loc)));
     }
 
     // Call.
@@ -74,7 +236,7 @@ pub fn parse_identifier_expr(state: &mut State) -> AST {
     let mut args: Vec<Box<AST>> = Vec::new();
     if !matches!(state.cur_tok, Token::TokChar(')')) {
         loop {
-            let arg = parse_expression(state);
+            let arg = parse_expression(state)?;
             args.push(Box::new(arg));
 
             if matches!(state.cur_tok, Token::TokChar(')')) {
@@ -82,7 +244,10 @@ pub fn parse_identifier_expr(state: &mut State) -> AST {
             }
 
             if !matches!(state.cur_tok, Token::TokChar(',')) {
-                panic!("Expected ')' or ',' in argument list")
+                return Err(ParseError::new(
+                    state,
+                    "Expected ')' or ',' in argument list",
+                ));
             }
 
             get_next_token(state);
@@ -92,29 +257,52 @@ pub fn parse_identifier_expr(state: &mut State) -> AST {
     // Eat the ')'.
     get_next_token(state);
 
-    return AST::Call(CallExprAST::new(id_name, args));
+    return Ok(AST::Call(CallExprAST::new(id_name, args, loc)));
 }
 
 // primary
 //   ::= identifierexpr
 //   ::= numberexpr
+//   ::= boolexpr
+//   ::= stringexpr
 //   ::= parenexpr
-fn parse_primary(state: &mut State) -> AST {
+//   ::= blockexpr
+//   ::= 'break'
+//   ::= 'continue'
+fn parse_primary(state: &mut ParserState) -> ParseResult {
     match state.cur_tok {
         Token::TokChar('(') => return parse_paren_expr(state),
+        Token::TokChar('{') => return parse_block_expr(state),
         Token::TokIdentifier(_) => return parse_identifier_expr(state),
-        Token::TokNumber(_) => return parse_number_expr(state),
+        Token::TokNumber(_) | Token::TokInt(_) => return parse_number_expr(state),
+        Token::TokTrue | Token::TokFalse => return parse_bool_expr(state),
+        Token::TokString(_) => return parse_string_expr(state),
         Token::TokIf => return parse_if_expr(state),
         Token::TokFor => return parse_for_expr(state),
+        Token::TokWhile => return parse_while_expr(state),
+        Token::TokBreak | Token::TokContinue => return parse_loop_jump(state),
         Token::TokVar => return parse_var_expr(state),
-        _ => panic!(
-            "Unknown token `{:?}` when expecting an expression.",
-            state.cur_tok
-        ),
+        Token::TokError(ref message) => {
+            let message = message.clone();
+            return Err(ParseError::lex(state, &message));
+        }
+        _ => {
+            let message = format!(
+                "Unknown token `{:?}` when expecting an expression",
+                state.cur_tok
+            );
+            return Err(ParseError::new(state, &message));
+        }
     }
 }
 
-fn parse_bin_op_rhs(state: &mut State, expr_prec: i32, lhs: AST) -> AST {
+// Whether a chain of `op` groups from the right: assignment, and powers as in
+// mathematics, so `2^3^2` is `2^(3^2)`.
+fn is_right_assoc(op: &str) -> bool {
+    op == "=" || op == "^"
+}
+
+fn parse_bin_op_rhs(state: &mut ParserState, expr_prec: i32, lhs: AST) -> ParseResult {
     let mut lhs_loop = lhs;
     loop {
         let tok_prec = get_tok_precedence(&state);
@@ -122,71 +310,226 @@ fn parse_bin_op_rhs(state: &mut State, expr_prec: i32, lhs: AST) -> AST {
         // If this is a binop that binds at least as tightly as the current binop,
         // consume it, otherwise we are done.
         if tok_prec < expr_prec {
-            return lhs_loop;
+            return Ok(lhs_loop);
         }
 
         // Okay, we know this is a binop.
+        let op_loc = state.cur_loc;
         let binop = match state.cur_tok {
-            Token::TokChar(a) => a,
-            _ => return AST::Null,
+            Token::TokChar(a) => a.to_string(),
+            Token::TokOp(ref op) => op.clone(),
+            _ => return Err(ParseError::new(state, "Expected a binary operator")),
         };
 
         get_next_token(state); // eat binop
 
-        // Parse the primary expression after the binary operator.
-        let mut rhs = parse_unary(state);
-
-        if matches!(rhs, AST::Null) {
-            return rhs;
+        if binop == "?" {
+            lhs_loop = parse_nested(state, |state| parse_conditional(state, lhs_loop, tok_prec))?;
+            continue;
         }
 
+        // Parse the primary expression after the binary operator.
+        let mut rhs = parse_unary(state)?;
+
         // If BinOp binds less tightly with RHS than the operator after RHS, let
-        // the pending operator take RHS as its LHS.
+        // the pending operator take RHS as its LHS.  A right-associative BinOp also
+        // lets an operator of the same precedence take it, so `a = b = c` is
+        // `a = (b = c)`.
         let next_prec = get_tok_precedence(&state);
-        if tok_prec < next_prec {
-            rhs = parse_bin_op_rhs(state, tok_prec + 1, rhs);
+        if is_right_assoc(&binop) && tok_prec <= next_prec {
+            rhs = parse_nested(state, |state| parse_bin_op_rhs(state, tok_prec, rhs))?;
+        } else if tok_prec < next_prec {
+            rhs = parse_nested(state, |state| parse_bin_op_rhs(state, tok_prec + 1, rhs))?;
+        }
+
+        // In chained comparison mode, gather the rest of `a < b < c ...` at this
+        // level before building anything.
+        if state.chain_compare && is_comparison(&binop) {
+            let mut ops = vec![binop];
+            let mut operands = vec![lhs_loop, rhs];
+            while get_tok_precedence(&state) == tok_prec {
+                let op = match state.cur_tok {
+                    Token::TokChar(op @ ('<' | '>')) => op.to_string(),
+                    _ => break,
+                };
+                get_next_token(state); // eat the comparison.
+
+                let mut rhs = parse_unary(state)?;
+                if tok_prec < get_tok_precedence(&state) {
+                    rhs = parse_nested(state, |state| parse_bin_op_rhs(state, tok_prec + 1, rhs))?;
+                }
+                ops.push(op);
+                operands.push(rhs);
+            }
+            lhs_loop = lower_compare_chain(state, ops, operands);
+            continue;
+        }
+
+        // Only a variable can be assigned to.
+        if binop == "=" && !matches!(lhs_loop, AST::Variable(_)) {
+            return Err(ParseError {
+                message: String::from("Destination of '=' must be a variable"),
+                loc: op_loc,
+                is_lex: false,
+            });
         }
 
         lhs_loop = AST::Binary(BinaryExprAST::new(binop, lhs_loop, rhs));
     }
 }
 
-fn parse_expression(state: &mut State) -> AST {
-    let lhs = parse_unary(state);
-    if matches!(lhs, AST::Null) {
-        return lhs;
-    } else {
-        return parse_bin_op_rhs(state, 0, lhs);
+fn is_comparison(op: &str) -> bool {
+    op == "<" || op == ">"
+}
+
+// Lower `a < b < c` to `(a < b) && (b < c)`, evaluating each operand once, in order,
+// and stopping at the first false comparison:
+//
+//   var cmp.0 = a in var cmp.1 = b in if cmp.0 < cmp.1 then cmp.1 < c else false
+//
+// The temporaries can't clash with user variables, as identifiers have no '.'.
+fn lower_compare_chain(state: &ParserState, ops: Vec<String>, operands: Vec<AST>) -> AST {
+    let mut operands = operands.into_iter();
+    let first = operands.next().unwrap();
+    if ops.len() == 1 {
+        let op = ops.into_iter().next().unwrap();
+        return AST::Binary(BinaryExprAST::new(op, first, operands.next().unwrap()));
+    }
+
+    let names = vec![(String::from("cmp.0"), first)];
+    let body = lower_compare_link(state, 0, &ops, &mut operands);
+    return AST::Var(VarExprAST::new(names, body));
+}
+
+// The comparisons from `ops[i]` on, with operand `i` already in `cmp.i`.
+fn lower_compare_link(
+    state: &ParserState,
+    i: usize,
+    ops: &[String],
+    operands: &mut std::vec::IntoIter<AST>,
+) -> AST {
+    let temp = |i: usize| AST::Variable(VariableExprAST::new(format!("cmp.{i}"), state.cur_loc));
+    let next = operands.next().unwrap();
+    if i + 1 == ops.len() {
+        return AST::Binary(BinaryExprAST::new(ops[i].clone(), temp(i), next));
+    }
+
+    let compare = AST::Binary(BinaryExprAST::new(ops[i].clone(), temp(i), temp(i + 1)));
+    let rest = lower_compare_link(state, i + 1, ops, operands);
+    let short_circuit = AST::If(IfExprAST::new(
+        compare,
+        rest,
+        AST::Number(NumberExprAST::new_bool(false)),
+    ));
+    let names = vec![(format!("cmp.{}", i + 1), next)];
+    return AST::Var(VarExprAST::new(names, short_circuit));
+}
+
+// conditional ::= expression '?' expression ':' expression
+//
+// Sugar for an if expression.  Called with the '?' already eaten.
+fn parse_conditional(state: &mut ParserState, cond: AST, prec: i32) -> ParseResult {
+    // The then branch ends at the ':', which would otherwise be read as sequencing.
+    let then = parse_unary(state)?;
+    let then = parse_bin_op_rhs(state, state.bin_op_precedence[":"] + 1, then)?;
+
+    if !matches!(state.cur_tok, Token::TokChar(':')) {
+        return Err(ParseError::new(
+            state,
+            "Expected ':' in conditional expression",
+        ));
+    }
+    get_next_token(state); // eat the ':'
+
+    // Parsing the else branch at the precedence of '?' makes `a ? b : c ? d : e` nest
+    // to the right.
+    let els = parse_unary(state)?;
+    let els = parse_bin_op_rhs(state, prec, els)?;
+
+    return Ok(AST::If(IfExprAST::new(cond, then, els)));
+}
+
+fn parse_expression(state: &mut ParserState) -> ParseResult {
+    return parse_nested(state, |state| {
+        let lhs = parse_unary(state)?;
+        parse_bin_op_rhs(state, 0, lhs)
+    });
+}
+
+// Run a parse step one level deeper.  Every way the parser can recurse goes through
+// here, so input nested past `max_parse_depth` is an error instead of a stack overflow.
+fn parse_nested(
+    state: &mut ParserState,
+    parse: impl FnOnce(&mut ParserState) -> ParseResult,
+) -> ParseResult {
+    if state.parse_depth >= state.max_parse_depth {
+        let message = format!(
+            "Expression nested too deeply (more than {} levels)",
+            state.max_parse_depth
+        );
+        return Err(ParseError::new(state, &message));
+    }
+    state.parse_depth += 1;
+    let result = parse(state);
+    state.parse_depth -= 1;
+    return result;
+}
+
+// postfix ::= primary ('.' integer)*
+fn parse_postfix(state: &mut ParserState) -> ParseResult {
+    let mut node = parse_primary(state)?;
+    while let Token::TokProject(index) = state.cur_tok {
+        node = AST::Projection(ProjectionExprAST::new(node, index, state.cur_loc));
+        get_next_token(state); // eat the projection
     }
+    return Ok(node);
 }
 
-fn parse_unary(state: &mut State) -> AST {
+// unary
+//   ::= postfix
+//   ::= '&' identifier
+//   ::= unaryop unary
+fn parse_unary(state: &mut ParserState) -> ParseResult {
     // If the current token is not an operator, it must be a primary expr.
     if !matches!(state.cur_tok, Token::TokChar(_)) {
-        return parse_primary(state);
+        return parse_postfix(state);
     };
 
     // If this is a unary operator, read it.
     match state.cur_tok {
         Token::TokChar(this_char) => {
-            if this_char == '(' || this_char == ')' {
-                return parse_primary(state);
+            if matches!(this_char, '(' | ')' | '{' | '}') {
+                return parse_postfix(state);
             }
             get_next_token(state);
-            let operand = parse_unary(state);
-            return AST::Unary(UnaryExprAST::new(this_char, operand))
+            // `&name` takes the address of a function, unless the program defined its
+            // own `unary&`.
+            let builtin_ref = !state.unary_ops.contains("&");
+            if let ('&', Token::TokIdentifier(name), true) =
+                (this_char, &state.cur_tok, builtin_ref)
+            {
+                let node = AST::FunctionRef(FunctionRefExprAST::new(name.clone(), state.cur_loc));
+                get_next_token(state); // eat the identifier
+                return Ok(node);
+            }
+            let operand = parse_nested(state, parse_unary)?;
+            return Ok(AST::Unary(UnaryExprAST::new(this_char, operand)));
         }
-        _ => return AST::Null,
+        _ => return Err(ParseError::new(state, "Expected a unary operator")),
     }
 }
 
 // prototype
-//   ::= id '(' id* ')'
-fn parse_prototype(state: &mut State) -> AST {
+//   ::= id '(' id* '...'? ')' (':' type)?
+// type
+//   ::= 'double' | 'int' | 'bool'
+//   ::= '(' 'double' (',' 'double')+ ')'
+fn parse_prototype(state: &mut ParserState) -> ParseResult {
     let mut fn_name: String;
 
     let kind: usize; // 0 = identifier, 1 = unary, 2 = binary.
     let mut binary_precedence = 30;
+    let loc = state.cur_loc;
 
     match state.cur_tok.clone() {
         Token::TokIdentifier(a) => {
@@ -196,22 +539,20 @@ fn parse_prototype(state: &mut State) -> AST {
         }
         Token::TokBinary => {
             get_next_token(state);
-            let this_char = match state.cur_tok {
-                Token::TokChar(this_char) => {
-                    assert!(this_char.is_ascii(), "Expected binary operator");
-                    this_char
-                }
-                _ => panic!("Expected binary operator"),
+            let op = match state.cur_tok {
+                Token::TokChar(this_char) if this_char.is_ascii() => this_char.to_string(),
+                Token::TokOp(ref op) => op.clone(),
+                _ => return Err(ParseError::new(state, "Expected binary operator")),
             };
             fn_name = String::from("binary");
-            fn_name.push_str(&this_char.to_string());
+            fn_name.push_str(&op);
             kind = 2;
             get_next_token(state);
 
             // Read the precedence if present.
-            if let Token::TokNumber(number) = state.cur_tok {
-                if number < 1. || number > 100. {
-                    panic!("Invalid precedence: must be 1..100");
+            if let Token::TokInt(number) = state.cur_tok {
+                if number < 1 || number > 100 {
+                    return Err(ParseError::new(state, "Invalid precedence: must be 1..100"));
                 }
                 binary_precedence = number as i32;
                 get_next_token(state);
@@ -220,22 +561,24 @@ fn parse_prototype(state: &mut State) -> AST {
         Token::TokUnary => {
             get_next_token(state);
             let this_char = match state.cur_tok {
-                Token::TokChar(this_char) => {
-                    assert!(this_char.is_ascii(), "Expected unary operator");
-                    this_char
-                }
-                _ => panic!("Expected binary operator"),
+                Token::TokChar(this_char) if this_char.is_ascii() => this_char,
+                _ => return Err(ParseError::new(state, "Expected unary operator")),
             };
             fn_name = String::from("unary");
             fn_name.push_str(&this_char.to_string());
             kind = 1;
             get_next_token(state);
         }
-        _ => panic!("Expected function name in prototype"),
+        _ => {
+            return Err(ParseError::new(
+                state,
+                "Expected function name in prototype",
+            ))
+        }
     };
 
     if !matches!(state.cur_tok, Token::TokChar('(')) {
-        panic!("Expected '(' in prototype");
+        return Err(ParseError::new(state, "Expected '(' in prototype"));
     }
 
     let mut arg_names: Vec<String> = Vec::new();
@@ -243,138 +586,321 @@ fn parse_prototype(state: &mut State) -> AST {
 
     while matches!(state.cur_tok, Token::TokIdentifier(_)) {
         if let Token::TokIdentifier(a) = state.cur_tok.clone() {
+            // Each name must pick out one argument, and not look like a recursive call.
+            if arg_names.contains(&a) {
+                let message = format!("Duplicate parameter `{a}` in prototype");
+                return Err(ParseError::new(state, &message));
+            }
+            if kind == 0 && a == fn_name {
+                let message = format!("Parameter `{a}` has the same name as its function");
+                return Err(ParseError::new(state, &message));
+            }
             arg_names.push(a)
         }
         get_next_token(state);
     }
 
+    // A trailing '...' takes any number of further arguments.
+    let is_var_arg = state.cur_tok == Token::TokEllipsis;
+    if is_var_arg {
+        if kind != 0 {
+            return Err(ParseError::new(
+                state,
+                "Operators cannot take variable arguments",
+            ));
+        }
+        get_next_token(state); // eat '...'.
+    }
+
     if !matches!(state.cur_tok, Token::TokChar(')')) {
-        panic!("Expected ')' in prototype");
+        return Err(ParseError::new(state, "Expected ')' in prototype"));
+    }
+
+    // Verify right number of names for operator.
+    if kind != 0 && arg_names.len() != kind {
+        return Err(ParseError::new(
+            state,
+            "Invalid number of operands for operator",
+        ));
     }
 
     // success.
     get_next_token(state); // eat ')'.
 
-    // Verify right number of names for operator.
-    if kind != 0 && arg_names.len() != kind {
-        panic!("Invalid number of operands for operator")
+    // Read the return type if present.
+    let mut return_type = Type::Double;
+    if state.cur_tok == Token::TokChar(':') {
+        get_next_token(state); // eat ':'.
+        if state.cur_tok == Token::TokChar('(') {
+            return_type = parse_tuple_type(state)?;
+        } else {
+            return_type = match &state.cur_tok {
+                Token::TokIdentifier(name) => match Type::from_name(name) {
+                    Some(return_type) => return_type,
+                    None => {
+                        let message =
+                            format!("Unknown return type `{name}`: expected double, int or bool");
+                        return Err(ParseError::new(state, &message));
+                    }
+                },
+                _ => return Err(ParseError::new(state, "Expected return type after ':'")),
+            };
+            get_next_token(state); // eat the type.
+        }
     }
 
-    return AST::Prototype(PrototypeAST::new(
+    return Ok(AST::Prototype(PrototypeAST::new(
         fn_name,
         arg_names,
         kind != 0,
         binary_precedence,
-    ));
+        is_var_arg,
+        return_type,
+        loc,
+    )));
+}
+
+// Read a tuple type such as `(double, double)`.  Tuples only hold doubles.
+fn parse_tuple_type(state: &mut ParserState) -> Result<Type, ParseError> {
+    let mut size = 0;
+    loop {
+        get_next_token(state); // eat '(' or ','.
+        if state.cur_tok != Token::TokIdentifier(String::from("double")) {
+            return Err(ParseError::new(state, "Expected `double` in tuple type"));
+        }
+        size += 1;
+        get_next_token(state); // eat 'double'.
+        match state.cur_tok {
+            Token::TokChar(',') => continue,
+            Token::TokChar(')') => break,
+            _ => return Err(ParseError::new(state, "Expected ')' or ',' in tuple type")),
+        }
+    }
+    get_next_token(state); // eat ')'.
+    if size < 2 {
+        return Err(ParseError::new(
+            state,
+            "A tuple type needs at least two elements",
+        ));
+    }
+    return Ok(Type::Tuple(size));
 }
 
 // definition ::= 'def' prototype expression
-fn parse_definition(state: &mut State) -> AST {
+fn parse_definition(state: &mut ParserState) -> ParseResult {
     get_next_token(state); // eat def.
-    let proto = parse_prototype(state);
-    let body = parse_expression(state);
+    let proto = parse_prototype(state)?;
+    // The body would have no way to reach the extra arguments.
+    if matches!(&proto, AST::Prototype(proto) if proto.is_var_arg()) {
+        return Err(ParseError::new(
+            state,
+            "Only extern functions can take variable arguments",
+        ));
+    }
+    let body = parse_expression(state)?;
 
-    return AST::Function(FunctionAST::new(proto, body));
+    return Ok(AST::Function(FunctionAST::new(proto, body)));
 }
 
 // toplevelexpr ::= expression
-fn parse_top_level_expr(state: &mut State) -> AST {
-    let proto = AST::Prototype(PrototypeAST::new(String::from("anon"), vec![], false, 0));
-    let body = parse_expression(state);
+fn parse_top_level_expr(state: &mut ParserState, name: &str) -> ParseResult {
+    let proto = AST::Prototype(PrototypeAST::new(
+        name.to_string(),
+        vec![],
+        false,
+        0,
+        false,
+        Type::Double,
+        state.cur_loc,
+    ));
+    let body = parse_expression(state)?;
 
-    return AST::Function(FunctionAST::new(proto, body));
+    return Ok(AST::Function(FunctionAST::new(proto, body)));
 }
 
-// external ::= 'extern' prototype
-fn parse_extern(state: &mut State) -> AST {
-    get_next_token(state);
-    let proto = parse_prototype(state);
-    return proto;
+// external ::= 'extern' callconv? prototype
+// callconv ::= '"C"' | '"fastcc"' | '"coldcc"'
+fn parse_extern(state: &mut ParserState) -> ParseResult {
+    get_next_token(state); // eat extern.
+
+    let mut call_conv = CallConv::C;
+    if let Token::TokString(name) = &state.cur_tok {
+        call_conv = match CallConv::from_name(name) {
+            Some(call_conv) => call_conv,
+            None => {
+                let known: Vec<&str> = CallConv::ALL.iter().map(CallConv::get_name).collect();
+                let message = format!(
+                    "Unknown calling convention `{name}`, expected one of: {}",
+                    known.join(", ")
+                );
+                return Err(ParseError::new(state, &message));
+            }
+        };
+        get_next_token(state); // eat the calling convention.
+    }
+
+    let mut proto = parse_prototype(state)?;
+    if let AST::Prototype(proto) = &mut proto {
+        proto.set_call_conv(call_conv);
+    }
+    return Ok(proto);
+}
+
+// global ::= 'global' identifier '=' expression
+fn parse_global(state: &mut ParserState) -> ParseResult {
+    get_next_token(state); // eat global.
+
+    let name = match state.cur_tok {
+        Token::TokIdentifier(ref name) => name.clone(),
+        _ => return Err(ParseError::new(state, "Expected identifier after global")),
+    };
+    get_next_token(state); // eat identifier.
+
+    if state.cur_tok != Token::TokChar('=') {
+        return Err(ParseError::new(state, "Expected '=' after global name"));
+    }
+    get_next_token(state); // eat '='.
+
+    let init = parse_expression(state)?;
+    return Ok(AST::Global(GlobalAST::new(name, init)));
 }
 
-// ifexpr ::= 'if' expression 'then' expression 'else' expression
-fn parse_if_expr(state: &mut State) -> AST {
-    get_next_token(state); // eat the `if`
+// ifexpr ::= 'if' expression 'then' expression elsepart?
+// elsepart
+//   ::= 'else' expression
+//   ::= 'elif' expression 'then' expression elsepart?
+fn parse_if_expr(state: &mut ParserState) -> ParseResult {
+    get_next_token(state); // eat the `if`, or the `elif`
 
     // condition.
-    let cond = parse_expression(state);
+    let cond = parse_expression(state)?;
 
     if !matches!(state.cur_tok, Token::TokThen) {
-        panic!("Expected 'then' in if expression");
+        return Err(ParseError::new(state, "Expected 'then' in if expression"));
     };
 
     get_next_token(state); // eat the `then`
 
-    let then = parse_expression(state);
+    let then = parse_expression(state)?;
 
+    // `elif` is short for `else if`: the rest of the chain is the else branch.
+    if matches!(state.cur_tok, Token::TokElif) {
+        let els = parse_nested(state, parse_if_expr)?;
+        return Ok(AST::If(IfExprAST::new(cond, then, els)));
+    }
+
+    // The else branch is optional and defaults to 0.0.
     if !matches!(state.cur_tok, Token::TokElse) {
-        panic!("Expected 'else' in if expression");
+        return Ok(AST::If(IfExprAST::new(
+            cond,
+            then,
+            AST::Number(NumberExprAST::new(0.0)),
+        )));
     };
 
     get_next_token(state); // eat the `else`
 
-    let els = parse_expression(state);
+    let els = parse_expression(state)?;
 
-    return AST::If(IfExprAST::new(cond, then, els));
+    return Ok(AST::If(IfExprAST::new(cond, then, els)));
 }
 
 // forexpr ::= 'for' identifier '=' expr ',' expr (',' expr)? 'in' expression
-fn parse_for_expr(state: &mut State) -> AST {
+fn parse_for_expr(state: &mut ParserState) -> ParseResult {
     get_next_token(state); // eat the `for`
 
     let id_name = match state.cur_tok.clone() {
         Token::TokIdentifier(a) => a,
-        _ => return AST::Null,
+        _ => return Err(ParseError::new(state, "Expected identifier after for")),
     };
     get_next_token(state); // eat the identifier
 
     if !matches!(state.cur_tok, Token::TokChar('=')) {
-        panic!("Expected '=' after for");
+        return Err(ParseError::new(state, "Expected '=' after for"));
     };
     get_next_token(state); // eat '='.
 
-    let start = parse_expression(state);
+    let start = parse_expression(state)?;
     if !matches!(state.cur_tok, Token::TokChar(',')) {
-        panic!("Expected ',' after for start value");
+        return Err(ParseError::new(state, "Expected ',' after for start value"));
     };
     get_next_token(state); // eat the ','
 
-    let end = parse_expression(state);
+    let end = parse_expression(state)?;
 
     // Step value is optional
     let mut step = AST::Null;
     if matches!(state.cur_tok, Token::TokChar(',')) {
         get_next_token(state); // eat the ','
-        step = parse_expression(state);
+        step = parse_expression(state)?;
     };
 
     if !matches!(state.cur_tok, Token::TokIn) {
-        panic!("Expected 'in' after for");
+        return Err(ParseError::new(state, "Expected 'in' after for"));
     };
     get_next_token(state); // eat the `in`
 
-    let body = parse_expression(state);
+    let body = parse_expression(state)?;
+
+    return Ok(AST::For(ForExprAST::new(id_name, start, end, step, body)));
+}
+
+// Parse `break` or `continue`.  Whether it is inside a loop is checked by codegen.
+fn parse_loop_jump(state: &mut ParserState) -> ParseResult {
+    let loc = state.cur_loc;
+    let node = match state.cur_tok {
+        Token::TokBreak => AST::Break(BreakExprAST::new(loc)),
+        _ => AST::Continue(ContinueExprAST::new(loc)),
+    };
+    get_next_token(state); // eat the keyword
+    return Ok(node);
+}
+
+// whileexpr ::= 'while' expression 'do' expression
+fn parse_while_expr(state: &mut ParserState) -> ParseResult {
+    get_next_token(state); // eat the `while`
+
+    let cond = parse_expression(state)?;
 
-    return AST::For(ForExprAST::new(id_name, start, end, step, body));
+    if !matches!(state.cur_tok, Token::TokDo) {
+        return Err(ParseError::new(
+            state,
+            "Expected 'do' after while condition",
+        ));
+    };
+    get_next_token(state); // eat the `do`
+
+    let body = parse_expression(state)?;
+
+    return Ok(AST::While(WhileExprAST::new(cond, body)));
 }
 
-// varexpr ::= 'var' identifier ('=' expression)?
-//                    (',' identifier ('=' expression)?)* 'in' expression
-fn parse_var_expr(state: &mut State) -> AST {
+// varexpr ::= 'var' varlist 'in' expression
+fn parse_var_expr(state: &mut ParserState) -> ParseResult {
     get_next_token(state); // eat the `var`
 
-    let mut names: HashMap<String, AST> = HashMap::new();
+    let names = parse_var_list(state)?;
 
-    // At least one variable name is required.
-    assert!(
-        matches!(state.cur_tok, Token::TokIdentifier(_)),
-        "expected identifier after var"
-    );
+    if !matches!(state.cur_tok, Token::TokIn) {
+        return Err(ParseError::new(state, "expected 'in' keyword after 'var'"));
+    };
+
+    get_next_token(state); // eat the 'in'.
+
+    let body = parse_expression(state)?;
+
+    return Ok(AST::Var(VarExprAST::new(names, body)));
+}
+
+// varlist ::= identifier ('=' expression)? (',' identifier ('=' expression)?)*
+fn parse_var_list(state: &mut ParserState) -> Result<Vec<(String, AST)>, ParseError> {
+    let mut names = Vec::new();
 
+    // At least one variable name is required.
     loop {
         let id_name = match state.cur_tok.clone() {
             Token::TokIdentifier(a) => a,
-            _ => return AST::Null,
+            _ => return Err(ParseError::new(state, "expected identifier after var")),
         };
         get_next_token(state); // eat the `identifier`
 
@@ -382,10 +908,10 @@ fn parse_var_expr(state: &mut State) -> AST {
         let mut init = AST::Null;
         if matches!(state.cur_tok, Token::TokChar('=')) {
             get_next_token(state); // eat the '='
-            init = parse_expression(state);
+            init = parse_expression(state)?;
         };
 
-        names.insert(id_name.to_string(), init);
+        names.push((id_name.to_string(), init));
 
         // End of var list, exit loop.
         if !matches!(state.cur_tok, Token::TokChar(',')) {
@@ -393,102 +919,442 @@ fn parse_var_expr(state: &mut State) -> AST {
         }
 
         get_next_token(state); // eat the ','.
-
-        assert!(
-            matches!(state.cur_tok, Token::TokIdentifier(_)),
-            "expected identifier list after var"
-        );
     }
 
-    if !matches!(state.cur_tok, Token::TokIn) {
-        panic!("expected 'in' keyword after 'var'");
-    };
+    return Ok(names);
+}
 
-    get_next_token(state); // eat the 'in'.
+// blockexpr ::= '{' (blockitem (';' blockitem)* ';'?)? '}'
+// blockitem
+//   ::= expression
+//   ::= 'var' varlist
+//
+// The block's value is that of its last item.  A var without 'in' scopes over the
+// rest of the block.
+fn parse_block_expr(state: &mut ParserState) -> ParseResult {
+    get_next_token(state); // eat the '{'.
+    return parse_nested(state, parse_block_items);
+}
 
-    let body = parse_expression(state);
+// Parse block items up to and including the closing '}'.
+fn parse_block_items(state: &mut ParserState) -> ParseResult {
+    let mut exprs = Vec::new();
+    loop {
+        if matches!(state.cur_tok, Token::TokChar('}')) {
+            get_next_token(state); // eat the '}'.
+            break;
+        }
 
-    return AST::Var(VarExprAST::new(names, body));
+        if matches!(state.cur_tok, Token::TokVar) {
+            get_next_token(state); // eat the `var`
+            let names = parse_var_list(state)?;
+            if !matches!(state.cur_tok, Token::TokIn) {
+                // The rest of the block, '}' included, is the var's body.
+                if matches!(state.cur_tok, Token::TokChar(';')) {
+                    get_next_token(state); // eat the ';'.
+                }
+                let body = parse_nested(state, parse_block_items)?;
+                exprs.push(AST::Var(VarExprAST::new(names, body)));
+                break;
+            }
+            get_next_token(state); // eat the 'in'.
+            let body = parse_expression(state)?;
+            exprs.push(AST::Var(VarExprAST::new(names, body)));
+        } else {
+            exprs.push(parse_expression(state)?);
+        }
+
+        match state.cur_tok {
+            Token::TokChar(';') => get_next_token(state), // eat the ';'.
+            Token::TokChar('}') => (),
+            _ => return Err(ParseError::new(state, "Expected ';' or '}' in block")),
+        }
+    }
+
+    return Ok(AST::Block(BlockExprAST::new(exprs)));
 }
 
-fn handle_definition(state: &mut State) {
-    // TODO: Can't redefine files yet.
-    let node = parse_definition(state);
+// Print the IR of everything defined so far.
+fn handle_dump(state: &mut State) {
+    print!("{}", state.module.print_to_string().to_string());
+}
 
-    if matches!(node, AST::Null) {
-        // Skip the token for error recovery
+// Parse an item for a handler.  On a syntax error, skip the token for error recovery.
+fn parse_item(
+    state: &mut ParserState,
+    parse: impl FnOnce(&mut ParserState) -> ParseResult,
+) -> ParseResult {
+    let result = parse(state);
+    if result.is_err() {
         get_next_token(state);
-    } else {
-        codegen(state, &node);
     }
+    return result;
 }
 
-fn handle_extern(state: &mut State) {
-    let node = parse_extern(state);
+fn handle_definition(state: &mut State) -> Result<(), Error> {
+    let node = parse_item(&mut state.parser, parse_definition)?;
+    codegen(state, &node)?;
 
-    if matches!(node, AST::Null) {
-        // Skip the token for error recovery
-        get_next_token(state);
+    // If this is an operator, install it.
+    if let AST::Function(function) = &node {
+        function.get_proto().install_operator(&mut state.parser);
+    }
+    return Ok(());
+}
+
+fn handle_extern(state: &mut State) -> Result<(), Error> {
+    let node = parse_item(&mut state.parser, parse_extern)?;
+    codegen(state, &node)?;
+
+    let proto = match node {
+        AST::Prototype(val) => val,
+        _ => {
+            return Err(Error::Codegen(CodegenError::new(String::from(
+                "PrototypeAST code generation failure. Expected a prototype after `extern`.",
+            ))))
+        }
+    };
+    proto.install_operator(&mut state.parser);
+    state
+        .function_protos
+        .insert(proto.get_name().to_string(), proto);
+    return Ok(());
+}
+
+fn handle_global(state: &mut State) -> Result<(), Error> {
+    let node = parse_item(&mut state.parser, parse_global)?;
+    codegen(state, &node)?;
+    return Ok(());
+}
+
+fn handle_top_level_expression(state: &mut State) -> Result<(), Error> {
+    if state.aot_main {
+        return collect_top_level_expression(state);
+    }
+
+    // Decorate results only for a person at a terminal; otherwise print just the value,
+    // one per line.
+    let value = format_value(eval_top_level_expression(state)?, state.result_precision);
+    if state.parser.source.is_interactive() {
+        println!("Out[#]: {value}\n");
     } else {
-        codegen(state, &node);
+        println!("{value}");
+    }
+    return Ok(());
+}
+
+// Compile the expression starting at `cur_tok` and run it with the long-lived JIT, after
+// adding to it the definitions made since the last one.
+fn eval_top_level_expression(state: &mut State) -> Result<f64, Error> {
+    // Every evaluation gets its own function name, so a stale or half-removed one can
+    // never clash with the next.
+    let name = format!("__anon_expr.{}", state.anon_count);
+    state.anon_count += 1;
+    let node = parse_item(&mut state.parser, |state| {
+        parse_top_level_expr(state, &name)
+    })?;
+
+    // The expression is compiled into a module of its own, which the long-lived JIT
+    // can drop again without touching the definitions.  Calls into the definitions are
+    // declared in it and resolved by the JIT.
+    // Its debug info, if any, would belong to the wrong module, so it has none.
+    let anon_module = state.context.create_module(&name);
+    let anon_fpm = create_fpm(&anon_module, state.opt_level, state.passes.as_deref());
+    let debug_info = state.debug_info.take();
+    let module = std::mem::replace(&mut state.module, anon_module);
+    let fpm = std::mem::replace(&mut state.fpm, anon_fpm);
+    let result = codegen(state, &node);
+    let anon_module = std::mem::replace(&mut state.module, module);
+    state.fpm = fpm;
+    state.debug_info = debug_info;
+    result?;
+    // Nothing can call the expression again, so its prototype would only pile up.
+    state.function_protos.remove(&name);
+
+    state.update_jit()?;
+    let ee = state.jit.as_ref().unwrap();
+    ee.add_module(&anon_module)
+        .map_err(|()| Error::Jit(format!("Could not add `{name}` to the JIT")))?;
+    add_runtime_mappings(ee, &anon_module);
+
+    let result = call_top_level(ee, &name);
+
+    // Take the expression's module back from the engine, freeing it.
+    ee.remove_module(&anon_module)
+        .map_err(|err| Error::Jit(format!("Could not take `{name}` back from the JIT: {err}")))?;
+    return result;
+}
+
+// Ahead-of-time mode: keep the expression as a function for the synthesized `main` to
+// call, instead of running it now.
+fn collect_top_level_expression(state: &mut State) -> Result<(), Error> {
+    // The '.' keeps these names from clashing with any Kaleidoscope identifier.
+    let name = format!("__toplevel.{}", state.toplevel_exprs.len());
+    let node = parse_item(&mut state.parser, |state| {
+        parse_top_level_expr(state, &name)
+    })?;
+
+    codegen(state, &node)?;
+    state.toplevel_exprs.push(name);
+    return Ok(());
+}
+
+const HELP: &str = "Enter definitions, externs and expressions, optionally separated by ';'.
+
+commands, each on a line of its own:
+  :help   show this message
+  :clear  forget all definitions
+  :quit   leave the REPL (so does `exit`)
+  #dump   print the IR of the definitions so far (on a line of its own)";
+
+// command ::= ':' identifier, on a line of its own
+//
+// Run a REPL command.  Returns false if the REPL should stop.
+fn handle_command(state: &mut State, command: &str) -> bool {
+    match command {
+        "quit" => return false,
+        "help" => println!("{HELP}\n"),
+        "clear" => state.clear_definitions(),
+        "" => eprintln!("Error: Expected a command name after ':'"),
+        _ => eprintln!("Error: Unknown command `:{command}`, try :help"),
+    }
+    get_next_token(&mut state.parser);
+    return true;
+}
+
+pub fn main_loop(state: &mut State) {
+    state.parser.commands = true;
+    // Only a person at a terminal needs the banner and prompts.
+    if state.parser.source.is_interactive() {
+        println!("Kaleidoscope. Type :help for help, :quit to leave.\n");
+        // Later prompts are printed by the lexer whenever it needs a new line.
+        print!("In [#]: ");
+        std::io::stdout().flush().unwrap();
+    }
+    top_level_loop(state, true);
+}
+
+// Drive the parser over a whole, non-interactive input such as a source file.  An item
+// that fails to compile is skipped, and its error is returned with all the others.
+pub fn file_loop(state: &mut State) -> Vec<Diagnostic> {
+    return top_level_loop(state, false);
+}
 
+// Run the input as the REPL does, one item at a time with one JIT kept across the
+// expressions, but return their values instead of printing them.  Stops at the first
+// error.
+pub fn eval_loop(state: &mut State) -> Result<Vec<f64>, Error> {
+    let mut values = Vec::new();
+
+    // Prime the first token
+    get_next_token(&mut state.parser);
+    loop {
+        match state.parser.cur_tok {
+            Token::TokEOF => break,
+            Token::TokChar(';') | Token::TokDump => get_next_token(&mut state.parser),
+            Token::TokDef => handle_definition(state)?,
+            Token::TokExtern => handle_extern(state)?,
+            Token::TokGlobal => handle_global(state)?,
+            _ => values.push(eval_top_level_expression(state)?),
+        }
+    }
+
+    return Ok(values);
+}
+
+// Compile the whole input, stopping at the first error.  Everything is parsed before
+// anything is compiled, and every prototype is registered first, so any item may call a
+// function defined further down: top-level expressions included, and mutually recursive
+// definitions in either order.
+pub fn compile_loop(state: &mut State) -> Result<(), Error> {
+    let first_expr = state.toplevel_exprs.len();
+    let items = parse_items(&mut state.parser, first_expr)?;
+
+    // First pass: make every function known by name.
+    for (node, _) in &items {
         let proto = match node {
-            AST::Prototype(val) => val,
-            _ => panic!(
-                "FunctionAST code generation failure, expected a ProtoTypeAST for proto field."
-            ),
+            AST::Function(function) => function.get_proto(),
+            AST::Prototype(proto) => proto,
+            _ => continue,
         };
-        state
-            .function_protos
-            .insert(proto.get_name().to_string(), proto);
+        // The first declaration wins, over a builtin of the same name too; a later
+        // redefinition replaces it when compiled.
+        match state.function_protos.get(proto.get_name()) {
+            Some(existing) if !existing.is_builtin() => (),
+            _ => {
+                state
+                    .function_protos
+                    .insert(proto.get_name().to_string(), proto.clone());
+            }
+        }
     }
+
+    // Second pass: generate code in input order.
+    for (node, toplevel_name) in &items {
+        codegen(state, node)?;
+        if let Some(name) = toplevel_name {
+            state.toplevel_exprs.push(name.clone());
+        }
+    }
+    return Ok(());
 }
 
-fn handle_top_level_expression(state: &mut State) {
-    let temp_module = state.module.clone();
-    let node = parse_top_level_expr(state);
+// Parse the whole input.  Top-level expressions come with the name of the anonymous
+// function wrapping them, numbered from `first_expr`.
+pub fn parse_items(
+    state: &mut ParserState,
+    first_expr: usize,
+) -> Result<Vec<(AST, Option<String>)>, Error> {
+    let mut items = Vec::new();
+    let mut expr_count = first_expr;
 
-    if matches!(node, AST::Null) {
-        // Skip the token for error recovery
-        get_next_token(state);
-    } else {
-        codegen(state, &node);
-        unsafe {
-            let ee = state
-                .module
-                .create_jit_execution_engine(OptimizationLevel::None)
-                .unwrap();
-            let test_fn = ee
-                .get_function::<unsafe extern "C" fn() -> f64>("anon")
-                .unwrap();
-            let return_value = test_fn.call();
-            println!("Out[#]: {return_value}\n");
+    // Prime the first token
+    get_next_token(state);
+    loop {
+        let item = match state.cur_tok {
+            Token::TokEOF => break,
+            // There is no code to dump until everything has been parsed.
+            Token::TokChar(';') | Token::TokDump => {
+                get_next_token(state);
+                continue;
+            }
+            Token::TokDef => (parse_item(state, parse_definition)?, None),
+            Token::TokExtern => (parse_item(state, parse_extern)?, None),
+            Token::TokGlobal => (parse_item(state, parse_global)?, None),
+            _ => {
+                // The '.' keeps these names from clashing with any Kaleidoscope identifier.
+                let name = format!("__toplevel.{expr_count}");
+                expr_count += 1;
+                let node = parse_item(state, |state| parse_top_level_expr(state, &name))?;
+                (node, Some(name))
+            }
         };
+
+        // Operators need their precedence to parse what follows.
+        match &item {
+            (AST::Function(function), None) => function.get_proto().install_operator(state),
+            (AST::Prototype(proto), None) => proto.install_operator(state),
+            _ => (),
+        }
+        items.push(item);
     }
-    state.module = temp_module;
+    return Ok(items);
 }
 
-pub fn main_loop(state: &mut State) {
-    print!("In [#]: ");
-    std::io::stdout().flush().unwrap();
+// Parse the whole input and check it without generating any code, returning every
+// error found.
+pub fn check_loop(state: &mut ParserState) -> Vec<Diagnostic> {
+    let mut checker = Checker::new();
+    let mut diagnostics = Vec::new();
+    let mut nodes = Vec::new();
+
     // Prime the first token
     get_next_token(state);
-    match state.cur_tok {
-        Token::TokChar(';') => get_next_token(state),
-        Token::TokDef => handle_definition(state),
-        Token::TokExtern => handle_extern(state),
-        _ => handle_top_level_expression(state),
-    };
     loop {
-        print!("In [#]: ");
-        std::io::stdout().flush().unwrap();
-        get_next_token(state);
-        match state.cur_tok {
+        let start = state.cur_loc;
+        let node = match state.cur_tok {
             Token::TokEOF => break,
-            Token::TokChar(';') => get_next_token(state),
-            Token::TokDef => handle_definition(state),
-            Token::TokExtern => handle_extern(state),
-            _ => handle_top_level_expression(state),
+            Token::TokChar(';') | Token::TokDump => {
+                get_next_token(state);
+                continue;
+            }
+            Token::TokDef => parse_item(state, parse_definition),
+            Token::TokExtern => parse_item(state, parse_extern),
+            Token::TokGlobal => parse_item(state, parse_global),
+            _ => parse_item(state, |state| parse_top_level_expr(state, "__check")),
+        };
+
+        // Operators still need their precedence to parse what follows.
+        match &node {
+            Ok(AST::Function(function)) => function.get_proto().install_operator(state),
+            Ok(AST::Prototype(proto)) => proto.install_operator(state),
+            _ => (),
+        }
+        let span = Span {
+            start,
+            end: state.cur_loc,
+        };
+        nodes.push((node, span));
+    }
+
+    // As when compiling, every function is known before anything is checked.
+    for (node, _) in &nodes {
+        match node {
+            Ok(AST::Function(function)) => checker.visit_prototype(function.get_proto()),
+            Ok(AST::Prototype(proto)) => checker.visit_prototype(proto),
+            _ => (),
+        }
+    }
+
+    for (node, span) in nodes {
+        let errors: Vec<Error> = match node {
+            Ok(node) => {
+                checker.visit(&node);
+                checker.errors.drain(..).map(Error::from).collect()
+            }
+            Err(err) => vec![err.into()],
+        };
+        diagnostics.extend(
+            errors
+                .into_iter()
+                .map(|err| Diagnostic::from_error(err, span)),
+        );
+    }
+    return diagnostics;
+}
+
+// Handle top-level items until the input ends.  The REPL reports each error as it
+// happens; otherwise they are collected and returned.
+fn top_level_loop(state: &mut State, interactive: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    // Prime the first token
+    get_next_token(&mut state.parser);
+    loop {
+        let start = state.parser.cur_loc;
+        match handle_top_level_item(state) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) if interactive => eprintln!("Error: {err}"),
+            Err(err) => {
+                let span = Span {
+                    start,
+                    end: state.parser.cur_loc,
+                };
+                diagnostics.push(Diagnostic::from_error(err, span));
+            }
+        }
+        // Warnings are reported along with the errors, in the same way.
+        for warning in state.warnings.drain(..) {
+            if interactive {
+                eprintln!("{warning}");
+            } else {
+                diagnostics.push(warning);
+            }
+        }
+    }
+
+    return diagnostics;
+}
+
+// top ::= definition | external | expression | ';' | '#dump' | command
+//
+// Handle the item starting at `cur_tok`, returning false at the end of the input.  Each
+// handler leaves `cur_tok` on the token after its item, so several items may share a line.
+// Commands only come from the lexer in the REPL.
+fn handle_top_level_item(state: &mut State) -> Result<bool, Error> {
+    match state.parser.cur_tok.clone() {
+        Token::TokEOF => return Ok(false),
+        Token::TokCommand(command) => return Ok(handle_command(state, &command)),
+        Token::TokChar(';') => get_next_token(&mut state.parser),
+        Token::TokDump => {
+            handle_dump(state);
+            get_next_token(&mut state.parser);
         }
+        Token::TokDef => handle_definition(state)?,
+        Token::TokExtern => handle_extern(state)?,
+        Token::TokGlobal => handle_global(state)?,
+        _ => handle_top_level_expression(state)?,
     }
+    return Ok(true);
 }
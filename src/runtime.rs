@@ -0,0 +1,75 @@
+use std::cell::Cell;
+
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::module::Module;
+
+use crate::compiler::Compiler;
+use crate::Error;
+
+pub use kaleidoscope_runtime::{
+    assert, format_value, print, printd, putchard, readchar, readnumber,
+};
+
+thread_local! {
+    // Set when the running expression divided an integer by zero.
+    static DIVIDED_BY_ZERO: Cell<bool> = Cell::new(false);
+}
+
+// The JIT's `kaleidoscope.divzero`.  Instead of stopping the process, as the runtime
+// library's does, it leaves the error for `call_top_level` to report.
+pub extern "C" fn jit_divzero() {
+    DIVIDED_BY_ZERO.with(|flag| flag.set(true));
+}
+
+// The error the expression that just ran left, if any, clearing it for the next one.
+pub fn take_runtime_error() -> Option<Error> {
+    if DIVIDED_BY_ZERO.with(|flag| flag.replace(false)) {
+        return Some(Error::Runtime(String::from("Division by zero")));
+    }
+    return None;
+}
+
+// Point any `extern` declarations of the runtime functions at their host
+// implementations, so the JIT doesn't have to find them by symbol lookup.
+pub fn add_runtime_mappings<'ctx>(ee: &ExecutionEngine<'ctx>, module: &Module<'ctx>) {
+    let builtins: [(&str, usize); 7] = [
+        ("putchard", putchard as *const () as usize),
+        ("printd", printd as *const () as usize),
+        ("print", print as *const () as usize),
+        ("assert", assert as *const () as usize),
+        ("readnumber", readnumber as *const () as usize),
+        ("readchar", readchar as *const () as usize),
+        ("kaleidoscope.divzero", jit_divzero as *const () as usize),
+    ];
+    for (name, address) in builtins {
+        if let Some(func_value) = module.get_function(name) {
+            ee.add_global_mapping(&func_value, address);
+        }
+    }
+}
+
+// Create a JIT for the module with the runtime functions mapped in.  Further modules
+// added to it need their own `add_runtime_mappings`.
+pub fn create_jit<'ctx>(state: &Compiler<'ctx>) -> Result<ExecutionEngine<'ctx>, String> {
+    let ee = state
+        .module
+        .create_jit_execution_engine(state.opt_level)
+        .map_err(|err| err.to_string())?;
+    add_runtime_mappings(&ee, &state.module);
+    return Ok(ee);
+}
+
+// Run the compiled top-level expression `name`, which takes no arguments.  An error it
+// ran into, such as dividing by zero, comes back as Error::Runtime.
+pub fn call_top_level(ee: &ExecutionEngine, name: &str) -> Result<f64, Error> {
+    let value = unsafe {
+        let func = ee
+            .get_function::<unsafe extern "C" fn() -> f64>(name)
+            .map_err(|err| Error::Jit(format!("Could not find `{name}` in the JIT: {err}")))?;
+        func.call()
+    };
+    if let Some(err) = take_runtime_error() {
+        return Err(err);
+    }
+    return Ok(value);
+}
@@ -0,0 +1,224 @@
+use inkwell::context::Context;
+use inkwell::values::FunctionValue;
+use kaleidoscope::ast::{
+    build_main, codegen, fold, walk, BinaryExprAST, CallExprAST, FunctionAST, NumberExprAST,
+    PrototypeAST, Type, VariableExprAST, Visitor, AST,
+};
+use kaleidoscope::compiler::Compiler;
+use kaleidoscope::lexer::{Source, SourceLocation};
+use kaleidoscope::parser::compile_loop;
+use kaleidoscope::runtime::{call_top_level, create_jit};
+use kaleidoscope::{compile_to_ir, parse_str, Severity, State};
+
+const LOC: SourceLocation = SourceLocation { line: 1, col: 1 };
+
+fn prototype(name: &str, args: &[&str]) -> AST {
+    let args = args.iter().map(|arg| arg.to_string()).collect();
+    return AST::Prototype(PrototypeAST::new(
+        name.to_string(),
+        args,
+        false,
+        0,
+        false,
+        Type::Double,
+        LOC,
+    ));
+}
+
+fn variable(name: &str) -> AST {
+    return AST::Variable(VariableExprAST::new(name.to_string(), LOC));
+}
+
+fn binary(op: &str, lhs: AST, rhs: AST) -> AST {
+    return AST::Binary(BinaryExprAST::new(op.to_string(), lhs, rhs));
+}
+
+#[test]
+fn only_the_compilers_prototypes_are_builtin() {
+    let context = Context::create();
+    let state = State::new(&context);
+    assert!(state.function_protos["sqrt"].is_builtin());
+
+    // Even at line 0, a prototype built by an embedder is not a builtin.
+    let proto = PrototypeAST::new(
+        String::from("f"),
+        Vec::new(),
+        false,
+        0,
+        false,
+        Type::Double,
+        SourceLocation { line: 0, col: 0 },
+    );
+    assert!(!proto.is_builtin());
+}
+
+// Add a function `name` to the state's module that LLVM can't verify: its block has no
+// terminator.
+fn add_broken_function<'ctx>(state: &State<'ctx>, name: &str) -> FunctionValue<'ctx> {
+    let fn_type = state.context.f64_type().fn_type(&[], false);
+    let func = state.module.add_function(name, fn_type, None);
+    state.context.append_basic_block(func, "entry");
+    return func;
+}
+
+fn warned_about(state: &State, name: &str) -> bool {
+    let name = format!("`{name}`");
+    return state
+        .warnings
+        .iter()
+        .any(|warning| warning.message.contains(&name));
+}
+
+#[test]
+fn invalid_function_is_removed() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    add_broken_function(&state, "broken");
+
+    // The next definition is fine, and takes the broken function out of the module.
+    assert_eq!(state.jit_eval("def f(x) x + 1; f(2)").unwrap(), 3.0);
+    assert!(state.module.get_function("broken").is_none());
+    assert!(warned_about(&state, "broken"));
+    assert!(!warned_about(&state, "f"));
+    assert!(state.module.verify().is_ok());
+}
+
+#[test]
+fn invalid_function_that_is_called_is_kept() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    let broken = add_broken_function(&state, "broken");
+    let caller = state.module.add_function("caller", broken.get_type(), None);
+    let builder = context.create_builder();
+    builder.position_at_end(context.append_basic_block(caller, "entry"));
+    let call = builder.build_call(broken, &[], "calltmp");
+    builder.build_return(Some(&call.try_as_basic_value().left().unwrap()));
+
+    state.parser.source = Source::from_text("def f(x) x + 1");
+    compile_loop(&mut state).unwrap();
+    assert!(state.module.get_function("broken").is_some());
+    assert!(warned_about(&state, "broken"));
+    assert!(!warned_about(&state, "caller"));
+    assert!(!warned_about(&state, "f"));
+}
+
+#[test]
+fn compile_an_ast_without_the_parser() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context);
+
+    // def square(x) x * x
+    let body = binary("*", variable("x"), variable("x"));
+    let square = AST::Function(FunctionAST::new(prototype("square", &["x"]), body));
+    codegen(&mut compiler, &square).unwrap();
+
+    // def main() square(3) + 1
+    let three = Box::new(AST::Number(NumberExprAST::new(3.0)));
+    let call = AST::Call(CallExprAST::new(String::from("square"), vec![three], LOC));
+    let body = binary("+", call, AST::Number(NumberExprAST::new(1.0)));
+    let main = AST::Function(FunctionAST::new(prototype("main", &[]), body));
+    codegen(&mut compiler, &main).unwrap();
+
+    assert!(compiler.module.verify().is_ok());
+    let ee = create_jit(&compiler).unwrap();
+    assert_eq!(call_top_level(&ee, "main").unwrap(), 10.0);
+}
+
+#[test]
+fn compiler_reports_errors_in_an_ast() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context);
+    let body = binary("+", variable("x"), variable("y"));
+    let f = AST::Function(FunctionAST::new(prototype("f", &["x"]), body));
+    assert!(codegen(&mut compiler, &f).is_err());
+    assert!(compiler.module.get_function("f").is_none());
+}
+
+#[test]
+fn trees_the_parser_never_builds_are_errors() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context);
+
+    // `1 = 2`, which the parser rejects.
+    let one = AST::Number(NumberExprAST::new(1.0));
+    let assign = binary("=", one, AST::Number(NumberExprAST::new(2.0)));
+    match codegen(&mut compiler, &assign) {
+        Err(err) => assert!(err.message.contains("must be a variable"), "{err}"),
+        Ok(_) => panic!("assigned to a number"),
+    }
+    assert!(codegen(&mut compiler, &AST::Null).is_err());
+}
+
+#[test]
+fn extern_called_twice_is_declared_once() {
+    let ir = compile_to_ir("extern cos(x); def f(x) cos(x); def g(x) cos(x) + 1").unwrap();
+    assert_eq!(ir.matches("declare double @cos(").count(), 1);
+    // A builtin is declared when first called, and only then.
+    let ir = compile_to_ir("def f() readnumber(); def g() readnumber() + 1").unwrap();
+    assert_eq!(ir.matches("declare double @readnumber(").count(), 1);
+}
+
+#[test]
+fn main_calls_the_top_level_expressions_in_order() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.aot_main = true;
+    state.parser.source = Source::from_text("def f() 1; f(); f() + 1");
+    compile_loop(&mut state).unwrap();
+    build_main(&mut state).unwrap();
+
+    let ir = state.module.print_to_string().to_string();
+    assert!(ir.contains("define i32 @main()"), "{ir}");
+    let first = ir.find("call double @__toplevel.0()").unwrap();
+    let second = ir.find("call double @__toplevel.1()").unwrap();
+    assert!(first < second, "{ir}");
+
+    // There is only ever one `main`.
+    assert!(build_main(&mut state).is_err());
+}
+
+#[test]
+fn visitor_and_fold_count_calls() {
+    struct CallCounter {
+        calls: usize,
+    }
+
+    impl Visitor for CallCounter {
+        fn visit(&mut self, node: &AST) {
+            if matches!(node, AST::Call(_)) {
+                self.calls += 1;
+            }
+            walk(self, node);
+        }
+    }
+
+    // Calls nested in arguments, branches and loop bodies all count.
+    let items =
+        parse_str("def f(x) g(h(x), 1) + (if x then k() else 2); for i = 0, i < f(1) in g(i)")
+            .unwrap();
+    let mut counter = CallCounter { calls: 0 };
+    for item in &items {
+        counter.visit(item);
+    }
+    assert_eq!(counter.calls, 5);
+
+    let folded = items.iter().fold(0, |count, item| {
+        fold(item, count, |count, node| {
+            count + matches!(node, AST::Call(_)) as usize
+        })
+    });
+    assert_eq!(folded, 5);
+}
+
+#[test]
+fn unused_parameter_is_a_warning() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert_eq!(state.jit_eval("def f(x y _z) x + 1; f(1, 2, 3)").unwrap(), 2.0);
+
+    assert_eq!(state.warnings.len(), 1, "{:?}", state.warnings);
+    let warning = &state.warnings[0];
+    assert_eq!(warning.severity, Severity::Warning);
+    assert!(warning.message.contains("Unused parameter `y` of `f`"), "{warning}");
+    assert_eq!(warning.span.start.line, 1);
+}
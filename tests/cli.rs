@@ -0,0 +1,229 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// Write `contents` to a file of the system's temporary directory, named for this test
+// run so tests running at the same time don't share it.
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("kaleidoscope-{}-{name}", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    return path;
+}
+
+// Run the compiler with `args`.
+fn kaleidoscope(args: &[&str]) -> Output {
+    return Command::new(env!("CARGO_BIN_EXE_kaleidoscope"))
+        .args(args)
+        .output()
+        .unwrap();
+}
+
+// Run the compiler with `args`, piping `input` into it.
+fn kaleidoscope_with_input(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kaleidoscope"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Dropping the pipe once written closes it, so the program sees the input end.
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    return child.wait_with_output().unwrap();
+}
+
+#[test]
+fn compiling_a_file_prints_its_ir() {
+    let path = temp_file("square.kal", "def square(x) x * x\n");
+    let output = kaleidoscope(&[path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("define double @square(double %x)"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn check_reports_errors_without_generating_code() {
+    let path = temp_file("arity.kal", "def f(x) x + 1\nf(1, 2)\n");
+    let output = kaleidoscope(&["--check", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("expected 1, got 2"), "{stderr}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("define"), "{stdout}");
+}
+
+#[test]
+fn tokens_prints_one_token_per_line() {
+    let path = temp_file("tokens.kal", "def f(x) x * 2.5 # done\n");
+    let output = kaleidoscope(&["--tokens", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let tokens: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        tokens,
+        vec![
+            "TokDef",
+            "TokIdentifier(\"f\")",
+            "TokChar('(')",
+            "TokIdentifier(\"x\")",
+            "TokChar(')')",
+            "TokIdentifier(\"x\")",
+            "TokChar('*')",
+            "TokNumber(2.5)",
+            "TokEOF",
+        ]
+    );
+}
+
+#[test]
+fn results_print_nan_and_infinity_as_c_does() {
+    let printed = |args: &[&str]| {
+        let output = kaleidoscope(args);
+        assert!(output.status.success());
+        return String::from_utf8_lossy(&output.stdout).into_owned();
+    };
+    assert_eq!(printed(&["-e", "1.0 / 0.0"]), "inf\n");
+    assert_eq!(printed(&["-e", "-1.0 / 0.0"]), "-inf\n");
+    assert_eq!(printed(&["-e", "0.0 / 0.0"]), "nan\n");
+    assert_eq!(printed(&["--precision", "3", "-e", "0.0 / 0.0"]), "nan\n");
+    assert_eq!(printed(&["--precision", "3", "-e", "1 / 3.0"]), "0.333\n");
+}
+
+#[test]
+fn precision_sets_the_significant_digits_shown() {
+    let printed = |args: &[&str]| {
+        let output = kaleidoscope(args);
+        assert!(output.status.success());
+        return String::from_utf8_lossy(&output.stdout).into_owned();
+    };
+    // By default, every digit needed to read the value back.
+    assert_eq!(printed(&["-e", "1.0 / 3.0"]), "0.3333333333333333\n");
+    assert_eq!(printed(&["--precision", "1", "-e", "1.0 / 3.0"]), "0.3\n");
+    assert_eq!(
+        printed(&["--precision", "5", "-e", "2.0 / 3.0"]),
+        "0.66667\n"
+    );
+    assert_eq!(printed(&["--precision", "2", "-e", "200.0 / 3.0"]), "67\n");
+}
+
+#[test]
+fn run_prints_each_result_in_order() {
+    let path = temp_file("run.kal", "def f(x) x * 2\nf(21)\nf(1.5)\n");
+    let output = kaleidoscope(&["--run", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n3\n");
+}
+
+#[test]
+fn failed_assert_stops_the_program() {
+    let output = kaleidoscope(&["-e", "assert(1 < 2) : 5"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+
+    let output = kaleidoscope(&["-e", "assert(2 < 1) : 5"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Assertion failed: got 0"), "{stderr}");
+}
+
+#[test]
+fn piped_input_gets_no_prompts() {
+    // `--run` leaves out the IR printed at the end.
+    let output = kaleidoscope_with_input(&["--run"], "def f(x) x + 1\nf(2)\nf(3)\n");
+
+    assert!(output.status.success());
+    // Only the results, without the banner or the `In [#]:` and `Out[#]:` decorations.
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n4\n");
+}
+
+#[test]
+fn eval_flag_prints_the_value_or_the_error() {
+    let output = kaleidoscope(&["-e", "3*4+1"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "13\n");
+
+    let output = kaleidoscope(&["--stdin-eval", "def f(x) x * x; f(5) + 1"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "26\n");
+
+    let output = kaleidoscope(&["-e", "nowhere + 1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unknown variable `nowhere`"), "{stderr}");
+}
+
+#[test]
+fn dump_ast_prints_each_item_parsed() {
+    let path = temp_file("dump.kal", "def f(x) x+1*2\nextern sin(a)\nf(2) : sin(0)\n");
+    let output = kaleidoscope(&["--dump-ast", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "def f(x) (x + (1 * 2));\nextern sin(a);\n(f(2) : sin(0));\n"
+    );
+
+    // With --check, the input is checked after it is printed.
+    let path = temp_file("dump-check.kal", "def f(x) x\nf(1, 2)\n");
+    let output = kaleidoscope(&["--dump-ast", "--check", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "def f(x) x;\nf(1, 2);\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("expected 1, got 2"), "{stderr}");
+}
+
+#[test]
+fn programs_read_their_input() {
+    let program =
+        "def echo() print(readnumber()); echo() : echo() : readchar() * 1000 + readchar()";
+    let output = kaleidoscope_with_input(&["-e", program], "42.5\nabc\nx");
+    assert!(output.status.success());
+    // A line that isn't a number reads as NaN, and the end of the input as -1.
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "42.5\nnan\n119999\n"
+    );
+}
+
+#[test]
+fn warnings_as_errors_fails_the_compile() {
+    let path = temp_file("unused.kal", "def f(x y) x\nf(1, 2)\n");
+    let normal = kaleidoscope(&["--run", path.to_str().unwrap()]);
+    let strict = kaleidoscope(&["--run", "--warnings-as-errors", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(normal.status.success());
+    assert_eq!(String::from_utf8_lossy(&normal.stdout), "1\n");
+    let stderr = String::from_utf8_lossy(&normal.stderr);
+    assert!(stderr.starts_with("warning at 1:"), "{stderr}");
+    assert!(stderr.contains("Unused parameter `y`"), "{stderr}");
+
+    assert_eq!(strict.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&strict.stderr);
+    assert!(stderr.starts_with("error at 1:"), "{stderr}");
+    assert!(stderr.contains("Unused parameter `y`"), "{stderr}");
+}
@@ -0,0 +1,37 @@
+use inkwell::context::Context;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::file_loop;
+use kaleidoscope::{Severity, State};
+
+#[test]
+fn every_error_is_reported() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f() nowhere\n1 + ;\ndef g() 2\n");
+
+    let diagnostics = file_loop(&mut state);
+    assert_eq!(diagnostics.len(), 2, "{diagnostics:?}");
+    assert!(diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic.severity == Severity::Error));
+    assert!(diagnostics[0].message.contains("nowhere"));
+    assert_eq!(diagnostics[0].span.start.line, 1);
+    assert_eq!(diagnostics[1].span.start.line, 2);
+
+    // Compiling went on after both errors.
+    assert!(state.module.get_function("g").is_some());
+    assert!(state.module.get_function("f").is_none());
+}
+
+#[test]
+fn unknown_variable_spans_its_expression() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(y) y\nx + 1\nf(2)\n");
+
+    let diagnostics = file_loop(&mut state);
+    assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+    assert!(diagnostics[0].message.contains("`x`"));
+    assert_eq!(diagnostics[0].span.start.line, 2);
+    assert_eq!(diagnostics[0].span.start.col, 1);
+}
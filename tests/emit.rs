@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+
+use inkwell::context::Context;
+use kaleidoscope::emit::{emit_asm_file, emit_bitcode_file, emit_ir_file, emit_object_file};
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::compile_loop;
+use kaleidoscope::{compile_to_ir, State};
+
+// A path in the system's temporary directory, named for this test run so tests running
+// at the same time don't share it.
+fn temp_path(name: &str) -> PathBuf {
+    return std::env::temp_dir().join(format!("kaleidoscope-{}-{name}", std::process::id()));
+}
+
+#[test]
+fn object_file_is_written() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(x) x + 1");
+    compile_loop(&mut state).unwrap();
+
+    let path = temp_path("f.o");
+    emit_object_file(&state, &path, None).unwrap();
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::remove_file(&path).unwrap();
+    assert!(len > 0);
+}
+
+#[test]
+fn ir_file_is_written() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(x) x + 1");
+    compile_loop(&mut state).unwrap();
+
+    let path = temp_path("f.ll");
+    emit_ir_file(&state, &path).unwrap();
+    let ir = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(ir.contains("define double @f(double %x)"), "{ir}");
+}
+
+#[test]
+fn compile_to_ir_returns_the_module() {
+    let ir = compile_to_ir("def square(x) x * x").unwrap();
+    assert!(ir.contains("define double @square(double %x)"), "{ir}");
+    assert!(ir.contains("fmul double %x, %x"), "{ir}");
+
+    let ir = compile_to_ir("extern sin(x); def f(x) sin(x) + 1; f(2)").unwrap();
+    assert!(ir.contains("declare double @sin(double)"), "{ir}");
+    assert!(ir.contains("call double @sin(double %x)"), "{ir}");
+    // The expression is kept, not run.
+    assert!(ir.contains("define double @__toplevel.0()"), "{ir}");
+}
+
+#[test]
+fn assembly_file_is_written() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def square(x) x * x");
+    compile_loop(&mut state).unwrap();
+
+    let path = temp_path("square.s");
+    emit_asm_file(&state, &path, None).unwrap();
+    let asm = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(asm.contains("square"), "{asm}");
+}
+
+#[test]
+fn object_file_for_another_target() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(x) x + 1");
+    compile_loop(&mut state).unwrap();
+
+    let path = temp_path("f-x86_64.o");
+    emit_object_file(&state, &path, Some("x86_64-unknown-linux-gnu")).unwrap();
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::remove_file(&path).unwrap();
+    assert!(len > 0);
+
+    let err = emit_object_file(&state, &path, Some("nonsense-target")).unwrap_err();
+    assert!(
+        err.contains("Unsupported target `nonsense-target`"),
+        "{err}"
+    );
+    assert!(!path.exists());
+}
+
+#[test]
+fn string_argument_is_a_global_constant() {
+    let ir = compile_to_ir("extern puts(s); puts(\"hi\\n\")").unwrap();
+    assert!(ir.contains(r#"c"hi\0A\00""#), "{ir}");
+    // `puts` is declared taking a double, so it is called through a cast.
+    assert!(ir.contains("@puts to double (i8*)*"), "{ir}");
+}
+
+#[test]
+fn variadic_extern_is_declared_variadic() {
+    let ir = compile_to_ir("extern printf(fmt ...); printf(\"%f %f\\n\", 1, 2)").unwrap();
+    assert!(ir.contains("declare double @printf(double, ...)"), "{ir}");
+    assert!(ir.contains("@printf to double (i8*, ...)*"), "{ir}");
+}
+
+#[test]
+fn debug_info_describes_the_source() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.enable_debug_info("square.kal");
+    state.parser.source = Source::from_text("def square(x)\n  x * x");
+    compile_loop(&mut state).unwrap();
+    state.finalize_debug_info();
+
+    let ir = state.module.print_to_string().to_string();
+    assert!(ir.contains("!llvm.dbg.cu"), "{ir}");
+    assert!(ir.contains("filename: \"square.kal\""), "{ir}");
+    assert!(ir.contains("DISubprogram(name: \"square\""), "{ir}");
+    // The multiplication is on the second line.
+    assert!(ir.contains("!DILocation(line: 2"), "{ir}");
+}
+
+#[test]
+fn bitcode_file_is_written() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(x) x + 1");
+    compile_loop(&mut state).unwrap();
+
+    let path = temp_path("f.bc");
+    emit_bitcode_file(&state, &path).unwrap();
+    let bitcode = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(bitcode.starts_with(b"BC\xc0\xde"), "{:x?}", &bitcode[..4]);
+
+    let missing = temp_path("no-such-directory").join("f.bc");
+    let err = emit_bitcode_file(&state, &missing).unwrap_err();
+    assert!(err.contains("Could not write"), "{err}");
+}
+
+#[test]
+fn extern_takes_a_calling_convention() {
+    let ir = compile_to_ir("extern \"fastcc\" fast_add(a b); def f(x) fast_add(x, 1)").unwrap();
+    assert!(
+        ir.contains("declare fastcc double @fast_add(double, double)"),
+        "{ir}"
+    );
+    assert!(ir.contains("call fastcc double @fast_add("), "{ir}");
+
+    // C is the default, and LLVM leaves it out.
+    let ir = compile_to_ir("extern \"C\" cos(x); def f(x) cos(x)").unwrap();
+    assert!(ir.contains("declare double @cos(double)"), "{ir}");
+
+    let err = compile_to_ir("extern \"stdcall\" g(a)").unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Unknown calling convention `stdcall`"),
+        "{err}"
+    );
+}
@@ -0,0 +1,159 @@
+use kaleidoscope::lexer::{Source, SourceLocation, Span};
+use kaleidoscope::parser::{parse_items, ParserState};
+use kaleidoscope::{compile_to_ir, jit_eval, parse_str, Diagnostic, Error, Severity};
+
+#[test]
+fn malformed_token_is_a_lex_error() {
+    match parse_str("99999999999999999999") {
+        Err(Error::Lex(err)) => assert!(err.message.contains("out of range"), "{err}"),
+        other => panic!("expected a lex error, got {other:?}"),
+    }
+}
+
+#[test]
+fn syntax_error_is_a_parse_error() {
+    match parse_str("def (x) x") {
+        Err(Error::Parse(err)) => {
+            assert!(!err.is_lex);
+            assert_eq!((err.loc.line, err.loc.col), (1, 5));
+        }
+        other => panic!("expected a parse error, got {other:?}"),
+    }
+}
+
+#[test]
+fn assignment_to_a_non_variable_is_a_parse_error() {
+    for source in ["1 = 2", "def f(x) (x) + 1 = 2"] {
+        match parse_str(source) {
+            Err(Error::Parse(err)) => assert!(err.message.contains("must be a variable"), "{err}"),
+            other => panic!("expected a parse error for {source:?}, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn unknown_name_is_a_codegen_error() {
+    assert!(matches!(
+        compile_to_ir("def f() nowhere"),
+        Err(Error::Codegen(_))
+    ));
+}
+
+#[test]
+fn unknown_variable_is_reported_with_its_position() {
+    match jit_eval("1 +\n  x") {
+        Err(Error::Codegen(err)) => {
+            assert!(err.message.contains("Unknown variable `x` at 2:3"), "{err}")
+        }
+        other => panic!("expected a codegen error, got {other:?}"),
+    }
+}
+
+#[test]
+fn bad_calls_are_codegen_errors() {
+    match jit_eval("nowhere(1)") {
+        Err(Error::Codegen(err)) => {
+            assert!(err.message.contains("Unknown function `nowhere`"), "{err}")
+        }
+        other => panic!("expected a codegen error, got {other:?}"),
+    }
+    match jit_eval("def f(x) x; f(1, 2)") {
+        Err(Error::Codegen(err)) => assert!(err.message.contains("expected 1, got 2"), "{err}"),
+        other => panic!("expected a codegen error, got {other:?}"),
+    }
+}
+
+#[test]
+fn definitions_alone_have_no_expression() {
+    assert!(matches!(jit_eval("def f() 1"), Err(Error::NoExpression)));
+}
+
+#[test]
+fn empty_input_has_no_items() {
+    for source in ["", "  \n\t\n", ";;\n\n;"] {
+        assert!(parse_str(source).unwrap().is_empty(), "{source:?}");
+    }
+}
+
+#[test]
+fn empty_input_has_no_expression() {
+    assert!(matches!(jit_eval(""), Err(Error::NoExpression)));
+    assert!(matches!(jit_eval(" \n "), Err(Error::NoExpression)));
+}
+
+#[test]
+fn diagnostic_from_error() {
+    let item = Span::at(SourceLocation { line: 1, col: 1 });
+
+    // A parse error points at where it stopped, not at the whole item.
+    let err = parse_str("1 + )").unwrap_err();
+    let loc = match &err {
+        Error::Parse(err) => err.loc,
+        other => panic!("expected a parse error, got {other:?}"),
+    };
+    let diagnostic = Diagnostic::from_error(err, item);
+    assert_eq!(diagnostic.severity, Severity::Error);
+    assert_eq!(diagnostic.span, Span::at(loc));
+
+    let diagnostic = Diagnostic::from_error(Error::Jit(String::from("no JIT")), item);
+    assert_eq!(diagnostic.message, "no JIT");
+    assert_eq!(diagnostic.span, item);
+}
+
+#[test]
+fn malformed_number_is_a_lex_error_at_its_position() {
+    match parse_str("def f(x) x + 1.2.3") {
+        Err(Error::Lex(err)) => {
+            assert!(
+                err.message.contains("Invalid number literal `1.2.3`"),
+                "{err}"
+            );
+            assert_eq!((err.loc.line, err.loc.col), (1, 14));
+        }
+        other => panic!("expected a lex error, got {other:?}"),
+    }
+    match parse_str("1 +\n  12345678901234567890") {
+        Err(Error::Lex(err)) => assert_eq!((err.loc.line, err.loc.col), (2, 3)),
+        other => panic!("expected a lex error, got {other:?}"),
+    }
+    // A lone '.' is an operator character, not a number.
+    assert!(matches!(parse_str("."), Err(Error::Parse(err)) if !err.is_lex));
+    assert!(parse_str("3.14").is_ok());
+}
+
+#[test]
+fn deep_nesting_is_a_parse_error() {
+    let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(parse_str(&nested(50)).is_ok());
+    match parse_str(&nested(100_000)) {
+        Err(Error::Parse(err)) => {
+            assert!(err.message.contains("nested too deeply"), "{err}");
+            assert!(err.message.contains("256 levels"), "{err}");
+        }
+        other => panic!("expected a parse error, got {other:?}"),
+    }
+
+    // The limit can be lowered.
+    let mut parser = ParserState::new();
+    parser.max_parse_depth = 8;
+    parser.source = Source::from_text(&nested(50));
+    assert!(parse_items(&mut parser, 0).is_err());
+}
+
+#[test]
+fn repeated_or_self_named_parameters_are_parse_errors() {
+    match parse_str("def f(x x) x") {
+        Err(Error::Parse(err)) => {
+            assert!(err.message.contains("Duplicate parameter `x`"), "{err}");
+            assert_eq!((err.loc.line, err.loc.col), (1, 9));
+        }
+        other => panic!("expected a parse error, got {other:?}"),
+    }
+    match parse_str("def f(f) f") {
+        Err(Error::Parse(err)) => assert!(err.message.contains("same name"), "{err}"),
+        other => panic!("expected a parse error, got {other:?}"),
+    }
+    // Operator parameters can't repeat either.
+    assert!(parse_str("def binary~ 5 (a a) a").is_err());
+    assert!(parse_str("def f(x y) x; extern g(a b)").is_ok());
+}
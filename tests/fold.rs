@@ -0,0 +1,141 @@
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::compile_loop;
+use kaleidoscope::{compile_to_ir, Pass, State};
+
+// The IR of `source` compiled without optimization passes, so only the compiler's own
+// folding is left to remove instructions.
+fn unoptimized_ir(source: &str) -> String {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.set_opt_level(OptimizationLevel::None);
+    state.parser.source = Source::from_text(source);
+    compile_loop(&mut state).unwrap();
+    return state.module.print_to_string().to_string();
+}
+
+#[test]
+fn literal_sum_is_folded() {
+    let ir = compile_to_ir("def f() 2+3").unwrap();
+    assert!(!ir.contains("fadd"), "{ir}");
+    assert!(!ir.contains(" add "), "{ir}");
+}
+
+#[test]
+fn constant_variables_are_folded() {
+    let ir = unoptimized_ir("def f() var a = 2, b = 3.5 in a * b - 1");
+    assert!(!ir.contains("load"), "{ir}");
+    assert!(!ir.contains("fmul"), "{ir}");
+    assert!(ir.contains("6.000000e+00"), "{ir}");
+}
+
+#[test]
+fn division_is_folded() {
+    let ir = unoptimized_ir("def f() var a = 7 in a / 2");
+    assert!(!ir.contains("sdiv"), "{ir}");
+    let ir = unoptimized_ir("def f() var a = 1.5 in a / 0.5");
+    assert!(!ir.contains("fdiv"), "{ir}");
+    assert!(ir.contains("3.000000e+00"), "{ir}");
+}
+
+#[test]
+fn division_by_zero_is_not_folded() {
+    let ir = unoptimized_ir("def f() var a = 7 in a / 0");
+    assert!(ir.contains("call void @kaleidoscope.divzero()"), "{ir}");
+    let ir = unoptimized_ir("def f() var a = 7 in a % 0");
+    assert!(ir.contains("call void @kaleidoscope.divzero()"), "{ir}");
+}
+
+#[test]
+fn optimization_level_picks_the_passes() {
+    let source = "def f(x) var y = x in y + 1";
+    assert!(unoptimized_ir(source).contains("alloca"));
+
+    // Above level 0, mem2reg promotes the variables to registers.
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.set_opt_level(OptimizationLevel::Default);
+    state.parser.source = Source::from_text(source);
+    compile_loop(&mut state).unwrap();
+    assert!(!state
+        .module
+        .print_to_string()
+        .to_string()
+        .contains("alloca"));
+}
+
+#[test]
+fn allocas_come_first_in_the_entry_block() {
+    let ir = unoptimized_ir(
+        "def f(x) var a = x + 1 in { var b = a * 2; for i = 0, i < 3 in b = b + i; b }",
+    );
+    let body = &ir[ir.find("define double @f(").unwrap()..];
+    let entry: Vec<&str> = body
+        .lines()
+        .skip_while(|line| *line != "entry:")
+        .skip(1)
+        .take_while(|line| !line.is_empty() && !line.ends_with(':'))
+        .collect();
+    let allocas = entry
+        .iter()
+        .take_while(|line| line.contains(" = alloca "))
+        .count();
+    // The allocas for `x`, `a`, `b` and `i` all come before the first store.
+    assert!(allocas >= 4, "{ir}");
+    assert!(
+        entry[allocas..]
+            .iter()
+            .all(|line| !line.contains(" = alloca ")),
+        "{ir}"
+    );
+    assert!(entry[allocas].contains("store double %x"), "{ir}");
+}
+
+#[test]
+fn only_the_chosen_passes_run() {
+    let ir_with = |passes: Option<&str>| {
+        let context = Context::create();
+        let mut state = State::new(&context);
+        state.set_passes(passes.map(|list| Pass::parse_list(list).unwrap()));
+        state.parser.source = Source::from_text("def f(x) var y = x in y * 1.0");
+        compile_loop(&mut state).unwrap();
+        return state.module.print_to_string().to_string();
+    };
+    // mem2reg alone removes the variable but leaves the multiplication by one.
+    let ir = ir_with(Some("mem2reg"));
+    assert!(!ir.contains("alloca"), "{ir}");
+    assert!(ir.contains("fmul double %x, 1.000000e+00"), "{ir}");
+    // instcombine, in the full pipeline, removes it too.
+    let ir = ir_with(None);
+    assert!(!ir.contains("fmul"), "{ir}");
+
+    let err = Pass::parse_list("mem2reg,nosuchpass").unwrap_err();
+    assert!(err.contains("Unknown pass `nosuchpass`"), "{err}");
+}
+
+#[test]
+fn only_unassigned_variables_are_constants() {
+    let ir = unoptimized_ir("def f(y) var x = 3 in x * y");
+    assert!(!ir.contains("load i64"), "{ir}");
+    assert!(ir.contains("fmul double 3.000000e+00, %"), "{ir}");
+
+    // Assigned anywhere in its scope, even after it is read, the variable is loaded.
+    let ir = unoptimized_ir("def f(y) var x = 3 in x * y + (x = 4)");
+    assert!(ir.contains("load i64, i64* %x"), "{ir}");
+    assert!(ir.contains("store i64 4, i64* %x"), "{ir}");
+}
+
+#[test]
+fn negative_literals_are_constants() {
+    let ir = unoptimized_ir("def f() -3.0");
+    assert!(!ir.contains("fneg"), "{ir}");
+    assert!(ir.contains("ret double -3.000000e+00"), "{ir}");
+    let ir = unoptimized_ir("def g() -3");
+    assert!(!ir.contains("sub i64"), "{ir}");
+    assert!(ir.contains("ret double -3.000000e+00"), "{ir}");
+
+    // A variable is still negated when the function runs.
+    let ir = unoptimized_ir("def h(x) -x");
+    assert!(ir.contains("fneg double"), "{ir}");
+}
@@ -0,0 +1,731 @@
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::{check_loop, compile_loop, eval_loop, ParserState};
+use kaleidoscope::{compile_to_ir, jit_eval, Error, State};
+
+#[test]
+fn definition_then_call() {
+    assert_eq!(jit_eval("def f(x) x + 1; f(3)").unwrap(), 4.0);
+}
+
+#[test]
+fn definition_persists_across_inputs() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert!(matches!(
+        state.jit_eval("def f(x) x + 1"),
+        Err(Error::NoExpression)
+    ));
+    assert_eq!(state.jit_eval("f(3)").unwrap(), 4.0);
+    // Each input only runs its own expressions.
+    assert_eq!(state.jit_eval("f(10)").unwrap(), 11.0);
+}
+
+#[test]
+fn redefinition_replaces_body() {
+    assert_eq!(jit_eval("def f() 1; def f() 2; f()").unwrap(), 2.0);
+}
+
+#[test]
+fn redefinition_keeps_callers() {
+    assert_eq!(
+        jit_eval("def f() 1; def g() f(); def f() 2; g()").unwrap(),
+        2.0
+    );
+}
+
+#[test]
+fn failed_redefinition_keeps_old_definition() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert!(state.jit_eval("def f() 1").is_err());
+    assert!(matches!(
+        state.jit_eval("def f() nowhere"),
+        Err(Error::Codegen(_))
+    ));
+    assert_eq!(state.jit_eval("f()").unwrap(), 1.0);
+}
+
+#[test]
+fn failed_definition_keeps_declaration_callers_valid() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert!(matches!(
+        state.jit_eval("extern f(); def g() f(); def f() nowhere"),
+        Err(Error::Codegen(_))
+    ));
+    // `g` still calls the declaration of `f`, which must not have been deleted.
+    assert!(state.module.get_function("f").is_some());
+    assert!(state.module.get_function("g").is_some());
+    assert!(state.module.verify().is_ok());
+    assert_eq!(state.jit_eval("def f() 5; g()").unwrap(), 5.0);
+}
+
+#[test]
+fn extern_then_definition() {
+    assert_eq!(jit_eval("extern f(x); def f(x) x + 1; f(2)").unwrap(), 3.0);
+}
+
+#[test]
+fn conflicting_extern_is_rejected() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert!(state.jit_eval("def f(x) x + 1").is_err());
+    assert!(matches!(
+        state.jit_eval("extern f(x y)"),
+        Err(Error::Codegen(_))
+    ));
+    assert!(matches!(
+        state.jit_eval("extern f(x): int"),
+        Err(Error::Codegen(_))
+    ));
+    // The definition and its prototype are untouched.
+    assert_eq!(state.jit_eval("f(2)").unwrap(), 3.0);
+}
+
+// `int` functions turn their double arguments into integers the compiler can't fold.
+const INT: &str =
+    "def int(x): int x; def irem(x y) int(x) % int(y); def idiv(x y) int(x) / int(y); def shl(x y) int(x) << int(y); def shr(x y) int(x) >> int(y); ";
+
+#[test]
+fn integer_remainder() {
+    assert_eq!(jit_eval("7 % 3").unwrap(), 1.0);
+    assert_eq!(jit_eval(&format!("{INT} irem(7, 3)")).unwrap(), 1.0);
+    assert_eq!(jit_eval(&format!("{INT} irem(-7, 3)")).unwrap(), -1.0);
+    assert_eq!(jit_eval(&format!("{INT} irem(-7, -1)")).unwrap(), 0.0);
+}
+
+#[test]
+fn bitwise_operators() {
+    assert_eq!(jit_eval("6 & 3").unwrap(), 2.0);
+    assert_eq!(jit_eval("6 | 3").unwrap(), 7.0);
+    assert_eq!(jit_eval("1 << 4").unwrap(), 16.0);
+    assert_eq!(jit_eval("-8 >> 1").unwrap(), -4.0);
+    assert!(matches!(jit_eval("1.5 & 1"), Err(Error::Codegen(_))));
+}
+
+#[test]
+fn power_groups_to_the_right() {
+    assert_eq!(jit_eval("2^10").unwrap(), 1024.0);
+    assert_eq!(jit_eval("2^3^2").unwrap(), 512.0);
+    assert_eq!(
+        jit_eval("def pow(a b c) a^b^c; pow(2, 3, 2)").unwrap(),
+        512.0
+    );
+}
+
+#[test]
+fn power_binds_tighter_than_multiplication() {
+    assert_eq!(jit_eval("2*3^2").unwrap(), 18.0);
+    assert_eq!(jit_eval("3^2*2").unwrap(), 18.0);
+    assert_eq!(jit_eval("1+2^2").unwrap(), 5.0);
+    assert_eq!(jit_eval("-2^2").unwrap(), 4.0);
+}
+
+#[test]
+fn xor_is_a_builtin_function() {
+    assert_eq!(jit_eval("xor(6, 3)").unwrap(), 5.0);
+    assert_eq!(
+        jit_eval(&format!("{INT} xor(int(6), int(3))")).unwrap(),
+        5.0
+    );
+    assert!(matches!(jit_eval("xor(1.5, 3)"), Err(Error::Codegen(_))));
+    // A program's own `xor` takes over.
+    assert_eq!(jit_eval("def xor(a b) a + b; xor(1.5, 3)").unwrap(), 4.5);
+}
+
+#[test]
+fn shift_amount_is_taken_modulo_64() {
+    let min = i64::MIN as f64;
+    // Folded at compile time.
+    assert_eq!(jit_eval("1 << 63").unwrap(), min);
+    assert_eq!(jit_eval("1 << 64").unwrap(), 1.0);
+    assert_eq!(jit_eval("1 << -1").unwrap(), min);
+    assert_eq!(jit_eval("-8 >> 65").unwrap(), -4.0);
+    // Computed at run time.
+    assert_eq!(jit_eval(&format!("{INT} shl(1, 63)")).unwrap(), min);
+    assert_eq!(jit_eval(&format!("{INT} shl(1, 64)")).unwrap(), 1.0);
+    assert_eq!(jit_eval(&format!("{INT} shl(1, -1)")).unwrap(), min);
+    assert_eq!(jit_eval(&format!("{INT} shr(-8, 65)")).unwrap(), -4.0);
+}
+
+#[test]
+fn division() {
+    assert_eq!(jit_eval("def f(a b) a / b // c\nf(6, 4)").unwrap(), 1.5);
+    assert_eq!(jit_eval("7 / 2").unwrap(), 3.0);
+    assert_eq!(jit_eval("1 + 6 / 2 * 3").unwrap(), 10.0);
+    assert_eq!(jit_eval(&format!("{INT} idiv(-7, 2)")).unwrap(), -3.0);
+    assert_eq!(jit_eval(&format!("{INT} idiv(-7, -1)")).unwrap(), 7.0);
+}
+
+#[test]
+fn integer_division_by_zero_is_a_runtime_error() {
+    let ir = compile_to_ir(&format!("{INT} irem(7, 0)")).unwrap();
+    assert!(ir.contains("call void @kaleidoscope.divzero()"), "{ir}");
+    assert!(!ir.contains("llvm.trap"), "{ir}");
+
+    assert!(matches!(jit_eval("7 % 0"), Err(Error::Runtime(_))));
+    assert!(matches!(
+        jit_eval(&format!("{INT} idiv(7, 0)")),
+        Err(Error::Runtime(_))
+    ));
+    assert!(jit_eval("7.0 % 0.0").unwrap().is_nan());
+
+    // The session carries on, without the error sticking to the next expression.
+    let context = Context::create();
+    let mut state = State::new(&context);
+    let err = state.jit_eval(&format!("{INT} irem(7, 0)")).unwrap_err();
+    assert_eq!(err.to_string(), "Division by zero");
+    assert_eq!(state.jit_eval("irem(7, 2)").unwrap(), 1.0);
+}
+
+// The values of `source`'s expressions, run as the REPL runs them.
+fn repl_values(source: &str) -> Vec<f64> {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text(source);
+    return eval_loop(&mut state).unwrap();
+}
+
+#[test]
+fn many_evaluations_in_one_session() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    let mut source = String::from("def f(x) x * 2\n");
+    for i in 0..50 {
+        source.push_str(&format!("f({i})\n"));
+    }
+    state.parser.source = Source::from_text(&source);
+
+    let values = eval_loop(&mut state).unwrap();
+    let expected: Vec<f64> = (0..50).map(|i| (i * 2) as f64).collect();
+    assert_eq!(values, expected);
+    // The expressions leave nothing behind.
+    assert!(!state
+        .function_protos
+        .keys()
+        .any(|name| name.starts_with("__anon_expr")));
+}
+
+#[test]
+fn session_sees_new_definitions() {
+    let values = repl_values("def f(x) x * 2; f(1); def f(x) x * 3; f(1); def g() f(2); g()");
+    assert_eq!(values, vec![2.0, 3.0, 6.0]);
+}
+
+#[test]
+fn definitions_after_an_expression_leave_the_jit_alone() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(x) x + 1; f(1)");
+    assert_eq!(eval_loop(&mut state).unwrap(), vec![2.0]);
+    let f = state
+        .jit
+        .as_ref()
+        .unwrap()
+        .get_function_address("f")
+        .unwrap();
+
+    // `g` is added to the same JIT, and `f` is not compiled again.
+    state.parser.source = Source::from_text("def g(x) f(x) * 2; g(1)");
+    state.parser.rewind();
+    assert_eq!(eval_loop(&mut state).unwrap(), vec![4.0]);
+    let ee = state.jit.as_ref().unwrap();
+    assert_eq!(ee.get_function_address("f").unwrap(), f);
+    assert!(state.jitted.contains("f") && state.jitted.contains("g"));
+}
+
+#[test]
+fn globals_keep_their_values_across_definitions() {
+    let values = repl_values("global n = 0; def bump() n = n + 1; bump(); def g() 1; bump()");
+    assert_eq!(values, vec![1.0, 2.0]);
+}
+
+#[test]
+fn definition_replaces_builtin_before_it_is_defined() {
+    assert_eq!(jit_eval("sqrt(1, 2); def sqrt(x y) x + y").unwrap(), 3.0);
+    assert_eq!(jit_eval("sqrt(16)").unwrap(), 4.0);
+}
+
+const IS_EVEN: &str = "def is_even(n) if n < 1 then 1 else is_odd(n - 1)\n";
+const IS_ODD: &str = "def is_odd(n) if n < 1 then 0 else is_even(n - 1)\n";
+
+#[test]
+fn mutual_recursion_in_either_order() {
+    let even_first = format!("{IS_EVEN}{IS_ODD}is_even(10) + is_odd(7)");
+    assert_eq!(jit_eval(&even_first).unwrap(), 2.0);
+    let odd_first = format!("{IS_ODD}{IS_EVEN}is_even(10) + is_odd(7)");
+    assert_eq!(jit_eval(&odd_first).unwrap(), 2.0);
+    assert_eq!(
+        jit_eval(&format!("{IS_ODD}{IS_EVEN}is_even(3)")).unwrap(),
+        0.0
+    );
+}
+
+#[test]
+fn expression_calls_a_later_definition() {
+    let source = format!("is_odd(5)\n{IS_EVEN}{IS_ODD}");
+    assert_eq!(jit_eval(&source).unwrap(), 1.0);
+}
+
+#[test]
+fn reset_forgets_the_last_program() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    let first = "def f(x) x * 2; def g() 7; def binary~ 5 (a b) a - b; f(3) ~ g()";
+    assert_eq!(state.jit_eval(first).unwrap(), -1.0);
+
+    state.reset();
+    // The same name with different arguments is a new function, not a redefinition.
+    assert_eq!(state.jit_eval("def f(x y) x + y; f(1, 2)").unwrap(), 3.0);
+    assert!(state.module.get_function("g").is_none());
+    assert!(!state.function_protos.contains_key("g"));
+    assert!(state.parser.get_precedence("~").is_none());
+
+    state.reset();
+    assert!(matches!(state.jit_eval("g()"), Err(Error::Codegen(_))));
+}
+
+#[test]
+fn ampersand_takes_a_function_address_unless_redefined() {
+    let twice = "def square(x) x * x; def twice(f x) invoke(f, invoke(f, x)); ";
+    assert_eq!(
+        jit_eval(&format!("{twice}twice(&square, 3)")).unwrap(),
+        81.0
+    );
+    // From its definition on, a program's own `unary&` takes over.
+    assert_eq!(
+        jit_eval("def unary&(x) x + 1; def f(x) &x; f(2)").unwrap(),
+        3.0
+    );
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert!(state.jit_eval("def unary&(x) x * 10").is_err());
+    assert_eq!(state.jit_eval("def f(x) &x; f(2)").unwrap(), 20.0);
+}
+
+#[test]
+fn var_initializers_see_the_variables_before_them() {
+    assert_eq!(
+        jit_eval("def f(x) var a = x + 1, b = a * 3 in b; f(1)").unwrap(),
+        6.0
+    );
+    assert_eq!(
+        jit_eval("def f(x) var x = x * 2, x = x + 1 in x; f(5)").unwrap(),
+        11.0
+    );
+}
+
+// The LLVM type of `expr`, as the type of the variable it initializes in a function of
+// `a`.  Without optimization the variable keeps its alloca.
+fn value_type(expr: &str) -> String {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.set_opt_level(OptimizationLevel::None);
+    state.parser.source = Source::from_text(&format!("def f(a) var v = {expr} in v"));
+    compile_loop(&mut state).unwrap();
+    let ir = state.module.print_to_string().to_string();
+    let start = ir.find("%v = alloca ").unwrap() + "%v = alloca ".len();
+    return ir[start..].split(',').next().unwrap().to_string();
+}
+
+#[test]
+fn comparisons_are_bools() {
+    for (source, value) in [
+        ("4 < 5", 1.0),
+        ("5 < 4", 0.0),
+        ("4.5 > 5", 0.0),
+        ("true", 1.0),
+    ] {
+        assert_eq!(jit_eval(source).unwrap(), value, "{source}");
+        assert_eq!(value_type(source), "i1", "{source}");
+    }
+    assert_eq!(value_type("a < 5"), "i1");
+    // A bool in arithmetic is 1 or 0.
+    assert_eq!(jit_eval("(4 < 5) + (2.5 > 3) + 1").unwrap(), 2.0);
+}
+
+#[test]
+fn integer_and_double_arithmetic() {
+    for (source, value, ty) in [
+        ("1/2", 0.0, "i64"),
+        ("1.0/2", 0.5, "double"),
+        ("2 + 3", 5.0, "i64"),
+        ("2.0 + 3", 5.0, "double"),
+        ("a + 1", 1.0, "double"),
+    ] {
+        assert_eq!(value_type(source), ty, "{source}");
+        let program = format!("def f(a) {source}; f(0)");
+        assert_eq!(jit_eval(&program).unwrap(), value, "{source}");
+    }
+}
+
+#[test]
+fn user_defined_division_replaces_the_builtin() {
+    let divide = "def binary/ 40 (a b) a * 100 + b; ";
+    assert_eq!(
+        jit_eval(&format!("{divide}def f(a b) a / b; f(1.5, 2)")).unwrap(),
+        152.0
+    );
+    // Literals are not folded with the builtin either.
+    assert_eq!(jit_eval(&format!("{divide}7 / 2")).unwrap(), 702.0);
+}
+
+#[test]
+fn for_variable_takes_the_type_of_its_start() {
+    let halves = "def f() var s = 0.0 in (for i = START, i < 4 in s = s + i / 2) : s; f()";
+    // From an integer, `i / 2` is integer division: 0 + 0 + 1 + 1 + 2.
+    assert_eq!(jit_eval(&halves.replace("START", "0")).unwrap(), 4.0);
+    assert_eq!(jit_eval(&halves.replace("START", "0.0")).unwrap(), 5.0);
+    assert_eq!(
+        jit_eval("def f() var n = 0 in (for i = 10, i > 0, -3 in n = n + 1) : n; f()").unwrap(),
+        5.0
+    );
+    assert!(matches!(
+        jit_eval("def f() for i = 0, i < 1, 0.5 in 0; f()"),
+        Err(Error::Codegen(_))
+    ));
+}
+
+#[test]
+fn host_output_functions_are_mapped_in() {
+    // Both print, and give back 0.
+    assert_eq!(jit_eval("extern printd(x); printd(42)").unwrap(), 0.0);
+    assert_eq!(jit_eval("extern putchard(c); putchard(10)").unwrap(), 0.0);
+}
+
+#[test]
+fn if_without_else_is_zero_when_false() {
+    assert_eq!(jit_eval("if 1 < 2 then 5").unwrap(), 5.0);
+    assert_eq!(jit_eval("if 2 < 1 then 5").unwrap(), 0.0);
+    assert_eq!(jit_eval("if 2 < 1 then 5 else 7").unwrap(), 7.0);
+}
+
+#[test]
+fn while_loop_runs_until_its_condition_fails() {
+    let counted = "{ var i = 0; var n = 0; while i < 5 do { i = i + 1; n = n + 2 }; n }";
+    assert_eq!(jit_eval(counted).unwrap(), 10.0);
+    // A false condition skips the body, and the loop itself is 0.
+    assert_eq!(
+        jit_eval("{ var n = 3; while n < 0 do n = 1; n }").unwrap(),
+        3.0
+    );
+    assert_eq!(jit_eval("while 0 < 0 do 1").unwrap(), 0.0);
+}
+
+#[test]
+fn for_loop_can_yield_its_last_body_value() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.for_returns_body = true;
+    let last = state
+        .jit_eval("{ var last = 0; for i = 0, i < 3 in last = i * 10; last }")
+        .unwrap();
+    assert!(last > 0.0);
+    assert_eq!(state.jit_eval("for i = 0, i < 3 in i * 10").unwrap(), last);
+    // A body that never finishes leaves the loop at 0.
+    assert_eq!(state.jit_eval("for i = 0, i < 3 in break").unwrap(), 0.0);
+
+    // Otherwise a loop is always 0.
+    state.for_returns_body = false;
+    assert_eq!(state.jit_eval("for i = 0, i < 3 in i * 10").unwrap(), 0.0);
+}
+
+#[test]
+fn inner_bindings_shadow_and_then_restore_outer_ones() {
+    assert_eq!(
+        jit_eval("def f(x) (var x = 2 in x * 10) + x; f(1)").unwrap(),
+        21.0
+    );
+    assert_eq!(
+        jit_eval("def g(i) (for i = 0, i < 3 in 0) + i; g(7)").unwrap(),
+        7.0
+    );
+    let nested = "def h(x) var x = x + 1 in (var x = x * 10 in x) + x; h(1)";
+    assert_eq!(jit_eval(nested).unwrap(), 22.0);
+}
+
+#[test]
+fn each_top_level_expression_gets_a_name_of_its_own() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("1 + 1; 2 + 2; 1 + 1");
+    assert_eq!(eval_loop(&mut state).unwrap(), vec![2.0, 4.0, 2.0]);
+    assert_eq!(state.anon_count, 3);
+    // Each ran in a module of its own, so none is left in the definitions.
+    assert!(!state
+        .module
+        .get_functions()
+        .any(|func| func.get_name().to_string_lossy().starts_with("__anon_expr")));
+}
+
+#[test]
+fn colon_sequences_expressions() {
+    assert_eq!(jit_eval("1 : 2 : 3").unwrap(), 3.0);
+    // Each side runs, left to right.
+    let source = "{ var n = 1; (n = n + 1) : (n = n * 10) : n }";
+    assert_eq!(jit_eval(source).unwrap(), 20.0);
+    assert_eq!(
+        jit_eval("extern printd(x); printd(1) : printd(2) : 3").unwrap(),
+        3.0
+    );
+}
+
+#[test]
+fn several_items_on_one_line() {
+    assert_eq!(
+        repl_values("def f(x) x+1; def g(x) x*2; f(3) + g(1)"),
+        vec![6.0]
+    );
+}
+
+#[test]
+fn jit_eval_runs_a_program() {
+    assert_eq!(jit_eval("1 + 2 * 3").unwrap(), 7.0);
+    assert_eq!(jit_eval("if 1 < 2 then 10 else 20").unwrap(), 10.0);
+    assert_eq!(jit_eval("def twice(x) x * 2; twice(21)").unwrap(), 42.0);
+    // The last expression is the result; every one runs.
+    assert_eq!(jit_eval("1; 2; sqrt(9)").unwrap(), 3.0);
+}
+
+#[test]
+fn descending_for_loop_terminates() {
+    // The condition is tested after each run of the body, before the step, so the body
+    // also runs for the first value that fails it: 10 down to 0.
+    let count = "def f() var n = 0 in (for i = 10, i > 0, -1 in n = n + 1) : n; f()";
+    assert_eq!(jit_eval(count).unwrap(), 11.0);
+    let sum = "def f() var s = 0.0 in (for x = 1.0, x > 0, -0.25 in s = s + x) : s; f()";
+    assert_eq!(jit_eval(sum).unwrap(), 2.5);
+}
+
+#[test]
+fn integer_and_double_variables_in_one_function() {
+    // Both are assigned, so both are loaded.  `a` is loaded as an integer, so `a / 2`
+    // divides as integers, and `b` as a double.
+    let source = "def f() var a = 6, b = 0.5 in (a = a + 1) : (b = b / 2) : a / 2 + b; f()";
+    assert_eq!(jit_eval(source).unwrap(), 3.25);
+}
+
+#[test]
+fn conditional_expression_picks_a_branch() {
+    assert_eq!(jit_eval("(1 < 2) ? 10 : 20").unwrap(), 10.0);
+    assert_eq!(jit_eval("(2 < 1) ? 10 : 20").unwrap(), 20.0);
+    // The else branch nests to the right.
+    let sign = "def sign(x) x < 0 ? -1 : x > 0 ? 1 : 0; ";
+    assert_eq!(jit_eval(&format!("{sign} sign(-3)")).unwrap(), -1.0);
+    assert_eq!(jit_eval(&format!("{sign} sign(0)")).unwrap(), 0.0);
+    assert_eq!(jit_eval(&format!("{sign} sign(5)")).unwrap(), 1.0);
+}
+
+#[test]
+fn deep_tail_recursion_does_not_overflow() {
+    let count = "def count(n acc) if n < 1 then acc else count(n - 1, acc + 1); ";
+    assert_eq!(
+        jit_eval(&format!("{count} count(10000000, 0)")).unwrap(),
+        10000000.0
+    );
+}
+
+#[test]
+fn global_is_shared_between_functions() {
+    let program = "global total = 1; def get() total; def add(x) total = total + x; ";
+    assert_eq!(jit_eval(&format!("{program} get()")).unwrap(), 1.0);
+    assert_eq!(
+        jit_eval(&format!("{program} add(4) : add(5) : get()")).unwrap(),
+        10.0
+    );
+}
+
+#[test]
+fn chained_assignment_sets_every_variable() {
+    let program = "def f() var a = 0, b = 0 in (a = b = 5) : a * 10 + b; f()";
+    assert_eq!(jit_eval(program).unwrap(), 55.0);
+}
+
+#[test]
+fn comparisons_with_nan_follow_the_chosen_ordering() {
+    // Through a parameter, so the comparison is not folded away.
+    let program = "def lt(a b) a < b; def gt(a b) a > b; ";
+    let nan_compares = |ordered: bool| {
+        let context = Context::create();
+        let mut state = State::new(&context);
+        state.ordered_compare = ordered;
+        let lt = state
+            .jit_eval(&format!("{program} lt(0.0 / 0.0, 1)"))
+            .unwrap();
+        let gt = state.jit_eval("gt(0.0 / 0.0, 1)").unwrap();
+        // The same with constant operands, which may be folded.
+        let folded = state.jit_eval("0.0 / 0.0 < 1").unwrap();
+        return (lt, gt, folded);
+    };
+    assert_eq!(nan_compares(false), (1.0, 1.0, 1.0));
+    assert_eq!(nan_compares(true), (0.0, 0.0, 0.0));
+}
+
+#[test]
+fn block_body_runs_in_order_and_yields_its_last_expression() {
+    assert_eq!(
+        jit_eval("def f(x) { var y = x + 1; y * 2 }; f(4)").unwrap(),
+        10.0
+    );
+    // A variable declared in a block ends with it.
+    let scoped = "def g(x) { var y = 1; { var y = 10; x = x + y }; x + y }; g(0)";
+    assert_eq!(jit_eval(scoped).unwrap(), 11.0);
+}
+
+#[test]
+fn chained_comparisons_read_either_way() {
+    let program = "def between(a b c) a < b < c; ";
+    let chained = |chain: bool, call: &str| {
+        let context = Context::create();
+        let mut state = State::new(&context);
+        state.parser.chain_compare = chain;
+        return state.jit_eval(&format!("{program} {call}")).unwrap();
+    };
+    // By default `3 < 2 < 1` is `(3 < 2) < 1`, which is `0 < 1`.
+    assert_eq!(chained(false, "between(3, 2, 1)"), 1.0);
+    assert_eq!(chained(true, "between(3, 2, 1)"), 0.0);
+    assert_eq!(chained(false, "between(1, 2, 3)"), 1.0);
+    assert_eq!(chained(true, "between(1, 2, 3)"), 1.0);
+    assert_eq!(chained(true, "between(1, 3, 2)"), 0.0);
+}
+
+#[test]
+fn elif_chain_takes_the_first_true_branch() {
+    let classify = "def classify(x) if x < 0 then 1 elif x < 10 then 2 else 3; ";
+    assert_eq!(jit_eval(&format!("{classify} classify(-5)")).unwrap(), 1.0);
+    assert_eq!(jit_eval(&format!("{classify} classify(5)")).unwrap(), 2.0);
+    assert_eq!(jit_eval(&format!("{classify} classify(50)")).unwrap(), 3.0);
+}
+
+#[test]
+fn break_and_continue_in_nested_loops() {
+    // Each run of the outer loop counts j = 2 and 3 before the inner loop breaks, until
+    // the outer loop breaks at i = 6.
+    let program = "def f() {
+        var total = 0; var last = 0;
+        for i = 0, i < 100 in {
+            if i > 5 then break;
+            var j = 0;
+            while j < 10 do {
+                j = j + 1;
+                if j > 3 then break;
+                if j < 2 then continue;
+                total = total + 1
+            };
+            last = i
+        };
+        total * 100 + last
+    }; f()";
+    assert_eq!(jit_eval(program).unwrap(), 1205.0);
+
+    // Outside a loop, the checker reports either one.
+    let mut parser = ParserState::new();
+    parser.source = Source::from_text("def f() break\ndef g(x) if x then continue else 1\n");
+    let diagnostics = check_loop(&mut parser);
+    assert_eq!(diagnostics.len(), 2, "{diagnostics:?}");
+    assert!(diagnostics[0].message.contains("`break` outside of a loop"));
+    assert!(diagnostics[1]
+        .message
+        .contains("`continue` outside of a loop"));
+}
+
+#[test]
+fn invoking_an_address_is_a_direct_call() {
+    let program = "def mix(a b) a * 10 + b; def apply(f x y) invoke(f, x, y); ";
+    assert_eq!(
+        jit_eval(&format!("{program} apply(&mix, 4, 2)")).unwrap(),
+        jit_eval(&format!("{program} mix(4, 2)")).unwrap()
+    );
+    // An address kept in a variable calls the same function.
+    assert_eq!(
+        jit_eval(&format!("{program} var g = &mix in apply(g, 4, 2)")).unwrap(),
+        42.0
+    );
+}
+
+#[test]
+fn math_builtins_are_intrinsics() {
+    assert_eq!(jit_eval("sqrt(16)").unwrap(), 4.0);
+    assert_eq!(jit_eval("max(3, 7)").unwrap(), 7.0);
+    assert_eq!(jit_eval("min(3, 7)").unwrap(), 3.0);
+
+    let ir = compile_to_ir("def f(x y) sqrt(min(x, y)) + max(x, y)").unwrap();
+    for intrinsic in ["llvm.sqrt.f64", "llvm.minnum.f64", "llvm.maxnum.f64"] {
+        assert!(ir.contains(&format!("call double @{intrinsic}(")), "{ir}");
+    }
+    // A program's own definition is called instead.
+    assert_eq!(jit_eval("def max(a b) a; max(3, 7)").unwrap(), 3.0);
+}
+
+#[test]
+fn if_branches_take_the_wider_type() {
+    for (source, value, ty) in [
+        ("if a < 1 then 2 else 3", 2.0, "i64"),
+        ("if a < 1 then 2 else 0.5", 2.0, "double"),
+        ("if a > 1 then 2 else 0.5", 0.5, "double"),
+        ("if a < 1 then a < 2 else 3", 1.0, "i64"),
+        ("if a < 1 then a < 2 else a > 3", 1.0, "i1"),
+    ] {
+        assert_eq!(value_type(source), ty, "{source}");
+        let program = format!("def f(a) {source}; f(0)");
+        assert_eq!(jit_eval(&program).unwrap(), value, "{source}");
+    }
+
+    let err = jit_eval("def f(a) if a < 1 then (1, 2) else 3").unwrap_err();
+    assert!(err.to_string().contains("incompatible types"), "{err}");
+}
+
+#[test]
+fn pair_is_returned_and_projected() {
+    let minmax = "def minmax(a b): (double, double) if a < b then (a, b) else (b, a); ";
+    assert_eq!(
+        jit_eval(&format!("{minmax} var m = minmax(7, 2) in m.0 * 10 + m.1")).unwrap(),
+        27.0
+    );
+    assert_eq!(
+        jit_eval(&format!("{minmax} minmax(2, 7).1 - minmax(2, 7).0")).unwrap(),
+        5.0
+    );
+}
+
+#[test]
+fn rounding_builtins() {
+    for (source, value) in [
+        ("floor(2.7)", 2.0),
+        ("floor(-2.1)", -3.0),
+        ("ceil(2.1)", 3.0),
+        ("round(2.5)", 3.0),
+        ("round(-2.5)", -3.0),
+        ("round(2.4)", 2.0),
+        ("rem(7.5, 2)", 1.5),
+    ] {
+        let program = format!("def f(a) {source} + a; f(0)");
+        assert_eq!(jit_eval(&program).unwrap(), value, "{source}");
+    }
+    assert!(compile_to_ir("def f(x) floor(x) + ceil(x) + round(x)")
+        .unwrap()
+        .contains("call double @llvm.round.f64(double %x)"));
+    // A program's own definition comes first.
+    assert_eq!(jit_eval("def round(x) x * 10; round(2.5)").unwrap(), 25.0);
+}
+
+#[test]
+fn for_body_can_do_its_own_stepping() {
+    let runs = |manual_step: bool, source: &str| {
+        let context = Context::create();
+        let mut state = State::new(&context);
+        state.for_manual_step = manual_step;
+        return state.jit_eval(source).unwrap();
+    };
+    // The body's step of two, plus the loop's own, visits 0, 3, 6 and 9.
+    let stepping = "{ var n = 0; for i = 0, i < 10 in { i = i + 2; n = n + 1 }; n }";
+    assert_eq!(runs(false, stepping), 4.0);
+    // Left to the body alone, it visits 0, 2, 4, 6 and 8.
+    assert_eq!(runs(true, stepping), 5.0);
+
+    // A body that leaves the variable alone is still stepped.
+    let counting = "{ var n = 0; for i = 0, i < 3 in n = n + 1; n }";
+    assert_eq!(runs(true, counting), runs(false, counting));
+}
@@ -0,0 +1,31 @@
+#![cfg(feature = "json")]
+
+use kaleidoscope::ast::ast_to_json;
+use kaleidoscope::parse_str;
+
+// The JSON of the first item of `source`.
+fn json(source: &str) -> String {
+    let items = parse_str(source).unwrap();
+    return ast_to_json(&items[0]).unwrap();
+}
+
+#[test]
+fn definition_as_json() {
+    let expected = concat!(
+        r#"{"Function":{"proto":{"Prototype":{"name":"f","args":["x"],"is_operator":false,"#,
+        r#""precedence":30,"is_var_arg":false,"return_type":"Double","loc":{"line":1,"col":5},"#,
+        r#""call_conv":"C","builtin":false}},"body":{"Binary":{"op":"+","lhs":{"Variable":"#,
+        r#"{"name":"x","loc":{"line":1,"col":10}}},"rhs":{"Number":{"val":{"Int":1}}}}}}}"#,
+    );
+    assert_eq!(json("def f(x) x+1"), expected);
+}
+
+#[test]
+fn var_bindings_as_json_in_order() {
+    let expected = concat!(
+        r#"{"Var":{"names":[["b",{"Number":{"val":{"Int":2}}}],["a","Null"]],"#,
+        r#""body":{"Variable":{"name":"a","loc":{"line":1,"col":17}}}}}"#,
+    );
+    let item = json("var b = 2, a in a");
+    assert!(item.contains(expected), "{item}");
+}
@@ -0,0 +1,190 @@
+use kaleidoscope::lexer::Token::*;
+use kaleidoscope::lexer::{tokenize, LexerConfig, Source};
+use kaleidoscope::parser::ParserState;
+use kaleidoscope::{lex, parse_str, Error};
+
+#[test]
+fn dump_on_its_own_line() {
+    assert_eq!(
+        lex("1\n  #dump\n2"),
+        vec![TokInt(1), TokDump, TokInt(2), TokEOF]
+    );
+}
+
+#[test]
+fn dump_after_a_token_is_a_comment() {
+    assert_eq!(lex("1 #dump\n2"), vec![TokInt(1), TokInt(2), TokEOF]);
+}
+
+#[test]
+fn slash_divides_and_double_slash_comments() {
+    assert_eq!(
+        lex("a / b // c"),
+        vec![
+            TokIdentifier(String::from("a")),
+            TokChar('/'),
+            TokIdentifier(String::from("b")),
+            TokEOF
+        ]
+    );
+}
+
+#[test]
+fn lexing_needs_no_llvm_context() {
+    let mut parser = ParserState::new();
+    parser.source = Source::from_text("def f(x) x");
+    assert_eq!(
+        tokenize(&mut parser),
+        vec![
+            TokDef,
+            TokIdentifier(String::from("f")),
+            TokChar('('),
+            TokIdentifier(String::from("x")),
+            TokChar(')'),
+            TokIdentifier(String::from("x")),
+            TokEOF
+        ]
+    );
+}
+
+#[test]
+fn command_lines_in_the_repl() {
+    let mut parser = ParserState::new();
+    parser.commands = true;
+    parser.source = Source::from_text("a\n  :quit now\nb : c");
+    assert_eq!(
+        tokenize(&mut parser),
+        vec![
+            TokIdentifier(String::from("a")),
+            TokCommand(String::from("quit now")),
+            TokIdentifier(String::from("b")),
+            TokChar(':'),
+            TokIdentifier(String::from("c")),
+            TokEOF
+        ]
+    );
+    // Outside the REPL a line may start with the ':' operator.
+    assert_eq!(lex("a\n: b")[1], TokChar(':'));
+}
+
+#[test]
+fn identifiers_take_underscores_and_digits() {
+    let ident = |name: &str| TokIdentifier(String::from(name));
+    assert_eq!(
+        lex("my_var _tmp a1_b2 _"),
+        vec![
+            ident("my_var"),
+            ident("_tmp"),
+            ident("a1_b2"),
+            ident("_"),
+            TokEOF
+        ]
+    );
+    // A keyword is only a keyword on its own.
+    assert_eq!(
+        lex("def def_ _def"),
+        vec![TokDef, ident("def_"), ident("_def"), TokEOF]
+    );
+}
+
+#[test]
+fn comment_at_the_end_without_a_newline() {
+    assert_eq!(
+        lex("1 + 2 # trailing comment"),
+        vec![TokInt(1), TokChar('+'), TokInt(2), TokEOF]
+    );
+    assert_eq!(lex("# only a comment"), vec![TokEOF]);
+}
+
+#[test]
+fn string_literals_take_escapes() {
+    assert_eq!(
+        lex(r#"puts("a\tb\n\"c\"\\")"#),
+        vec![
+            TokIdentifier(String::from("puts")),
+            TokChar('('),
+            TokString(String::from("a\tb\n\"c\"\\")),
+            TokChar(')'),
+            TokEOF
+        ]
+    );
+}
+
+#[test]
+fn crlf_and_cr_line_endings_lex_as_newlines() {
+    let lf = lex("def f(x)\n  x + 1 # one\n\nf(2)\n");
+    assert_eq!(lex("def f(x)\r\n  x + 1 # one\r\n\r\nf(2)\r\n"), lf);
+    assert_eq!(lex("def f(x)\r  x + 1 # one\r\rf(2)\r"), lf);
+
+    // Each ending counts as one line.
+    for source in ["1 +\r\n\r\n)", "1 +\r\r)", "1 +\n\n)"] {
+        match parse_str(source) {
+            Err(Error::Parse(err)) => assert_eq!(err.loc.line, 3, "{source:?}"),
+            other => panic!("expected a parse error, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn hex_and_unicode_escapes_in_strings() {
+    assert_eq!(
+        lex(r#""\x41\x7e \u{263a}\u{1F600}""#),
+        vec![TokString(String::from("A~ \u{263a}\u{1F600}")), TokEOF]
+    );
+    for (source, problem) in [
+        (r#""\x80""#, "out of range"),
+        (r#""\x4""#, "two hex digits"),
+        (r#""\u263a""#, "Expected '{'"),
+        (r#""\u{263a""#, "hex digits and a '}'"),
+        (r#""\u{110000}""#, "not a valid character"),
+        (r#""\q""#, "Unknown escape `\\q`"),
+    ] {
+        match &lex(source)[0] {
+            TokError(message) => assert!(message.contains(problem), "{message}"),
+            other => panic!("expected an error for {source}, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn embedder_chooses_the_comment_syntax() {
+    let lex_with = |config: LexerConfig, source: &str| {
+        let mut parser = ParserState::new();
+        parser.lexer_config = config;
+        parser.source = Source::from_text(source);
+        return tokenize(&mut parser);
+    };
+    let source = "1 # 2 // 3\n4 ; 5";
+
+    // By default both '#' and "//" start comments.
+    assert_eq!(
+        lex_with(LexerConfig::default(), source),
+        vec![TokInt(1), TokInt(4), TokChar(';'), TokInt(5), TokEOF]
+    );
+    // With only "//", '#' is an ordinary character.
+    assert_eq!(
+        lex_with(LexerConfig::with_line_comment("//"), source),
+        vec![
+            TokInt(1),
+            TokChar('#'),
+            TokInt(2),
+            TokInt(4),
+            TokChar(';'),
+            TokInt(5),
+            TokEOF
+        ]
+    );
+    assert_eq!(
+        lex_with(LexerConfig::with_line_comment(";"), source),
+        vec![
+            TokInt(1),
+            TokChar('#'),
+            TokInt(2),
+            TokChar('/'),
+            TokChar('/'),
+            TokInt(3),
+            TokInt(4),
+            TokEOF
+        ]
+    );
+}
@@ -0,0 +1,45 @@
+#![cfg(feature = "orc")]
+
+use inkwell::context::Context;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::orc::OrcJit;
+use kaleidoscope::parser::compile_loop;
+use kaleidoscope::{orc_eval, State};
+
+// Compile a program into a fresh module, with its top-level expressions kept as
+// `__toplevel.N` functions.
+fn add_program(jit: &OrcJit, source: &str) -> kaleidoscope::orc::OrcModule {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text(source);
+    state.aot_main = true;
+    compile_loop(&mut state).unwrap();
+    return jit.add_module(&state.module).unwrap();
+}
+
+fn call(jit: &OrcJit, name: &str) -> f64 {
+    return jit.call_top_level(name).unwrap();
+}
+
+#[test]
+fn definition_then_call() {
+    assert_eq!(orc_eval("def f(x) x + 1; f(3)").unwrap(), 4.0);
+}
+
+#[test]
+fn runtime_functions_are_defined() {
+    assert_eq!(orc_eval("print(2); sqrt(16) + 1").unwrap(), 5.0);
+}
+
+#[test]
+fn removed_module_can_be_defined_again() {
+    let jit = OrcJit::new().unwrap();
+    let first = add_program(&jit, "def f() 1");
+    assert_eq!(call(&jit, "f"), 1.0);
+
+    jit.remove_module(first).unwrap();
+    assert!(jit.lookup("f").is_err());
+
+    add_program(&jit, "def f() 2");
+    assert_eq!(call(&jit, "f"), 2.0);
+}
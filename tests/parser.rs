@@ -0,0 +1,105 @@
+use inkwell::context::Context;
+use kaleidoscope::ast::AST;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::{parse_items, ParserState};
+use kaleidoscope::State;
+use kaleidoscope::{jit_eval, parse_str};
+
+// The operator at the root of the expression `source`, as `parser` parses it.
+fn root_operator(parser: &mut ParserState, source: &str) -> String {
+    parser.source = Source::from_text(source);
+    parser.rewind();
+    let items = parse_items(parser, 0).unwrap();
+    let ast = format!("{:?}", items[0].0);
+    // The outermost binary expression is the first one printed.
+    let start = ast.find("op: \"").unwrap() + 5;
+    let end = start + ast[start..].find('"').unwrap();
+    return ast[start..end].to_string();
+}
+
+#[test]
+fn slash_is_a_binary_operator() {
+    let ast = parse_str("a / b // c").unwrap();
+    assert_eq!(ast.len(), 1);
+    let ast = format!("{:?}", ast[0]);
+    assert!(ast.contains("op: \"/\""), "{ast}");
+    assert!(!ast.contains("\"c\""), "{ast}");
+}
+
+#[test]
+fn set_precedence_changes_the_parse() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    assert_eq!(root_operator(&mut state.parser, "1 + 2 * 3"), "+");
+
+    state.parser.set_precedence("+", 50).unwrap();
+    assert_eq!(root_operator(&mut state.parser, "1 + 2 * 3"), "*");
+    assert_eq!(state.jit_eval("1 + 2 * 3").unwrap(), 9.0);
+
+    // The override outlives the definitions.
+    state.reset();
+    assert_eq!(root_operator(&mut state.parser, "1 + 2 * 3"), "*");
+}
+
+#[test]
+fn set_precedence_rejects_bad_input() {
+    let mut parser = ParserState::default();
+    assert!(parser.set_precedence("a", 10).is_err());
+    assert!(parser.set_precedence("+", 0).is_err());
+    assert!(parser.set_precedence("+", 101).is_err());
+    assert_eq!(parser.get_precedence("+"), Some(20));
+}
+
+#[test]
+fn parsing_needs_no_llvm_context() {
+    let mut parser = ParserState::new();
+    parser.set_precedence("+", 50).unwrap();
+    assert_eq!(root_operator(&mut parser, "1 + 2 * 3"), "*");
+
+    parser.source = Source::from_text("def binary~ 5 (a b) a - b; 1 ~ 2 + 3");
+    parser.rewind();
+    let items = parse_items(&mut parser, 0).unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[1].1.as_deref(), Some("__toplevel.0"));
+    assert_eq!(parser.get_precedence("~"), Some(5));
+}
+
+#[test]
+fn var_bindings_round_trip_in_order() {
+    let source = "def f(x) var c = 3, a = x, b = a + c, d in a * b";
+    let printed = parse_str(source).unwrap()[0].to_string();
+    assert!(printed.contains("var c = 3, a = x, b = "), "{printed}");
+    assert!(printed.ends_with(", d in (a * b)"), "{printed}");
+    // Printing what was printed parses to the same program.
+    assert_eq!(parse_str(&printed).unwrap()[0].to_string(), printed);
+}
+
+#[test]
+fn items_share_a_line_after_a_semicolon() {
+    let items = parse_str("def f(x) x; f(2)").unwrap();
+    assert_eq!(items.len(), 2);
+    assert!(matches!(items[0], AST::Function(_)));
+    assert_eq!(
+        parse_str("def f(x) x+1; def g(x) x*2; f(3)").unwrap().len(),
+        3
+    );
+}
+
+#[test]
+fn operators_can_be_several_characters() {
+    let mut parser = ParserState::new();
+    parser.source = Source::from_text("def binary := 2 (a b) a * 10 + b; 1 := 2 * 3");
+    parser.rewind();
+    let items = parse_items(&mut parser, 0).unwrap();
+    assert_eq!(parser.get_precedence(":="), Some(2));
+    assert!(format!("{:?}", items[1].0).contains("op: \":=\""));
+    // Without the space, ':' followed by '=' is still the longer operator, and a lone
+    // ':' is still sequencing.
+    assert_eq!(root_operator(&mut parser, "a:=b"), ":=");
+    assert_eq!(root_operator(&mut parser, "a : b"), ":");
+
+    assert_eq!(
+        jit_eval("def binary := 2 (a b) a * 10 + b; 1 := 2 * 3").unwrap(),
+        16.0
+    );
+}
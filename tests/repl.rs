@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::rc::Rc;
+
+use inkwell::context::Context;
+use kaleidoscope::ast::Type;
+use kaleidoscope::lexer::Source;
+use kaleidoscope::parser::{eval_loop, file_loop, main_loop};
+use kaleidoscope::State;
+
+#[test]
+fn command_after_a_definition_ends_it() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("def f(x) x+1\n:quit\ndef g() 1\n");
+    main_loop(&mut state);
+    // `f` is not `x+1 : quit`, and nothing after `:quit` ran.
+    assert!(state.module.get_function("f").is_some());
+    assert!(state.module.get_function("g").is_none());
+}
+
+#[test]
+fn command_after_an_extern_is_not_its_return_type() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_text("extern sin(x)\n  :help\nextern cos(x)\n");
+    main_loop(&mut state);
+    assert_eq!(state.function_protos["sin"].get_return_type(), Type::Double);
+    assert!(state.function_protos.contains_key("cos"));
+}
+
+#[test]
+fn empty_input_ends_each_loop_at_once() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    state.parser.source = Source::from_reader(std::io::empty());
+    main_loop(&mut state);
+
+    state.parser.source = Source::from_reader(std::io::empty());
+    state.parser.rewind();
+    assert!(file_loop(&mut state).is_empty());
+
+    state.parser.source = Source::from_reader(std::io::empty());
+    state.parser.rewind();
+    assert!(eval_loop(&mut state).unwrap().is_empty());
+}
+
+// Input typed a line at a time: each read gives back at most what is left of one line,
+// and nothing once the lines typed so far are used up.
+#[derive(Clone, Default)]
+struct Typist(Rc<RefCell<VecDeque<String>>>);
+
+impl Typist {
+    fn type_line(&self, line: &str) {
+        self.0.borrow_mut().push_back(line.to_string());
+    }
+}
+
+impl Read for Typist {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut lines = self.0.borrow_mut();
+        let line = match lines.pop_front() {
+            Some(line) => line,
+            None => return Ok(0),
+        };
+        let len = line.len().min(buf.len());
+        buf[..len].copy_from_slice(&line.as_bytes()[..len]);
+        if len < line.len() {
+            lines.push_front(line[len..].to_string());
+        }
+        return Ok(len);
+    }
+}
+
+#[test]
+fn interactive_input_is_handled_a_line_at_a_time() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    let typist = Typist::default();
+    state.parser.source = Source::interactive(typist.clone());
+    assert!(state.parser.source.is_interactive());
+
+    // The definition is compiled with only its own line in.
+    typist.type_line("def f(x) x + 1;\n");
+    assert!(eval_loop(&mut state).unwrap().is_empty());
+    assert!(state.module.get_function("f").is_some());
+
+    typist.type_line("f(1); f(2);\n");
+    state.parser.rewind();
+    assert_eq!(eval_loop(&mut state).unwrap(), vec![2.0, 3.0]);
+}
+
+#[test]
+fn main_loop_runs_over_a_byte_buffer() {
+    let context = Context::create();
+    let mut state = State::new(&context);
+    let input: &[u8] = b"def f(x) x + 1\nf(2)\nextern cos(x)\ncos(0)\n";
+    state.parser.source = Source::from_reader(input);
+    assert!(!state.parser.source.is_interactive());
+    main_loop(&mut state);
+
+    assert!(state.module.get_function("f").is_some());
+    assert!(state.function_protos.contains_key("cos"));
+    // Both expressions were compiled and run, with `f` in the JIT.
+    assert_eq!(state.anon_count, 2);
+    assert!(state.jitted.contains("f"));
+}